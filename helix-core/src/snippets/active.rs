@@ -0,0 +1,2709 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use smallvec::SmallVec;
+
+use crate::diff::compare_ropes;
+use crate::movement::Direction;
+use crate::snippets::render::{
+    CursorPlacementPolicy, PendingVariable, RenderedSnippet, SnippetRenderCtx, Tabstop, TabstopKind,
+};
+use crate::snippets::{Snippet, TabstopIdx};
+use crate::{Assoc, ChangeSet, Range, Rope, RopeSlice, Selection, Tendril, Transaction};
+
+/// A snapshot of the active tabstop for UI display, see
+/// [`ActiveSnippet::current_tabstop_info`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct TabstopInfo {
+    /// Index of the active tabstop in visiting order, starting at zero.
+    pub index: usize,
+    /// Total number of tabstops, including the final one.
+    pub total: usize,
+    /// The kind of the active tabstop; for a choice this carries the
+    /// selectable values.
+    pub kind: TabstopKind,
+    /// How many mirror ranges the active tabstop has.
+    pub mirrors: usize,
+}
+
+/// A tabstop transition reported to the
+/// [observer](ActiveSnippet::set_observer), so the UI layer can show
+/// choice popups or placeholder hints exactly when the relevant tabstop
+/// activates.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SnippetEvent {
+    /// A tabstop became active.
+    Entered(TabstopInfo),
+    /// The previously active tabstop was left.
+    Left(TabstopInfo),
+    /// Edits were made since the last transition; emitted right before
+    /// `Left` so the embedder can create a history savepoint and undo
+    /// granularity aligns with tabstop-sized edits.
+    Savepoint,
+    /// The final tabstop (`$0`) was reached; filling in is complete.
+    Completed,
+}
+
+type SnippetObserver = Box<dyn FnMut(SnippetEvent)>;
+type AutoAdvancePredicate = Box<dyn Fn(&str) -> bool>;
+
+/// How strictly [`ActiveSnippet::is_valid`] checks the selection against
+/// the active tabstop. The default requires every cursor to stay inside a
+/// tabstop range; embedders can relax that so harmless motions (a quick
+/// look around, a motion overshooting by a word) don't kill the session.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ValidityPolicy {
+    /// Every cursor must be contained in an active tabstop range,
+    /// boundaries included.
+    #[default]
+    Tabstop,
+    /// Cursors may additionally stray up to `chars` characters outside an
+    /// active tabstop range.
+    Nearby { chars: usize },
+    /// Cursors may sit anywhere within the snippet instance ranges.
+    Snippet,
+}
+
+/// Why [`ActiveSnippet::validate`] rejected the session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Invalidity {
+    /// The selection moved outside the active tabstop (subject to the
+    /// [`ValidityPolicy`]). The session itself is intact, so offering the
+    /// [recovery selection](ActiveSnippet::recovery_selection) makes
+    /// sense.
+    CursorLeftTabstop,
+    /// Every mirror range of the active tabstop is gone; there is nothing
+    /// to return to.
+    TabstopDeleted,
+    /// The active tabstop's ranges overlap or escaped their snippet
+    /// instance -- an edit violated the session's invariants and the
+    /// session should be dismissed.
+    RangesOutOfOrder,
+}
+
+/// The order [`ActiveSnippet::next_tabstop`] and
+/// [`ActiveSnippet::prev_tabstop`] visit the tabstops in. The final
+/// tabstop stays last either way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum VisitOrder {
+    /// By tabstop number, as in the snippet source (the default).
+    #[default]
+    Numeric,
+    /// By document position of each tabstop's first range, avoiding
+    /// jarring backwards jumps in large templates.
+    Document,
+}
+
+/// One entry of [`ActiveSnippet::outline`], describing a tabstop for a
+/// picker listing e.g. "1: name, 2: args, 3: body" to jump around large
+/// templates.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutlineEntry {
+    /// The tabstop, in the internal order (final tabstop last).
+    pub idx: TabstopIdx,
+    /// A short label: the choices joined with `|` for a choice tabstop,
+    /// otherwise the first line of the tabstop's current text, truncated.
+    pub label: Tendril,
+    /// The tabstop's primary (first) range.
+    pub range: Range,
+}
+
+/// What [`ActiveSnippet::insert_snippet`] does with a nested expansion
+/// once the configured [depth limit](ActiveSnippet::set_nesting_limit) is
+/// reached, to prevent unbounded tabstop growth from snippets expanded
+/// inside snippets repeatedly.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum NestingPolicy {
+    /// Refuse the nested expansion. [`ActiveSnippet::insert_snippet`]
+    /// returns `None`; embedders should check
+    /// [`ActiveSnippet::can_nest`] and not expand in the first place.
+    #[default]
+    Reject,
+    /// Keep the session but flatten the nested snippet into the enclosing
+    /// placeholder: its text stays (it is already part of the growing
+    /// placeholder), its tabstops are not spliced in.
+    Flatten,
+    /// Drop the outer session and continue with the nested snippet alone.
+    Replace,
+}
+
+/// What an edit [mapped](ActiveSnippet::map_with_report) over the snippet
+/// killed, so the embedder can drop the corresponding highlights and
+/// cursors right away instead of discovering empty selections later.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MappingReport {
+    /// Indices of the snippet [instances](ActiveSnippet::ranges) whose
+    /// range collapsed to nothing with this edit.
+    pub dead_instances: Vec<usize>,
+    /// Tabstops whose every range collapsed to nothing with this edit.
+    pub dead_tabstops: Vec<TabstopIdx>,
+}
+
+/// The mapped state saved by [`ActiveSnippet::map_undo`] so the matching
+/// redo can restore it exactly.
+#[derive(Debug, Clone, PartialEq)]
+struct Snapshot {
+    ranges: Vec<Range>,
+    tabstops: Vec<Tabstop>,
+    variables: Vec<PendingVariable>,
+}
+
+/// The state of a snippet session: the ranges of every tabstop mapped
+/// through all edits made while the snippet is active.
+///
+/// With the `serde` feature the session serializes (ranges, tabstops,
+/// active index), so session-restore can resume a half-completed snippet
+/// after a restart. The state is only meaningful against identical buffer
+/// contents; undo snapshots are transient and not persisted.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ActiveSnippet {
+    ranges: Vec<Range>,
+    active_tabstops: HashSet<TabstopIdx>,
+    visited_tabstops: HashSet<TabstopIdx>,
+    overwritten_tabstops: HashSet<TabstopIdx>,
+    current_tabstop: TabstopIdx,
+    tabstops: Vec<Tabstop>,
+    variables: Vec<PendingVariable>,
+    placement_policy: CursorPlacementPolicy,
+    validity_policy: ValidityPolicy,
+    visit_order: VisitOrder,
+    wrap_around: bool,
+    mirrors_read_only: bool,
+    nesting_depth: usize,
+    max_nesting_depth: usize,
+    nesting_policy: NestingPolicy,
+    edited_since_transition: bool,
+    auto_advance: bool,
+    skip_empty_tabstops: bool,
+    edits_since_interaction: usize,
+    last_mapped_generation: Option<usize>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    undo_snapshots: Vec<Snapshot>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    observer: Option<SnippetObserver>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    auto_advance_predicate: Option<AutoAdvancePredicate>,
+}
+
+impl ActiveSnippet {
+    pub fn new(snippet: RenderedSnippet) -> Option<Self> {
+        let snippet = Self {
+            ranges: snippet.ranges,
+            tabstops: snippet.tabstops,
+            variables: snippet.variables,
+            active_tabstops: HashSet::new(),
+            visited_tabstops: HashSet::new(),
+            overwritten_tabstops: HashSet::new(),
+            current_tabstop: TabstopIdx(0),
+            placement_policy: CursorPlacementPolicy::default(),
+            validity_policy: ValidityPolicy::default(),
+            visit_order: VisitOrder::default(),
+            wrap_around: false,
+            mirrors_read_only: false,
+            nesting_depth: 0,
+            max_nesting_depth: usize::MAX,
+            nesting_policy: NestingPolicy::default(),
+            edited_since_transition: false,
+            auto_advance: false,
+            skip_empty_tabstops: false,
+            edits_since_interaction: 0,
+            last_mapped_generation: None,
+            auto_advance_predicate: None,
+            undo_snapshots: Vec::new(),
+            observer: None,
+        };
+        // a snippet with only the final tabstop doesn't need a session
+        (snippet.tabstops.len() != 1).then(|| {
+            let mut snippet = snippet;
+            snippet.activate_tabstop();
+            snippet
+        })
+    }
+
+    /// Builds a session from a set of ranges over identical identifiers,
+    /// as returned by LSP `textDocument/linkedEditingRange`, reusing the
+    /// mapping and mirror-sync machinery to keep the occurrences in sync
+    /// while typing. The session has a single tabstop whose mirrors are
+    /// the linked ranges and each range is its own instance, so
+    /// [`ActiveSnippet::map`], [`ActiveSnippet::sync_mirrors`] and
+    /// [`ActiveSnippet::is_valid`] behave like for a snippet expanded at
+    /// multiple cursors. Returns `None` for fewer than two ranges (nothing
+    /// to link).
+    pub fn from_linked_ranges(ranges: impl IntoIterator<Item = Range>) -> Option<Self> {
+        let mut ranges: Vec<Range> = ranges.into_iter().collect();
+        if ranges.len() < 2 {
+            return None;
+        }
+        ranges.sort_unstable_by_key(|range| range.from());
+        let tabstop = Tabstop {
+            ranges: ranges.iter().copied().collect(),
+            byte_ranges: SmallVec::new(),
+            parent: None,
+            kind: TabstopKind::Placeholder,
+        };
+        let mut snippet = Self {
+            ranges,
+            tabstops: vec![tabstop],
+            variables: Vec::new(),
+            active_tabstops: HashSet::new(),
+            visited_tabstops: HashSet::new(),
+            overwritten_tabstops: HashSet::new(),
+            current_tabstop: TabstopIdx(0),
+            placement_policy: CursorPlacementPolicy::default(),
+            validity_policy: ValidityPolicy::default(),
+            visit_order: VisitOrder::default(),
+            wrap_around: false,
+            mirrors_read_only: false,
+            nesting_depth: 0,
+            max_nesting_depth: usize::MAX,
+            nesting_policy: NestingPolicy::default(),
+            edited_since_transition: false,
+            auto_advance: false,
+            skip_empty_tabstops: false,
+            edits_since_interaction: 0,
+            last_mapped_generation: None,
+            auto_advance_predicate: None,
+            undo_snapshots: Vec::new(),
+            observer: None,
+        };
+        snippet.activate_tabstop();
+        Some(snippet)
+    }
+
+    pub fn tabstops(&self) -> impl Iterator<Item = &Tabstop> {
+        self.tabstops.iter()
+    }
+
+    /// The ranges of the snippet instances, tracked through all edits made
+    /// while the snippet is active, so the renderer can highlight the
+    /// snippet region for its whole lifetime.
+    pub fn ranges(&self) -> impl Iterator<Item = &Range> {
+        self.ranges.iter()
+    }
+
+    /// The mirror ranges of the active tabstop, for placeholder underlines
+    /// or background highlights.
+    pub fn current_tabstop_ranges(&self) -> impl Iterator<Item = &Range> {
+        self.tabstops[self.current_tabstop.0].ranges.iter()
+    }
+
+    /// A compact textual snapshot of the session -- one line per tabstop
+    /// with its kind and ranges, the current tabstop marked with `*` -- so
+    /// integration tests in embedders can assert snippet state without
+    /// poking private fields.
+    pub fn snapshot(&self) -> String {
+        use std::fmt::Write;
+        let mut snapshot = String::new();
+        for (idx, tabstop) in self.tabstops.iter().enumerate() {
+            let current = if idx == self.current_tabstop.0 { "*" } else { "" };
+            let _ = write!(snapshot, "{idx}{current}");
+            match &tabstop.kind {
+                TabstopKind::Choice { choices } => {
+                    snapshot.push_str(" choice(");
+                    for (i, choice) in choices.iter().enumerate() {
+                        if i != 0 {
+                            snapshot.push('|');
+                        }
+                        snapshot.push_str(&choice.value);
+                    }
+                    snapshot.push(')');
+                }
+                TabstopKind::Placeholder => snapshot.push_str(" placeholder"),
+                TabstopKind::Empty => snapshot.push_str(" empty"),
+                TabstopKind::Transform(_) => snapshot.push_str(" transform"),
+            }
+            for range in &tabstop.ranges {
+                let _ = write!(snapshot, " {}..{}", range.from(), range.to());
+            }
+            snapshot.push('\n');
+        }
+        snapshot
+    }
+
+    /// Describes the active tabstop for a snippet-mode statusline or popup
+    /// (e.g. "tabstop 2/5, choice of 3").
+    pub fn current_tabstop_info(&self) -> TabstopInfo {
+        let tabstop = &self.tabstops[self.current_tabstop.0];
+        TabstopInfo {
+            index: self.current_tabstop.0,
+            total: self.tabstops.len(),
+            kind: tabstop.kind.clone(),
+            mirrors: tabstop.ranges.len(),
+        }
+    }
+
+    /// Whether the selection is still contained in the active tabstop
+    /// (subject to the [`ValidityPolicy`]), that is whether the user is
+    /// still "filling in" the snippet.
+    pub fn is_valid(&self, new_selection: &Selection) -> bool {
+        self.validate(new_selection).is_ok()
+    }
+
+    /// Like [`ActiveSnippet::is_valid`] but says why the session is no
+    /// longer valid, so the UI can pick between offering the
+    /// [recovery selection](ActiveSnippet::recovery_selection) and
+    /// dismissing snippet mode outright.
+    pub fn validate(&self, new_selection: &Selection) -> Result<(), Invalidity> {
+        let active_tabstop = &self.tabstops[self.current_tabstop.0];
+        if active_tabstop.ranges.is_empty() {
+            return Err(Invalidity::TabstopDeleted);
+        }
+        let consistent = active_tabstop
+            .ranges
+            .windows(2)
+            .all(|pair| pair[0].to() <= pair[1].from())
+            && active_tabstop.ranges.iter().all(|range| {
+                self.ranges.iter().any(|snippet_range| {
+                    snippet_range.from() <= range.from() && range.to() <= snippet_range.to()
+                })
+            });
+        if !consistent {
+            return Err(Invalidity::RangesOutOfOrder);
+        }
+        let contained = new_selection
+            .ranges()
+            .iter()
+            .all(|range| match self.validity_policy {
+                ValidityPolicy::Tabstop => active_tabstop.ranges.iter().any(|tabstop_range| {
+                    tabstop_range.from() <= range.from() && range.to() <= tabstop_range.to()
+                }),
+                ValidityPolicy::Nearby { chars } => {
+                    active_tabstop.ranges.iter().any(|tabstop_range| {
+                        tabstop_range.from().saturating_sub(chars) <= range.from()
+                            && range.to() <= tabstop_range.to() + chars
+                    })
+                }
+                ValidityPolicy::Snippet => self.ranges.iter().any(|snippet_range| {
+                    snippet_range.from() <= range.from() && range.to() <= snippet_range.to()
+                }),
+            });
+        if contained {
+            Ok(())
+        } else {
+            Err(Invalidity::CursorLeftTabstop)
+        }
+    }
+
+    /// A selection to offer when the user strayed outside the active
+    /// tabstop ([`ActiveSnippet::is_valid`] returned `false`): the active
+    /// tabstop's ranges, which mapping keeps clamped to the snippet
+    /// instances, so the editor can offer "return to snippet" instead of
+    /// silently dropping the session. `None` when the active tabstop has
+    /// no ranges left to return to.
+    pub fn recovery_selection(&self) -> Option<Selection> {
+        let tabstop = &self.tabstops[self.current_tabstop.0];
+        if tabstop.ranges.is_empty() {
+            return None;
+        }
+        Some(self.tabstop_selection(0, Direction::Forward))
+    }
+
+    /// Whether the (char) position sits inside the active tabstop, so the
+    /// editor's auto-pairs logic can tell input typed into a placeholder
+    /// apart from input elsewhere.
+    pub fn in_active_placeholder(&self, char_idx: usize) -> bool {
+        self.tabstops[self.current_tabstop.0]
+            .ranges
+            .iter()
+            .any(|range| range.from() <= char_idx && char_idx <= range.to())
+    }
+
+    /// Deletes the (placeholder) text of the active tabstop in all mirrors,
+    /// used when the user starts typing over a placeholder.
+    pub fn delete_placeholder(&self, doc: &Rope) -> Transaction {
+        Transaction::delete(
+            doc,
+            self.tabstops[self.current_tabstop.0]
+                .ranges
+                .iter()
+                .map(|range| (range.from(), range.to())),
+        )
+    }
+
+    /// Like [`ActiveSnippet::delete_placeholder`], but also marks the
+    /// active tabstop as overwritten: the first keystroke over a
+    /// placeholder clears it across all mirrors, subsequent ones find
+    /// nothing left to clear and get `None`. Saves the caller from
+    /// tracking which tabstops it already cleared.
+    pub fn begin_overwrite(&mut self, doc: &Rope) -> Option<Transaction> {
+        if !self.overwritten_tabstops.insert(self.current_tabstop) {
+            return None;
+        }
+        Some(self.delete_placeholder(doc))
+    }
+
+    /// Whether the active tabstop's placeholder was already
+    /// [overwritten](ActiveSnippet::begin_overwrite).
+    pub fn is_overwritten(&self) -> bool {
+        self.overwritten_tabstops.contains(&self.current_tabstop)
+    }
+
+    /// Marks the secondary occurrences (mirrors) of every tabstop as
+    /// read-only: [`ActiveSnippet::read_only_ranges`] exposes them so the
+    /// editor can reject direct edits inside them (see
+    /// [`ActiveSnippet::edit_violates_read_only`]) or auto-revert them
+    /// with [`ActiveSnippet::revert_mirrors`], forcing edits through the
+    /// primary occurrence.
+    pub fn set_mirrors_read_only(&mut self, read_only: bool) {
+        self.mirrors_read_only = read_only;
+    }
+
+    /// The ranges of the active tabstop's secondary occurrences while
+    /// [mirrors are read-only](ActiveSnippet::set_mirrors_read_only),
+    /// empty otherwise.
+    pub fn read_only_ranges(&self) -> &[Range] {
+        if !self.mirrors_read_only {
+            return &[];
+        }
+        let ranges = &self.tabstops[self.current_tabstop.0].ranges;
+        &ranges[1.min(ranges.len())..]
+    }
+
+    /// Whether a change of the (not yet applied) changeset touches one of
+    /// the [read-only ranges](ActiveSnippet::read_only_ranges), so the
+    /// editor can reject the edit outright. Insertions at a mirror's
+    /// boundary don't count: they land outside the synced text.
+    pub fn edit_violates_read_only(&self, changes: &ChangeSet) -> bool {
+        changes.changes_iter().any(|(from, to, _)| {
+            self.read_only_ranges().iter().any(|range| {
+                if from == to {
+                    range.from() < from && from < range.to()
+                } else {
+                    from < range.to() && range.from() < to
+                }
+            })
+        })
+    }
+
+    /// Produces the transaction restoring every read-only mirror of the
+    /// active tabstop to the primary occurrence's text, auto-reverting
+    /// direct edits that slipped through. Returns `None` when mirrors are
+    /// not read-only or none diverges from the primary.
+    pub fn revert_mirrors(&self, doc: &Rope) -> Option<Transaction> {
+        if !self.mirrors_read_only {
+            return None;
+        }
+        let tabstop = &self.tabstops[self.current_tabstop.0];
+        let primary = tabstop.ranges.first()?;
+        let text = doc.slice(..);
+        let value: Tendril = text.slice(primary.from()..primary.to()).to_string().into();
+        let changes: Vec<_> = tabstop.ranges[1..]
+            .iter()
+            .filter(|range| text.slice(range.from()..range.to()) != &*value)
+            .map(|range| (range.from(), range.to(), Some(value.clone())))
+            .collect();
+        if changes.is_empty() {
+            return None;
+        }
+        Some(Transaction::change(doc, changes.into_iter()))
+    }
+
+    /// Removes the mirror range of the active tabstop containing the
+    /// (char) position, when the user intentionally collapsed that cursor.
+    /// The other mirrors and the rest of the session stay alive instead of
+    /// the whole session being invalidated. Returns `false` (and removes
+    /// nothing) when no mirror contains the position or the active tabstop
+    /// has no other mirror left.
+    pub fn remove_mirror(&mut self, char_idx: usize) -> bool {
+        let tabstop = &mut self.tabstops[self.current_tabstop.0];
+        if tabstop.ranges.len() < 2 {
+            return false;
+        }
+        let Some(idx) = tabstop
+            .ranges
+            .iter()
+            .position(|range| range.from() <= char_idx && char_idx <= range.to())
+        else {
+            return false;
+        };
+        tabstop.ranges.remove(idx);
+        if idx < tabstop.byte_ranges.len() {
+            tabstop.byte_ranges.remove(idx);
+        }
+        true
+    }
+
+    /// Removes the whole snippet instance containing the (char) position
+    /// from the session: its instance range and every tabstop mirror and
+    /// variable region inside it. Used when the user drops the cursor in
+    /// one expansion while keeping the cursors in the others, so later
+    /// tabstops don't resurrect a cursor there. Returns `false` (and
+    /// removes nothing) when no instance contains the position or it is
+    /// the only one left.
+    pub fn remove_instance(&mut self, char_idx: usize) -> bool {
+        if self.ranges.len() < 2 {
+            return false;
+        }
+        let Some(idx) = self
+            .ranges
+            .iter()
+            .position(|range| range.from() <= char_idx && char_idx <= range.to())
+        else {
+            return false;
+        };
+        let instance = self.ranges.remove(idx);
+        for tabstop in &mut self.tabstops {
+            let mut idx = 0;
+            while let Some(range) = tabstop.ranges.get(idx) {
+                if instance.from() <= range.from() && range.to() <= instance.to() {
+                    tabstop.ranges.remove(idx);
+                    if idx < tabstop.byte_ranges.len() {
+                        tabstop.byte_ranges.remove(idx);
+                    }
+                } else {
+                    idx += 1;
+                }
+            }
+        }
+        self.variables.retain(|var| {
+            !(instance.from() <= var.range.from() && var.range.to() <= instance.to())
+        });
+        true
+    }
+
+    /// Maps the snippet through a changeset. Returns `false` when every
+    /// instance of the snippet was deleted and the session should end.
+    pub fn map(&mut self, changes: &ChangeSet) -> bool {
+        // a regular edit diverges from the recorded undo history, the
+        // snapshots no longer apply
+        self.undo_snapshots.clear();
+        self.edited_since_transition = true;
+        self.edits_since_interaction += 1;
+        self.map_positions(changes)
+    }
+
+    /// Maps the snippet through a document transaction identified by
+    /// `generation`, typically the document's revision after applying it.
+    /// With the same document open in several views each view's event
+    /// handling sees the transaction, but only the first call per
+    /// generation applies the mapping -- the rest report liveness without
+    /// touching the ranges. Since [validity](ActiveSnippet::is_valid) and
+    /// navigation already take the view's own selection, this lets one
+    /// session be shared across views instead of keeping a conflicting
+    /// copy per view. Returns whether the session is still alive, like
+    /// [`ActiveSnippet::map`].
+    pub fn map_document(&mut self, generation: usize, changes: &ChangeSet) -> bool {
+        if self.last_mapped_generation == Some(generation) {
+            return self.ranges.iter().any(|range| range.from() != range.to());
+        }
+        self.last_mapped_generation = Some(generation);
+        self.map(changes)
+    }
+
+    /// Re-anchors the session after a whole-buffer rewrite, such as an
+    /// external formatter replacing the document contents. Formatters
+    /// typically produce one transaction replacing the full text, and
+    /// [mapping](ActiveSnippet::map) through that collapses every range
+    /// to a point; this instead diffs the text before and after the
+    /// rewrite and maps through the resulting minimal changeset, so
+    /// ranges move with the reformatted code and only die when the diff
+    /// really deleted their region. Returns `false` like
+    /// [`ActiveSnippet::map`] when every instance disappeared.
+    pub fn remap_after_format(&mut self, before: &Rope, after: &Rope) -> bool {
+        let transaction = compare_ropes(before, after);
+        self.map(transaction.changes())
+    }
+
+    /// Like [`ActiveSnippet::map`], but also reports which instances and
+    /// tabstops this particular edit killed.
+    pub fn map_with_report(&mut self, changes: &ChangeSet) -> (bool, MappingReport) {
+        let instance_was_alive: Vec<bool> = self
+            .ranges
+            .iter()
+            .map(|range| range.from() != range.to())
+            .collect();
+        let tabstop_was_alive: Vec<bool> = self
+            .tabstops
+            .iter()
+            .map(|tabstop| tabstop.ranges.iter().any(|range| range.from() != range.to()))
+            .collect();
+        let alive = self.map(changes);
+        let report = MappingReport {
+            dead_instances: self
+                .ranges
+                .iter()
+                .zip(instance_was_alive)
+                .enumerate()
+                .filter(|(_, (range, was_alive))| *was_alive && range.from() == range.to())
+                .map(|(idx, _)| idx)
+                .collect(),
+            dead_tabstops: self
+                .tabstops
+                .iter()
+                .zip(tabstop_was_alive)
+                .enumerate()
+                .filter(|(_, (tabstop, was_alive))| {
+                    *was_alive
+                        && tabstop
+                            .ranges
+                            .iter()
+                            .all(|range| range.from() == range.to())
+                })
+                .map(|(idx, _)| TabstopIdx(idx))
+                .collect(),
+        };
+        (alive, report)
+    }
+
+    /// Maps the snippet through an inverted changeset (an undo). The state
+    /// before the undo is remembered so the matching
+    /// [redo](ActiveSnippet::map_redo) restores it exactly: mapping alone
+    /// is lossy (deleted ranges are clamped together and don't grow back),
+    /// so without the snapshot any undo would kill the session even though
+    /// redo makes the text identical again. Returns `false` when no
+    /// instance has text left in the undone document; the session can
+    /// still be resumed by the matching redo, and only becomes
+    /// unrecoverable once a regular [map](ActiveSnippet::map) discards the
+    /// snapshots.
+    pub fn map_undo(&mut self, changes: &ChangeSet) -> bool {
+        self.undo_snapshots.push(Snapshot {
+            ranges: self.ranges.clone(),
+            tabstops: self.tabstops.clone(),
+            variables: self.variables.clone(),
+        });
+        self.map_positions(changes)
+    }
+
+    /// Maps the snippet forward again after an undo (a redo). Restores the
+    /// state saved by the matching [`ActiveSnippet::map_undo`], falling
+    /// back to plain mapping when there is none (a redo without a preceding
+    /// undo seen by this session). Returns `false` like
+    /// [`ActiveSnippet::map`].
+    pub fn map_redo(&mut self, changes: &ChangeSet) -> bool {
+        match self.undo_snapshots.pop() {
+            Some(snapshot) => {
+                self.ranges = snapshot.ranges;
+                self.tabstops = snapshot.tabstops;
+                self.variables = snapshot.variables;
+                self.ranges.iter().any(|range| range.from() != range.to())
+            }
+            None => self.map_positions(changes),
+        }
+    }
+
+    fn map_positions(&mut self, changes: &ChangeSet) -> bool {
+        // collect every position into one sorted batch so the changeset is
+        // walked exactly once: updating per tabstop re-walks (and, for
+        // positions before the previous batch, rewinds) the changeset,
+        // which gets expensive with tens of thousands of cursors
+        let position_count =
+            2 * (self.ranges.len() + self.variables.len())
+                + 2 * self
+                    .tabstops
+                    .iter()
+                    .map(|tabstop| tabstop.ranges.len())
+                    .sum::<usize>();
+        let mut positions_to_map = Vec::with_capacity(position_count);
+        for range in &mut self.ranges {
+            positions_to_map.push((&mut range.anchor, Assoc::Before));
+            positions_to_map.push((&mut range.head, Assoc::After));
+        }
+        for (i, tabstop) in self.tabstops.iter_mut().enumerate() {
+            // the active tabstop grows with edits at its boundary (like
+            // typing at the end of the placeholder), inactive tabstops
+            // only track edits fully inside them
+            let assoc = if self.active_tabstops.contains(&TabstopIdx(i)) {
+                (Assoc::BeforeWord, Assoc::AfterWord)
+            } else {
+                (Assoc::After, Assoc::Before)
+            };
+            for range in &mut tabstop.ranges {
+                positions_to_map.push((&mut range.anchor, assoc.0));
+                positions_to_map.push((&mut range.head, assoc.1));
+            }
+        }
+        // variable regions only shift with surrounding edits, they don't
+        // grow with typing at their boundary like the active tabstop
+        for var in &mut self.variables {
+            positions_to_map.push((&mut var.range.anchor, Assoc::After));
+            positions_to_map.push((&mut var.range.head, Assoc::Before));
+        }
+        positions_to_map.sort_by_key(|(pos, _)| **pos);
+        changes.update_positions(positions_to_map.into_iter());
+
+        for tabstop in &mut self.tabstops {
+            // ensure the tabstop ranges are still contained within their
+            // snippet instance; a mapping that leaves a range past every
+            // instance has violated the session's invariants (instance
+            // ranges out of order, or a crafted overlapping edit) -- treat
+            // that as the session dying rather than panicking
+            let mut snippet_ranges = self.ranges.iter();
+            let Some(mut snippet_range) = snippet_ranges.next() else {
+                return false;
+            };
+            for range in &mut tabstop.ranges {
+                while range.from() > snippet_range.to() {
+                    match snippet_ranges.next() {
+                        Some(next) => snippet_range = next,
+                        None => return false,
+                    }
+                }
+                range.anchor = range.anchor.clamp(snippet_range.from(), snippet_range.to());
+                range.head = range.head.clamp(range.anchor, snippet_range.to());
+            }
+        }
+
+        self.ranges.iter().any(|range| range.from() != range.to())
+    }
+
+    pub fn next_tabstop(&mut self, current_selection: &Selection) -> Option<(Selection, bool)> {
+        let primary_idx = self.primary_idx(current_selection);
+        let start = self.current_tabstop;
+        let left = self.observer.is_some().then(|| self.current_tabstop_info());
+        let order = self.visit_sequence();
+        let pos = order
+            .iter()
+            .position(|&idx| idx == start)
+            .map_or(0, |pos| pos + 1);
+        for &idx in &order[pos..] {
+            if self.skipped_marker(idx) {
+                continue;
+            }
+            self.current_tabstop = idx;
+            if self.activate_tabstop() {
+                self.notify_transition(left);
+                let selection = self.tabstop_selection(primary_idx, Direction::Forward);
+                return Some((selection, self.current_tabstop.0 + 1 == self.tabstops.len()));
+            }
+        }
+        if !self.wrap_around {
+            return None;
+        }
+        // cycle back to the first live tabstop instead of stopping at `$0`
+        for &idx in &order {
+            if self.skipped_marker(idx) {
+                continue;
+            }
+            self.current_tabstop = idx;
+            if self.activate_tabstop() {
+                self.notify_transition(left);
+                let selection = self.tabstop_selection(primary_idx, Direction::Forward);
+                return Some((selection, self.current_tabstop.0 + 1 == self.tabstops.len()));
+            }
+        }
+        self.current_tabstop = start;
+        None
+    }
+
+    pub fn prev_tabstop(&mut self, current_selection: &Selection) -> Option<Selection> {
+        let primary_idx = self.primary_idx(current_selection);
+        let start = self.current_tabstop;
+        let left = self.observer.is_some().then(|| self.current_tabstop_info());
+        let order = self.visit_sequence();
+        let pos = order.iter().position(|&idx| idx == start).unwrap_or(0);
+        for &idx in order[..pos].iter().rev() {
+            if self.skipped_marker(idx) {
+                continue;
+            }
+            self.current_tabstop = idx;
+            if self.activate_tabstop() {
+                self.notify_transition(left);
+                return Some(self.tabstop_selection(primary_idx, Direction::Forward));
+            }
+        }
+        if !self.wrap_around {
+            return None;
+        }
+        // cycle back to the last live tabstop (`$0`) from the first
+        for &idx in order.iter().rev() {
+            if self.skipped_marker(idx) {
+                continue;
+            }
+            self.current_tabstop = idx;
+            if self.activate_tabstop() {
+                self.notify_transition(left);
+                return Some(self.tabstop_selection(primary_idx, Direction::Forward));
+            }
+        }
+        self.current_tabstop = start;
+        None
+    }
+
+    /// The tabstop indices in visiting order. Numeric order is the
+    /// identity; document order sorts all but the final tabstop by the
+    /// document position of their first range.
+    fn visit_sequence(&self) -> Vec<TabstopIdx> {
+        let mut order: Vec<TabstopIdx> = (0..self.tabstops.len()).map(TabstopIdx).collect();
+        if self.visit_order == VisitOrder::Document {
+            let last = order.len() - 1;
+            order[..last].sort_by_key(|idx| {
+                self.tabstops[idx.0]
+                    .ranges
+                    .first()
+                    .map_or(usize::MAX, |range| range.from())
+            });
+        }
+        order
+    }
+
+    /// Whether navigation passes over the tabstop: with
+    /// [`ActiveSnippet::set_skip_empty_tabstops`] enabled, tabstops without
+    /// a placeholder whose every range is empty are treated as pure
+    /// position markers. The final tabstop is always stopped at so the
+    /// session can end.
+    fn skipped_marker(&self, idx: TabstopIdx) -> bool {
+        if !self.skip_empty_tabstops || idx.0 + 1 == self.tabstops.len() {
+            return false;
+        }
+        let tabstop = &self.tabstops[idx.0];
+        matches!(tabstop.kind, TabstopKind::Empty)
+            && tabstop.ranges.iter().all(|range| range.from() == range.to())
+    }
+
+    /// The tabstop whose range contains `pos`, together with the index of
+    /// the containing mirror range -- for mouse-click activation and hover
+    /// hints over snippet regions. Positions on a range boundary count as
+    /// inside, so empty tabstops are hittable; where tabstop ranges nest
+    /// (a tabstop inside a placeholder), the smallest containing range
+    /// wins.
+    pub fn tabstop_under(&self, pos: usize) -> Option<(TabstopIdx, usize)> {
+        let mut best: Option<(TabstopIdx, usize, usize)> = None;
+        for (idx, tabstop) in self.tabstops.iter().enumerate() {
+            for (mirror, range) in tabstop.ranges.iter().enumerate() {
+                if range.from() <= pos && pos <= range.to() {
+                    let len = range.to() - range.from();
+                    if best.map_or(true, |(.., best_len)| len < best_len) {
+                        best = Some((TabstopIdx(idx), mirror, len));
+                    }
+                }
+            }
+        }
+        best.map(|(idx, mirror, _)| (idx, mirror))
+    }
+
+    /// Jumps straight to tabstop `n` in snippet source numbering (`0` being
+    /// the final tabstop), so keybindings like "go to snippet body" don't
+    /// have to press through the tabstops in between. Dead tabstops whose
+    /// ranges were deleted are skipped forward over like in
+    /// [`ActiveSnippet::next_tabstop`]. Returns the selection of the landed
+    /// tabstop and whether it is the final one, or `None` -- leaving the
+    /// active tabstop unchanged -- when no tabstop at or after `n` is alive.
+    pub fn goto_tabstop(
+        &mut self,
+        n: usize,
+        current_selection: &Selection,
+    ) -> Option<(Selection, bool)> {
+        let primary_idx = self.primary_idx(current_selection);
+        let prev = self.current_tabstop;
+        let left = self.observer.is_some().then(|| self.current_tabstop_info());
+        // `$0` elaborates past the end, clamp it to the actual final tabstop
+        let mut idx = TabstopIdx::elaborate(n).0.min(self.tabstops.len() - 1);
+        while idx < self.tabstops.len() {
+            self.current_tabstop = TabstopIdx(idx);
+            if self.activate_tabstop() {
+                self.notify_transition(left);
+                let selection = self.tabstop_selection(primary_idx, Direction::Forward);
+                return Some((selection, idx + 1 == self.tabstops.len()));
+            }
+            idx += 1;
+        }
+        self.current_tabstop = prev;
+        None
+    }
+
+    /// Produces the transaction replacing every mirror of the active
+    /// [choice](TabstopKind::Choice) tabstop with the next choice, cycling
+    /// past the last one. The current choice is whatever the first mirror
+    /// shows, so cycling picks up where the user left off even after edits.
+    /// Applying the transaction and [mapping](ActiveSnippet::map) the
+    /// snippet through it keeps the mirror ranges in sync, like for any
+    /// other edit. Returns `None` when the active tabstop is not a choice.
+    pub fn next_choice(&self, doc: &Rope) -> Option<Transaction> {
+        self.cycle_choice(doc, Direction::Forward)
+    }
+
+    /// Like [`ActiveSnippet::next_choice`] but cycles backwards.
+    pub fn prev_choice(&self, doc: &Rope) -> Option<Transaction> {
+        self.cycle_choice(doc, Direction::Backward)
+    }
+
+    /// Runs the transform of every [`TabstopKind::Transform`] tabstop on
+    /// the text currently in its ranges -- what mirroring placed there or
+    /// the user typed -- and produces the transaction replacing each range
+    /// with the transformed result. Meant to be applied when the user
+    /// leaves a tabstop (right after [`ActiveSnippet::next_tabstop`] or
+    /// [`ActiveSnippet::prev_tabstop`]), so `${1/../../}` tabstops show
+    /// their transformed text instead of staying raw forever. Returns
+    /// `None` when no transform changes anything.
+    pub fn apply_pending_transforms(&self, doc: &Rope) -> Option<Transaction> {
+        let text = doc.slice(..);
+        let mut changes = Vec::new();
+        for tabstop in &self.tabstops {
+            let TabstopKind::Transform(transform) = &tabstop.kind else {
+                continue;
+            };
+            for range in &tabstop.ranges {
+                let current = text.slice(range.from()..range.to()).to_string();
+                let transformed = transform.apply(&current);
+                if *transformed != *current {
+                    changes.push((range.from(), range.to(), Some(transformed)));
+                }
+            }
+        }
+        if changes.is_empty() {
+            return None;
+        }
+        Some(Transaction::change(doc, changes.into_iter()))
+    }
+
+    /// Copies the text of the mirror the user just edited to the active
+    /// tabstop's other mirror ranges. With one cursor per mirror every
+    /// mirror receives the edit directly, but a user who collapsed to a
+    /// single cursor otherwise leaves the mirrors diverged. `doc` is the
+    /// document after applying `changes` and [mapping](ActiveSnippet::map)
+    /// the snippet through them. Returns `None` when the active tabstop has
+    /// no other mirrors, no change touched it, or all mirrors already
+    /// agree.
+    pub fn sync_mirrors(&self, doc: &Rope, changes: &ChangeSet) -> Option<Transaction> {
+        let tabstop = &self.tabstops[self.current_tabstop.0];
+        if tabstop.ranges.len() < 2 {
+            return None;
+        }
+        let text = doc.slice(..);
+        // locate the mirror the edit landed in, in post-change coordinates
+        let mut edited = None;
+        'changes: for (from, _, _) in changes.changes_iter() {
+            let pos = changes.map_pos(from, Assoc::After);
+            for (idx, range) in tabstop.ranges.iter().enumerate() {
+                if range.from() <= pos && pos <= range.to() {
+                    edited = Some(idx);
+                    break 'changes;
+                }
+            }
+        }
+        let edited = edited?;
+        let source = tabstop.ranges[edited];
+        let value: Tendril = text.slice(source.from()..source.to()).to_string().into();
+        let changes: Vec<_> = tabstop
+            .ranges
+            .iter()
+            .enumerate()
+            .filter(|&(idx, range)| {
+                idx != edited && text.slice(range.from()..range.to()) != &*value
+            })
+            .map(|(_, range)| (range.from(), range.to(), Some(value.clone())))
+            .collect();
+        if changes.is_empty() {
+            return None;
+        }
+        Some(Transaction::change(doc, changes.into_iter()))
+    }
+
+    /// The text currently in each tabstop, in the internal tabstop order
+    /// (the final tabstop last). The ranges have tracked every edit, so on
+    /// finish this is what the user entered; post-expansion hooks can e.g.
+    /// create a matching test file named after `$1`. Mirrors hold the same
+    /// text, so only the first range of each tabstop is read; a tabstop
+    /// whose ranges were deleted yields an empty value.
+    pub fn values(&self, doc: &Rope) -> Vec<(TabstopIdx, Tendril)> {
+        let text = doc.slice(..);
+        self.tabstops
+            .iter()
+            .enumerate()
+            .map(|(idx, tabstop)| {
+                let value = tabstop.ranges.first().map_or_else(Tendril::default, |range| {
+                    text.slice(range.from()..range.to()).to_string().into()
+                });
+                (TabstopIdx(idx), value)
+            })
+            .collect()
+    }
+
+    /// Describes every live tabstop for a picker, see [`OutlineEntry`].
+    /// Dead tabstops (all ranges deleted) are omitted; jump to a picked
+    /// entry with [`ActiveSnippet::goto_tabstop`].
+    pub fn outline(&self, doc: &Rope) -> Vec<OutlineEntry> {
+        const MAX_LABEL: usize = 24;
+        let text = doc.slice(..);
+        self.tabstops
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, tabstop)| {
+                let range = *tabstop.ranges.first()?;
+                let label = match &tabstop.kind {
+                    TabstopKind::Choice { choices } => {
+                        let mut label = Tendril::new();
+                        for (i, choice) in choices.iter().enumerate() {
+                            if i != 0 {
+                                label.push('|');
+                            }
+                            label.push_str(&choice.value);
+                        }
+                        label
+                    }
+                    _ => text
+                        .slice(range.from()..range.to())
+                        .chars()
+                        .take_while(|&c| c != '\n')
+                        .take(MAX_LABEL)
+                        .collect(),
+                };
+                Some(OutlineEntry {
+                    idx: TabstopIdx(idx),
+                    label,
+                    range,
+                })
+            })
+            .collect()
+    }
+
+    /// Converts the session back into a reusable [`Snippet`]: the current
+    /// text of the first snippet instance, with the first occurrence of
+    /// every top-level tabstop turned into a placeholder holding the value
+    /// the user entered so far, enabling a "save what I just filled in as
+    /// a new snippet" workflow. Mirrors keep their text as literal text,
+    /// and tabstops nested inside another placeholder flatten into its
+    /// value.
+    pub fn to_snippet(&self, doc: &Rope) -> anyhow::Result<Snippet> {
+        use std::fmt::Write;
+
+        fn escape_into(out: &mut String, piece: RopeSlice, in_placeholder: bool) {
+            for c in piece.chars() {
+                match c {
+                    '\\' | '$' => {
+                        out.push('\\');
+                        out.push(c);
+                    }
+                    '}' if in_placeholder => {
+                        out.push('\\');
+                        out.push('}');
+                    }
+                    _ => out.push(c),
+                }
+            }
+        }
+
+        let instance = self.ranges[0];
+        let text = doc.slice(..);
+        let last = self.tabstops.len() - 1;
+        let mut stops: Vec<(usize, Range)> = self
+            .tabstops
+            .iter()
+            .enumerate()
+            .filter(|(_, tabstop)| tabstop.parent.is_none())
+            .filter_map(|(idx, tabstop)| {
+                let range = tabstop
+                    .ranges
+                    .iter()
+                    .find(|range| instance.from() <= range.from() && range.to() <= instance.to())?;
+                Some((idx, *range))
+            })
+            .collect();
+        stops.sort_by_key(|&(_, range)| range.from());
+        let mut source = String::new();
+        let mut cursor = instance.from();
+        for (idx, range) in stops {
+            escape_into(&mut source, text.slice(cursor..range.from()), false);
+            if idx == last {
+                source.push_str("$0");
+            } else if range.from() == range.to() {
+                let _ = write!(source, "${}", idx + 1);
+            } else {
+                let _ = write!(source, "${{{}:", idx + 1);
+                escape_into(&mut source, text.slice(range.from()..range.to()), true);
+                source.push('}');
+            }
+            cursor = range.to();
+        }
+        escape_into(&mut source, text.slice(cursor..instance.to()), false);
+        Snippet::parse(&source)
+    }
+
+    /// Ends the session explicitly, consuming it. Produces the cleanup
+    /// transaction -- the pending transforms of every
+    /// [`TabstopKind::Transform`] tabstop, plus (when
+    /// `delete_untouched_placeholders` is set) the deletion of every
+    /// placeholder the user never visited -- and the selection at the final
+    /// tabstop (`$0`), mapped through that cleanup. The transaction may be
+    /// empty; apply it and set the selection to leave the snippet.
+    pub fn finish(
+        mut self,
+        doc: &Rope,
+        delete_untouched_placeholders: bool,
+    ) -> (Transaction, Selection) {
+        let text = doc.slice(..);
+        let mut changes = Vec::new();
+        let last = self.tabstops.len() - 1;
+        for (i, tabstop) in self.tabstops.iter().enumerate() {
+            match &tabstop.kind {
+                TabstopKind::Transform(transform) => {
+                    for range in &tabstop.ranges {
+                        let current = text.slice(range.from()..range.to()).to_string();
+                        let transformed = transform.apply(&current);
+                        if *transformed != *current {
+                            changes.push((range.from(), range.to(), Some(transformed)));
+                        }
+                    }
+                }
+                TabstopKind::Placeholder
+                    if delete_untouched_placeholders
+                        && i != last
+                        && !self.visited_tabstops.contains(&TabstopIdx(i)) =>
+                {
+                    changes.extend(
+                        tabstop
+                            .ranges
+                            .iter()
+                            .filter(|range| range.from() != range.to())
+                            .map(|range| (range.from(), range.to(), None)),
+                    );
+                }
+                _ => (),
+            }
+        }
+        changes.sort_unstable_by_key(|&(from, ..)| from);
+        let transaction = Transaction::change(doc, changes.into_iter());
+        self.current_tabstop = TabstopIdx(last);
+        let selection = self
+            .tabstop_selection(0, Direction::Forward)
+            .map(transaction.changes());
+        (transaction, selection)
+    }
+
+    /// Moves directly to the final tabstop (`$0`), passing over every
+    /// tabstop in between, and returns its selection -- the usual Escape
+    /// binding during a snippet session. The final tabstop counts as
+    /// entered, so observers see the [`SnippetEvent::Completed`]
+    /// notification; the session itself stays usable for a following
+    /// [`ActiveSnippet::finish`] or [`ActiveSnippet::prev_tabstop`].
+    pub fn finish_at_end(&mut self, current_selection: &Selection) -> Selection {
+        let primary_idx = self.primary_idx(current_selection);
+        let left = self.observer.is_some().then(|| self.current_tabstop_info());
+        self.current_tabstop = TabstopIdx(self.tabstops.len() - 1);
+        self.activate_tabstop();
+        self.notify_transition(left);
+        self.tabstop_selection(primary_idx, Direction::Forward)
+    }
+
+    /// Ends the session by reverting it, consuming it. Produces the
+    /// transaction replacing every snippet instance with the text it
+    /// overwrote at expansion, so an "undo snippet" command doesn't have to
+    /// walk editor history. `original_texts` yields that text per instance,
+    /// in the order of the expansion's change ranges; the instance ranges
+    /// have tracked every edit made since, so text typed into tabstops is
+    /// reverted along with the expansion. Instances beyond the provided
+    /// texts are deleted.
+    pub fn cancel<I>(self, doc: &Rope, original_texts: I) -> Transaction
+    where
+        I: IntoIterator,
+        I::Item: Into<Tendril>,
+    {
+        let mut original_texts = original_texts.into_iter();
+        Transaction::change(
+            doc,
+            self.ranges.iter().map(|range| {
+                (
+                    range.from(),
+                    range.to(),
+                    original_texts.next().map(Into::into),
+                )
+            }),
+        )
+    }
+
+    fn cycle_choice(&self, doc: &Rope, direction: Direction) -> Option<Transaction> {
+        let tabstop = &self.tabstops[self.current_tabstop.0];
+        let TabstopKind::Choice { choices } = &tabstop.kind else {
+            return None;
+        };
+        if choices.is_empty() || tabstop.ranges.is_empty() {
+            return None;
+        }
+        let text = doc.slice(..);
+        let first = tabstop.ranges[0];
+        let current = choices
+            .iter()
+            .position(|choice| text.slice(first.from()..first.to()) == &*choice.value);
+        let next = match direction {
+            Direction::Forward => current.map_or(0, |idx| (idx + 1) % choices.len()),
+            Direction::Backward => current.map_or(choices.len() - 1, |idx| {
+                (idx + choices.len() - 1) % choices.len()
+            }),
+        };
+        let value = choices[next].value.clone();
+        Some(Transaction::change(
+            doc,
+            tabstop
+                .ranges
+                .iter()
+                .map(|range| (range.from(), range.to(), Some(value.clone()))),
+        ))
+    }
+
+    /// Inserts another snippet rendered inside the active tabstop (nested
+    /// expansion), splicing its tabstops into the session so they are
+    /// visited next, before the session returns to the enclosing
+    /// placeholder.
+    ///
+    /// The nested instance sits inside the active placeholder. Replacing
+    /// the placeholder text collapses its [mapped](ActiveSnippet::map)
+    /// range to the end of the replacement, so the splice re-grows the
+    /// enclosing placeholder around the nested instance; the outer
+    /// instance range grew with the mapping and needs no merging. The
+    /// nested final tabstop is not visited:
+    /// leaving the last real nested tabstop continues at the enclosing
+    /// placeholder instead.
+    ///
+    /// Once the configured [nesting limit](ActiveSnippet::set_nesting_limit)
+    /// is reached the [`NestingPolicy`] decides what happens instead of
+    /// the splice.
+    pub fn insert_snippet(mut self, snippet: RenderedSnippet) -> Option<Self> {
+        if snippet.ranges.len() != 1 || self.tabstops[self.current_tabstop.0].ranges.len() != 1 {
+            // inserting a nested snippet at multiple cursors is not (yet) supported
+            return None;
+        }
+        // the render transaction replaced the placeholder text, collapsing
+        // its mapped range; re-grow it around the nested instance so leaving
+        // the nested tabstops selects the whole expansion
+        let nested_range = snippet.ranges[0];
+        let enclosing = &mut self.tabstops[self.current_tabstop.0].ranges[0];
+        *enclosing = Range::new(
+            enclosing.from().min(nested_range.from()),
+            enclosing.to().max(nested_range.to()),
+        );
+        if self.nesting_depth >= self.max_nesting_depth {
+            match self.nesting_policy {
+                NestingPolicy::Reject => return None,
+                NestingPolicy::Flatten => {
+                    self.variables.extend(snippet.variables);
+                    return Some(self);
+                }
+                NestingPolicy::Replace => {
+                    let mut replacement = Self::new(snippet)?;
+                    replacement.placement_policy = self.placement_policy;
+                    replacement.validity_policy = self.validity_policy;
+                    replacement.visit_order = self.visit_order;
+                    replacement.wrap_around = self.wrap_around;
+                    replacement.mirrors_read_only = self.mirrors_read_only;
+                    replacement.max_nesting_depth = self.max_nesting_depth;
+                    replacement.nesting_policy = self.nesting_policy;
+                    replacement.observer = self.observer;
+                    return Some(replacement);
+                }
+            }
+        }
+        self.nesting_depth += 1;
+        let offset = self.current_tabstop.0;
+        let mut tabstops = snippet.tabstops;
+        tabstops.pop();
+        let spliced = tabstops.len();
+        // the enclosing placeholder and everything after it shift past the
+        // spliced tabstops, parents pointing at them follow
+        for tabstop in &mut self.tabstops {
+            if let Some(parent) = &mut tabstop.parent {
+                if parent.0 >= offset {
+                    parent.0 += spliced;
+                }
+            }
+        }
+        let remap = |set: &HashSet<TabstopIdx>| {
+            set.iter()
+                .map(|idx| {
+                    if idx.0 >= offset {
+                        TabstopIdx(idx.0 + spliced)
+                    } else {
+                        *idx
+                    }
+                })
+                .collect()
+        };
+        self.visited_tabstops = remap(&self.visited_tabstops);
+        self.overwritten_tabstops = remap(&self.overwritten_tabstops);
+        for tabstop in &mut tabstops {
+            // top level nested tabstops hang off the enclosing placeholder,
+            // so it stays active (and growing) while they are filled in
+            match &mut tabstop.parent {
+                Some(parent) => parent.0 += offset,
+                parent @ None => *parent = Some(TabstopIdx(offset + spliced)),
+            }
+        }
+        let left = self.observer.is_some().then(|| self.current_tabstop_info());
+        self.tabstops.splice(offset..offset, tabstops);
+        self.variables.extend(snippet.variables);
+        if self.activate_tabstop() {
+            self.notify_transition(left);
+        }
+        Some(self)
+    }
+
+    /// Renders `snippet` over the active placeholder and splices it into
+    /// the session in one step: the render transaction replacing the
+    /// placeholder text is produced, the session is
+    /// [mapped](ActiveSnippet::map) through it and the rendered tabstops
+    /// are [spliced in](ActiveSnippet::insert_snippet). Returns that
+    /// transaction -- not yet applied to `doc` -- along with the selection
+    /// of the first nested tabstop and the combined session. Like nested
+    /// insertion this supports a single-mirror active tabstop; `None`
+    /// leaves nothing to apply.
+    pub fn expand_in_active_tabstop(
+        mut self,
+        doc: &Rope,
+        snippet: &Snippet,
+        ctx: &mut SnippetRenderCtx,
+    ) -> Option<(Transaction, Selection, Self)> {
+        let tabstop = &self.tabstops[self.current_tabstop.0];
+        if tabstop.ranges.len() != 1 {
+            return None;
+        }
+        let placeholder = Selection::new(tabstop.ranges.iter().copied().collect(), 0);
+        let (transaction, _, rendered) =
+            snippet.render(doc, &placeholder, |range| (range.from(), range.to()), ctx);
+        if !self.map(transaction.changes()) {
+            return None;
+        }
+        let spliced = self.insert_snippet(rendered)?;
+        let selection = spliced.tabstop_selection(0, Direction::Forward);
+        Some((transaction, selection, spliced))
+    }
+
+    /// Produces the transaction that patches every region rendered from the
+    /// variable `name` with `value` -- tracked through all edits made since
+    /// the expansion -- leaving text the user typed into tabstops intact.
+    /// Returns `None` when the snippet contains no such occurrence.
+    pub fn update_variable(&self, doc: &Rope, name: &str, value: &str) -> Option<Transaction> {
+        let changes: Vec<_> = self
+            .variables
+            .iter()
+            .filter(|var| &*var.name == name)
+            .map(|var| {
+                (
+                    var.range.from(),
+                    var.range.to(),
+                    Some(var.render_value(value)),
+                )
+            })
+            .collect();
+        if changes.is_empty() {
+            return None;
+        }
+        Some(Transaction::change(doc, changes.into_iter()))
+    }
+
+    /// Makes [`ActiveSnippet::next_tabstop`] and
+    /// [`ActiveSnippet::prev_tabstop`] skip tabstops that are pure position
+    /// markers -- no placeholder and every range empty -- which large
+    /// generated templates tend to accumulate. The markers still mirror
+    /// edits; navigation just never lands on them. Off by default.
+    pub fn set_skip_empty_tabstops(&mut self, enabled: bool) {
+        self.skip_empty_tabstops = enabled;
+    }
+
+    /// Enables auto-advance: after [mapping](ActiveSnippet::map) an edit,
+    /// [`ActiveSnippet::try_auto_advance`] moves to the next tabstop when
+    /// the active one's text exactly matches one of its choices, for rapid
+    /// form-like template filling. Off by default.
+    pub fn set_auto_advance(&mut self, enabled: bool) {
+        self.auto_advance = enabled;
+    }
+
+    /// Like [`ActiveSnippet::set_auto_advance`], but the predicate decides
+    /// -- from the active tabstop's current text -- whether input is
+    /// complete, also for tabstops without choices. The predicate is not
+    /// persisted or cloned with the session.
+    pub fn set_auto_advance_predicate(&mut self, predicate: impl Fn(&str) -> bool + 'static) {
+        self.auto_advance = true;
+        self.auto_advance_predicate = Some(Box::new(predicate));
+    }
+
+    /// Advances to the next tabstop like [`ActiveSnippet::next_tabstop`]
+    /// when [auto-advance](ActiveSnippet::set_auto_advance) is enabled and
+    /// the active tabstop's text matches one of its choices (or the
+    /// predicate accepts it). Call after mapping an edit; `None` when the
+    /// session stays where it is.
+    pub fn try_auto_advance(
+        &mut self,
+        doc: &Rope,
+        current_selection: &Selection,
+    ) -> Option<(Selection, bool)> {
+        if !self.auto_advance {
+            return None;
+        }
+        let tabstop = &self.tabstops[self.current_tabstop.0];
+        let first = tabstop.ranges.first()?;
+        let value = doc.slice(first.from()..first.to()).to_string();
+        let advance = match (&self.auto_advance_predicate, &tabstop.kind) {
+            (Some(predicate), _) => predicate(&value),
+            (None, TabstopKind::Choice { choices }) => {
+                choices.iter().any(|choice| *choice.value == *value)
+            }
+            (None, _) => false,
+        };
+        if !advance {
+            return None;
+        }
+        self.next_tabstop(current_selection)
+    }
+
+    /// Whether edits were [mapped](ActiveSnippet::map) over the snippet
+    /// since the last tabstop transition, so a history savepoint should be
+    /// created before the next [`ActiveSnippet::next_tabstop`] or
+    /// [`ActiveSnippet::prev_tabstop`] -- undo then works in tabstop-sized
+    /// steps rather than individual keystrokes. Observers get the same
+    /// signal as [`SnippetEvent::Savepoint`].
+    pub fn savepoint_hint(&self) -> bool {
+        self.edited_since_transition
+    }
+
+    /// The number of [mapped](ActiveSnippet::map) edits since the last
+    /// tabstop interaction (navigation or a nested expansion). Unlike the
+    /// [savepoint hint](ActiveSnippet::savepoint_hint) this keeps
+    /// counting, so embedders can auto-dismiss snippet mode once the user
+    /// has clearly moved on to editing elsewhere.
+    pub fn edits_since_interaction(&self) -> usize {
+        self.edits_since_interaction
+    }
+
+    /// Sets the observer notified of tabstop transitions: `Left` for the
+    /// tabstop being left, then `Entered` for the newly active one, then
+    /// `Completed` when the newly active tabstop is the final one. The
+    /// observer is not persisted with the session and has to be set again
+    /// after a restore.
+    pub fn set_observer(&mut self, observer: impl FnMut(SnippetEvent) + 'static) {
+        self.observer = Some(Box::new(observer));
+    }
+
+    fn notify(&mut self, event: SnippetEvent) {
+        if let Some(observer) = &mut self.observer {
+            observer(event);
+        }
+    }
+
+    /// Reports the transition from `left` to the just activated tabstop
+    /// and resets the [savepoint hint](ActiveSnippet::savepoint_hint).
+    /// `left` is `None` when there is no observer (and so nothing to
+    /// report) or no previously active tabstop.
+    fn notify_transition(&mut self, left: Option<TabstopInfo>) {
+        let edited = std::mem::take(&mut self.edited_since_transition);
+        self.edits_since_interaction = 0;
+        if self.observer.is_none() {
+            return;
+        }
+        if edited {
+            self.notify(SnippetEvent::Savepoint);
+        }
+        if let Some(left) = left {
+            self.notify(SnippetEvent::Left(left));
+        }
+        self.notify(SnippetEvent::Entered(self.current_tabstop_info()));
+        if self.current_tabstop.0 + 1 == self.tabstops.len() {
+            self.notify(SnippetEvent::Completed);
+        }
+    }
+
+    fn activate_tabstop(&mut self) -> bool {
+        // if the user removes the selection in one snippet instance (but
+        // other cursors remain in other instances) the embedder can prune
+        // the affected cursor with `remove_mirror`/`remove_instance`
+        // instead of invalidating the whole session
+        let tabstop = &self.tabstops[self.current_tabstop.0];
+        if tabstop.ranges.is_empty() {
+            return false;
+        }
+        self.active_tabstops.clear();
+        self.active_tabstops.insert(self.current_tabstop);
+        self.visited_tabstops.insert(self.current_tabstop);
+        let mut parent = tabstop.parent;
+        while let Some(tabstop) = parent {
+            self.active_tabstops.insert(tabstop);
+            parent = self.tabstops[tabstop.0].parent;
+        }
+        true
+    }
+
+    /// The index of the snippet instance the primary cursor is in.
+    fn primary_idx(&self, current_selection: &Selection) -> usize {
+        let primary = current_selection.primary();
+        self.ranges
+            .iter()
+            .position(|range| range.from() <= primary.from() && primary.to() <= range.to())
+            .unwrap_or(0)
+    }
+
+    /// Sets where the cursor is placed when a tabstop is entered, per
+    /// tabstop kind (the default selects the tabstop text everywhere).
+    pub fn set_placement_policy(&mut self, policy: CursorPlacementPolicy) {
+        self.placement_policy = policy;
+    }
+
+    /// Sets how far the selection may stray from the active tabstop before
+    /// [`ActiveSnippet::is_valid`] reports the session as over.
+    pub fn set_validity_policy(&mut self, policy: ValidityPolicy) {
+        self.validity_policy = policy;
+    }
+
+    /// Limits how deep [`ActiveSnippet::insert_snippet`] may nest further
+    /// expansions; `policy` decides what happens beyond `max_depth`
+    /// levels. The default is no limit.
+    pub fn set_nesting_limit(&mut self, max_depth: usize, policy: NestingPolicy) {
+        self.max_nesting_depth = max_depth;
+        self.nesting_policy = policy;
+    }
+
+    /// Whether another [nested expansion](ActiveSnippet::insert_snippet)
+    /// is within the configured depth limit. With
+    /// [`NestingPolicy::Reject`] embedders should check this before
+    /// applying the nested render.
+    pub fn can_nest(&self) -> bool {
+        self.nesting_depth < self.max_nesting_depth
+    }
+
+    /// Sets the order tabstops are visited in, see [`VisitOrder`].
+    pub fn set_visit_order(&mut self, order: VisitOrder) {
+        self.visit_order = order;
+    }
+
+    /// Makes [`ActiveSnippet::next_tabstop`] cycle back to the first
+    /// tabstop after the last one and [`ActiveSnippet::prev_tabstop`] from
+    /// the first to the last, instead of returning `None` (the default).
+    pub fn set_wrap_around(&mut self, wrap_around: bool) {
+        self.wrap_around = wrap_around;
+    }
+
+    fn tabstop_selection(&self, primary_idx: usize, direction: Direction) -> Selection {
+        let tabstop = &self.tabstops[self.current_tabstop.0];
+        tabstop.selection_with_placement(
+            direction,
+            primary_idx,
+            self.ranges.len(),
+            self.placement_policy.placement(&tabstop.kind),
+        )
+    }
+}
+
+impl fmt::Debug for ActiveSnippet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ActiveSnippet")
+            .field("ranges", &self.ranges)
+            .field("current_tabstop", &self.current_tabstop)
+            .field("tabstops", &self.tabstops)
+            .field("variables", &self.variables)
+            .field("observer", &self.observer.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Clone for ActiveSnippet {
+    /// The observer is not cloned; set one on the clone if it needs to
+    /// report transitions too.
+    fn clone(&self) -> Self {
+        Self {
+            ranges: self.ranges.clone(),
+            active_tabstops: self.active_tabstops.clone(),
+            visited_tabstops: self.visited_tabstops.clone(),
+            overwritten_tabstops: self.overwritten_tabstops.clone(),
+            current_tabstop: self.current_tabstop,
+            tabstops: self.tabstops.clone(),
+            variables: self.variables.clone(),
+            placement_policy: self.placement_policy,
+            validity_policy: self.validity_policy,
+            visit_order: self.visit_order,
+            wrap_around: self.wrap_around,
+            mirrors_read_only: self.mirrors_read_only,
+            nesting_depth: self.nesting_depth,
+            max_nesting_depth: self.max_nesting_depth,
+            nesting_policy: self.nesting_policy,
+            edited_since_transition: self.edited_since_transition,
+            auto_advance: self.auto_advance,
+            skip_empty_tabstops: self.skip_empty_tabstops,
+            edits_since_interaction: self.edits_since_interaction,
+            last_mapped_generation: self.last_mapped_generation,
+            auto_advance_predicate: None,
+            undo_snapshots: self.undo_snapshots.clone(),
+            observer: None,
+        }
+    }
+}
+
+impl PartialEq for ActiveSnippet {
+    /// Transient state (the undo snapshots and the observer) is not
+    /// compared.
+    fn eq(&self, other: &Self) -> bool {
+        self.ranges == other.ranges
+            && self.active_tabstops == other.active_tabstops
+            && self.visited_tabstops == other.visited_tabstops
+            && self.overwritten_tabstops == other.overwritten_tabstops
+            && self.current_tabstop == other.current_tabstop
+            && self.tabstops == other.tabstops
+            && self.variables == other.variables
+            && self.placement_policy == other.placement_policy
+            && self.validity_policy == other.validity_policy
+            && self.visit_order == other.visit_order
+            && self.wrap_around == other.wrap_around
+            && self.mirrors_read_only == other.mirrors_read_only
+            && self.nesting_depth == other.nesting_depth
+            && self.max_nesting_depth == other.max_nesting_depth
+            && self.nesting_policy == other.nesting_policy
+            && self.edited_since_transition == other.edited_since_transition
+            && self.auto_advance == other.auto_advance
+            && self.skip_empty_tabstops == other.skip_empty_tabstops
+            && self.edits_since_interaction == other.edits_since_interaction
+            && self.last_mapped_generation == other.last_mapped_generation
+    }
+}
+
+/// Independent snippet sessions, one per selection of the expansion, so
+/// deleting the cursor in one expansion doesn't constrain or invalidate
+/// the others. The alternative -- one shared [`ActiveSnippet`] -- keeps
+/// the cursors moving in lockstep but dies as a whole, see the note on
+/// [`ActiveSnippet::map`].
+pub struct ActiveSnippets {
+    instances: Vec<ActiveSnippet>,
+}
+
+impl ActiveSnippets {
+    /// Splits the render [per instance](RenderedSnippet::split_per_instance)
+    /// and starts a session for each. Like [`ActiveSnippet::new`] this is
+    /// `None` when the snippet only has the final tabstop and no session is
+    /// needed.
+    pub fn new(snippet: RenderedSnippet) -> Option<Self> {
+        let instances: Vec<_> = snippet
+            .split_per_instance()
+            .into_iter()
+            .filter_map(ActiveSnippet::new)
+            .collect();
+        (!instances.is_empty()).then_some(Self { instances })
+    }
+
+    /// Maps every session through the changeset, dropping only the ones
+    /// whose instance was deleted. Returns `false` when no session is left
+    /// and snippet handling should end.
+    pub fn map(&mut self, changes: &ChangeSet) -> bool {
+        self.instances.retain_mut(|instance| instance.map(changes));
+        !self.instances.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ActiveSnippet> {
+        self.instances.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut ActiveSnippet> {
+        self.instances.iter_mut()
+    }
+
+    /// The session whose instance contains the (char) position, typically
+    /// the one the primary cursor is in.
+    pub fn instance_at_mut(&mut self, char_idx: usize) -> Option<&mut ActiveSnippet> {
+        self.instances.iter_mut().find(|instance| {
+            instance
+                .ranges
+                .iter()
+                .any(|range| range.from() <= char_idx && char_idx <= range.to())
+        })
+    }
+}
+
+/// A stack of snippet sessions for one document, innermost on top:
+/// expanding a snippet while another is active pushes a new session
+/// instead of [splicing](ActiveSnippet::insert_snippet) into the current
+/// one, and leaving the inner session resumes the outer. Every embedder
+/// hand-rolls this routing; here it lives next to the sessions it
+/// manages.
+#[derive(Default)]
+pub struct SnippetStack {
+    stack: Vec<ActiveSnippet>,
+}
+
+impl SnippetStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, snippet: ActiveSnippet) {
+        self.stack.push(snippet);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stack.is_empty()
+    }
+
+    /// The innermost session, the one navigation is routed to.
+    pub fn top(&self) -> Option<&ActiveSnippet> {
+        self.stack.last()
+    }
+
+    pub fn top_mut(&mut self) -> Option<&mut ActiveSnippet> {
+        self.stack.last_mut()
+    }
+
+    /// Maps every session through the changeset -- outer sessions keep
+    /// tracking edits made inside inner ones -- dropping the sessions
+    /// whose instances were deleted. Returns `false` when no session is
+    /// left.
+    pub fn map(&mut self, changes: &ChangeSet) -> bool {
+        self.stack.retain_mut(|snippet| snippet.map(changes));
+        !self.stack.is_empty()
+    }
+
+    /// Routes [`ActiveSnippet::next_tabstop`] to the innermost session
+    /// still [valid](ActiveSnippet::is_valid) for the selection, popping
+    /// sessions that are broken or out of tabstops and falling through to
+    /// the next-outer one. `None` once every session is exhausted.
+    pub fn next_tabstop(&mut self, current_selection: &Selection) -> Option<(Selection, bool)> {
+        while let Some(top) = self.stack.last_mut() {
+            if top.is_valid(current_selection) {
+                if let Some(result) = top.next_tabstop(current_selection) {
+                    return Some(result);
+                }
+            }
+            self.stack.pop();
+        }
+        None
+    }
+
+    /// Like [`SnippetStack::next_tabstop`] but backwards. Broken sessions
+    /// are popped; a session merely at its first tabstop stays on the
+    /// stack and the call returns `None`.
+    pub fn prev_tabstop(&mut self, current_selection: &Selection) -> Option<Selection> {
+        while let Some(top) = self.stack.last_mut() {
+            if top.is_valid(current_selection) {
+                return top.prev_tabstop(current_selection);
+            }
+            self.stack.pop();
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::snippets::{Snippet, SnippetRenderCtx};
+
+    #[test]
+    fn insert_snippet_splices_a_consistent_session() {
+        let mut doc = Rope::from("\n");
+        let outer = Snippet::parse("match ${1:expr} { $2 }$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (transaction, _, rendered) = outer.render(
+            &doc,
+            &Selection::point(0),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        let mut active = ActiveSnippet::new(rendered).unwrap();
+
+        // expand a nested snippet over the active placeholder text
+        let nested = Snippet::parse("Some($1)$0").unwrap();
+        let placeholder = Selection::single(6, 10);
+        let (transaction, _, rendered) = nested.render(
+            &doc,
+            &placeholder,
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        assert_eq!(doc, "match Some() {  }\n");
+        assert!(active.map(transaction.changes()));
+        let mut active = active.insert_snippet(rendered).unwrap();
+
+        // the nested tabstop comes first and its `$0` was dropped
+        let info = active.current_tabstop_info();
+        assert_eq!((info.index, info.total), (0, 4));
+        // it hangs off the enclosing placeholder, which stays active
+        assert_eq!(
+            active.tabstops().next().unwrap().parent,
+            Some(TabstopIdx(1))
+        );
+        // leaving the nested tabstop returns to the enclosing placeholder,
+        // which grew around the nested expansion
+        let (selection, last) = active.next_tabstop(&Selection::point(11)).unwrap();
+        assert!(!last);
+        assert_eq!(selection.primary(), Range::new(6, 12));
+        let (selection, last) = active.next_tabstop(&selection).unwrap();
+        assert!(!last);
+        assert_eq!(selection.primary(), Range::point(15));
+        let (_, last) = active.next_tabstop(&selection).unwrap();
+        assert!(last);
+    }
+
+    #[test]
+    fn expand_in_active_tabstop_replaces_the_placeholder_atomically() {
+        let mut doc = Rope::from("\n");
+        let outer = Snippet::parse("match ${1:expr} { $2 }$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (transaction, _, rendered) = outer.render(
+            &doc,
+            &Selection::point(0),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        let active = ActiveSnippet::new(rendered).unwrap();
+
+        // one call renders over the placeholder and splices the session
+        let nested = Snippet::parse("Some($1)$0").unwrap();
+        let (transaction, selection, active) = active
+            .expand_in_active_tabstop(&doc, &nested, &mut ctx)
+            .unwrap();
+        assert!(transaction.apply(&mut doc));
+        assert_eq!(doc, "match Some() {  }\n");
+        // the selection lands on the first nested tabstop
+        assert_eq!(selection.primary(), Range::point(11));
+        let info = active.current_tabstop_info();
+        assert_eq!((info.index, info.total), (0, 4));
+    }
+
+    #[test]
+    fn finish_deletes_untouched_placeholders() {
+        let mut doc = Rope::from("\n");
+        let snippet = Snippet::parse("let ${1:name} = ${2:val};$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (transaction, _, rendered) = snippet.render(
+            &doc,
+            &Selection::point(0),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        assert_eq!(doc, "let name = val;\n");
+        let active = ActiveSnippet::new(rendered).unwrap();
+
+        // only `$1` was ever visited, so finishing deletes `val`
+        let (transaction, selection) = active.finish(&doc, true);
+        assert!(transaction.apply(&mut doc));
+        assert_eq!(doc, "let name = ;\n");
+        assert_eq!(selection.primary(), Range::point(12));
+    }
+
+    #[test]
+    fn snippet_stack_falls_through_to_the_outer_session() {
+        let mut doc = Rope::from("\n");
+        let outer = Snippet::parse("foo(${1:arg})$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (transaction, _, rendered) = outer.render(
+            &doc,
+            &Selection::point(0),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        let mut stack = SnippetStack::new();
+        stack.push(ActiveSnippet::new(rendered).unwrap());
+
+        // a nested expansion over the placeholder pushes a second session
+        let inner = Snippet::parse("Some(${1:x})$0").unwrap();
+        let (transaction, _, rendered) = inner.render(
+            &doc,
+            &Selection::single(4, 7),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        assert_eq!(doc, "foo(Some(x))\n");
+        assert!(stack.map(transaction.changes()));
+        stack.push(ActiveSnippet::new(rendered).unwrap());
+
+        // navigation drains the inner session first
+        let (selection, last) = stack.next_tabstop(&Selection::single(9, 10)).unwrap();
+        assert!(last);
+        assert_eq!(selection.primary(), Range::point(11));
+        // then pops it and resumes the outer one at its `$0`
+        let (selection, last) = stack.next_tabstop(&selection).unwrap();
+        assert!(last);
+        assert_eq!(selection.primary(), Range::point(12));
+        assert!(stack.next_tabstop(&selection).is_none());
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    fn tabstop_under_finds_the_clicked_mirror() {
+        let mut doc = Rope::from("\n");
+        let snippet = Snippet::parse("${1:arg} = ${1:arg};$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (transaction, _, rendered) = snippet.render(
+            &doc,
+            &Selection::point(0),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        assert_eq!(doc, "arg = arg;\n");
+        let active = ActiveSnippet::new(rendered).unwrap();
+
+        // "arg = arg;" -- a click into the second mirror names it
+        let (idx, mirror) = active.tabstop_under(7).unwrap();
+        assert_eq!((idx, mirror), (TabstopIdx(0), 1));
+        let (_, mirror) = active.tabstop_under(1).unwrap();
+        assert_eq!(mirror, 0);
+        // the empty `$0` on its boundary is still hittable
+        let (idx, _) = active.tabstop_under(10).unwrap();
+        assert_eq!(idx, TabstopIdx(1));
+        // outside every snippet region there is nothing to activate
+        assert_eq!(active.tabstop_under(4), None);
+    }
+
+    #[test]
+    fn finish_at_end_jumps_straight_to_the_final_tabstop() {
+        let mut doc = Rope::from("\n");
+        let snippet = Snippet::parse("${1:a} ${2:b}$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (transaction, _, rendered) = snippet.render(
+            &doc,
+            &Selection::point(0),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        let mut active = ActiveSnippet::new(rendered).unwrap();
+        let completed = std::rc::Rc::new(std::cell::Cell::new(false));
+        let sink = std::rc::Rc::clone(&completed);
+        active.set_observer(move |event| {
+            if matches!(event, SnippetEvent::Completed) {
+                sink.set(true);
+            }
+        });
+
+        // `$2` is never entered, the session goes straight to `$0`
+        let selection = active.finish_at_end(&Selection::point(0));
+        assert_eq!(selection.primary(), Range::point(3));
+        assert!(completed.get());
+    }
+
+    #[test]
+    fn cancel_restores_the_replaced_text() {
+        let mut doc = Rope::from("expr\n");
+        let snippet = Snippet::parse("Some(${1:expr})$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (transaction, _, rendered) = snippet.render(
+            &doc,
+            &Selection::single(0, 4),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        assert_eq!(doc, "Some(expr)\n");
+        let mut active = ActiveSnippet::new(rendered).unwrap();
+
+        // edits made inside the placeholder are reverted along with it
+        let edit = Transaction::change(&doc, [(5, 9, Some("value".into()))].into_iter());
+        assert!(edit.apply(&mut doc));
+        assert!(active.map(edit.changes()));
+
+        let transaction = active.cancel(&doc, ["expr"]);
+        assert!(transaction.apply(&mut doc));
+        assert_eq!(doc, "expr\n");
+    }
+
+    #[test]
+    fn undo_redo_round_trip_restores_the_session() {
+        let mut doc = Rope::from("\n");
+        let snippet = Snippet::parse("foo(${1:arg})$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (transaction, _, rendered) = snippet.render(
+            &doc,
+            &Selection::point(0),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        let mut active = ActiveSnippet::new(rendered).unwrap();
+
+        // type over the placeholder, then undo and redo that edit
+        let edit = Transaction::change(&doc, [(4, 7, Some("value".into()))].into_iter());
+        let undo = edit.invert(&doc);
+        assert!(edit.apply(&mut doc));
+        assert!(active.map(edit.changes()));
+        let before_undo: Vec<_> = active.tabstops().cloned().collect();
+
+        assert!(undo.apply(&mut doc));
+        assert!(active.map_undo(undo.changes()));
+        assert!(edit.apply(&mut doc));
+        assert!(active.map_redo(edit.changes()));
+
+        // plain mapping clamps ranges on the round trip, the snapshot
+        // restores them exactly
+        let after_redo: Vec<_> = active.tabstops().cloned().collect();
+        assert_eq!(before_undo, after_redo);
+        assert!(active.is_valid(&Selection::single(4, 9)));
+    }
+
+    #[test]
+    fn mapping_reports_what_an_edit_killed() {
+        let mut doc = Rope::from("\n");
+        let snippet = Snippet::parse("foo(${1:x})$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (transaction, _, rendered) = snippet.render(
+            &doc,
+            &Selection::point(0),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        let mut active = ActiveSnippet::new(rendered).unwrap();
+
+        // deleting the placeholder text kills `$1` but not the snippet
+        let delete = Transaction::change(&doc, [(4, 5, None)].into_iter());
+        assert!(delete.apply(&mut doc));
+        let (alive, report) = active.map_with_report(delete.changes());
+        assert!(alive);
+        assert!(report.dead_instances.is_empty());
+        assert_eq!(report.dead_tabstops, [TabstopIdx(0)]);
+
+        // deleting the rest kills the instance; `$1` isn't re-reported and
+        // `$0` never had text to lose
+        let delete = Transaction::change(&doc, [(0, 5, None)].into_iter());
+        assert!(delete.apply(&mut doc));
+        let (alive, report) = active.map_with_report(delete.changes());
+        assert!(!alive);
+        assert_eq!(report.dead_instances, [0]);
+        assert!(report.dead_tabstops.is_empty());
+    }
+
+    #[test]
+    fn removing_one_instance_keeps_the_session_alive() {
+        let mut doc = Rope::from("\n\n");
+        let snippet = Snippet::parse("foo(${1:x}, $2)$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let selection = Selection::new(vec![Range::point(0), Range::point(1)].into(), 0);
+        let (transaction, _, rendered) = snippet.render(
+            &doc,
+            &selection,
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        assert_eq!(doc, "foo(x, )\nfoo(x, )\n");
+        let mut active = ActiveSnippet::new(rendered).unwrap();
+
+        // the user collapsed the cursor in the second expansion
+        assert!(active.remove_instance(12));
+        // the remaining cursor still visits `$2` and `$0`, only in the
+        // first instance
+        let (selection, last) = active.next_tabstop(&Selection::point(5)).unwrap();
+        assert!(!last);
+        assert_eq!(selection.primary(), Range::point(7));
+        assert_eq!(selection.ranges().len(), 1);
+        let (selection, last) = active.next_tabstop(&selection).unwrap();
+        assert!(last);
+        assert_eq!(selection.primary(), Range::point(8));
+        // the only instance left cannot be removed
+        assert!(!active.remove_instance(4));
+    }
+
+    #[test]
+    fn begin_overwrite_clears_the_placeholder_once() {
+        let mut doc = Rope::from("\n");
+        let snippet = Snippet::parse("${1:name} = ${1:name}$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (transaction, _, rendered) = snippet.render(
+            &doc,
+            &Selection::point(0),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        assert_eq!(doc, "name = name\n");
+        let mut active = ActiveSnippet::new(rendered).unwrap();
+        assert!(!active.is_overwritten());
+
+        // the first keystroke clears all mirrors, the next finds nothing
+        let transaction = active.begin_overwrite(&doc).unwrap();
+        assert!(transaction.apply(&mut doc));
+        assert!(active.map(transaction.changes()));
+        assert_eq!(doc, " = \n");
+        assert!(active.is_overwritten());
+        assert!(active.begin_overwrite(&doc).is_none());
+    }
+
+    #[test]
+    fn per_selection_instances_die_independently() {
+        let mut doc = Rope::from("\n\n");
+        let snippet = Snippet::parse("foo(${1:x})$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let selection = Selection::new(vec![Range::point(0), Range::point(1)].into(), 0);
+        let (transaction, _, rendered) = snippet.render(
+            &doc,
+            &selection,
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        assert_eq!(doc, "foo(x)\nfoo(x)\n");
+        let mut active = ActiveSnippets::new(rendered).unwrap();
+        assert_eq!(active.iter().count(), 2);
+
+        // deleting the whole second expansion only drops its session
+        let delete = Transaction::change(&doc, [(7, 13, None)].into_iter());
+        assert!(delete.apply(&mut doc));
+        assert!(active.map(delete.changes()));
+        assert_eq!(active.iter().count(), 1);
+        let survivor = active.instance_at_mut(4).unwrap();
+        let (selection, last) = survivor.next_tabstop(&Selection::point(5)).unwrap();
+        assert!(last);
+        assert_eq!(selection.primary(), Range::point(6));
+    }
+
+    #[test]
+    fn validity_policy_relaxes_the_subset_check() {
+        let mut doc = Rope::from("\n");
+        let snippet = Snippet::parse("foo(${1:arg})$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (transaction, _, rendered) = snippet.render(
+            &doc,
+            &Selection::point(0),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        let mut active = ActiveSnippet::new(rendered).unwrap();
+
+        // `$1` covers `arg` (4..7); a cursor past the closing paren is out
+        let overshoot = Selection::point(8);
+        assert!(!active.is_valid(&overshoot));
+        active.set_validity_policy(ValidityPolicy::Nearby { chars: 1 });
+        assert!(active.is_valid(&overshoot));
+        assert!(!active.is_valid(&Selection::point(0)));
+        active.set_validity_policy(ValidityPolicy::Snippet);
+        assert!(active.is_valid(&Selection::point(0)));
+        assert!(!active.is_valid(&Selection::point(9)));
+    }
+
+    #[test]
+    fn shared_sessions_map_each_transaction_once() {
+        let mut doc = Rope::from("\n");
+        let snippet = Snippet::parse("foo(${1:arg})$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (transaction, _, rendered) = snippet.render(
+            &doc,
+            &Selection::point(0),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        let mut active = ActiveSnippet::new(rendered).unwrap();
+
+        // both views report the same transaction, the ranges shift once
+        let edit = Transaction::change(&doc, [(0, 0, Some("x".into()))].into_iter());
+        assert!(edit.apply(&mut doc));
+        assert!(active.map_document(1, edit.changes()));
+        assert!(active.map_document(1, edit.changes()));
+        let selection = active.recovery_selection().unwrap();
+        assert_eq!(selection.primary(), Range::new(5, 8));
+        // each view still navigates with its own selection
+        let (selection, last) = active.next_tabstop(&selection).unwrap();
+        assert!(last);
+        assert_eq!(selection.primary(), Range::point(9));
+    }
+
+    #[test]
+    fn validate_names_the_reason_for_invalidity() {
+        let mut doc = Rope::from("\n");
+        let snippet = Snippet::parse("foo(${1:arg})$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (transaction, _, rendered) = snippet.render(
+            &doc,
+            &Selection::point(0),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        let active = ActiveSnippet::new(rendered).unwrap();
+
+        // inside `$1` the session is fine, outside only the cursor is
+        assert_eq!(active.validate(&Selection::single(4, 7)), Ok(()));
+        assert_eq!(
+            active.validate(&Selection::point(0)),
+            Err(Invalidity::CursorLeftTabstop)
+        );
+    }
+
+    #[test]
+    fn observer_sees_tabstop_transitions() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let mut doc = Rope::from("\n");
+        let snippet = Snippet::parse("${1:a} ${2:b}$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (transaction, _, rendered) = snippet.render(
+            &doc,
+            &Selection::point(0),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        let mut active = ActiveSnippet::new(rendered).unwrap();
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let sink = Rc::clone(&events);
+        active.set_observer(move |event| sink.borrow_mut().push(event));
+
+        let (selection, _) = active.next_tabstop(&Selection::point(0)).unwrap();
+        active.next_tabstop(&selection).unwrap();
+
+        let events = events.borrow();
+        let indices: Vec<_> = events
+            .iter()
+            .map(|event| match event {
+                SnippetEvent::Left(info) => ("left", info.index),
+                SnippetEvent::Entered(info) => ("entered", info.index),
+                SnippetEvent::Savepoint => ("savepoint", 0),
+                SnippetEvent::Completed => ("completed", 2),
+            })
+            .collect();
+        assert_eq!(
+            indices,
+            [
+                ("left", 0),
+                ("entered", 1),
+                ("left", 1),
+                ("entered", 2),
+                ("completed", 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn wrap_around_cycles_through_the_tabstops() {
+        let mut doc = Rope::from("\n");
+        let snippet = Snippet::parse("${1:a} ${2:b}$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (transaction, _, rendered) = snippet.render(
+            &doc,
+            &Selection::point(0),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        let mut active = ActiveSnippet::new(rendered).unwrap();
+        active.set_wrap_around(true);
+
+        // forward past `$0` lands on `$1` again
+        let (selection, last) = active.next_tabstop(&Selection::point(0)).unwrap();
+        assert!(!last);
+        let (selection, last) = active.next_tabstop(&selection).unwrap();
+        assert!(last);
+        let (selection, last) = active.next_tabstop(&selection).unwrap();
+        assert!(!last);
+        assert_eq!(selection.primary(), Range::new(0, 1));
+        // and backward from `$1` lands on `$0`
+        let selection = active.prev_tabstop(&selection).unwrap();
+        assert_eq!(selection.primary(), Range::point(3));
+    }
+
+    #[test]
+    fn skipping_empty_tabstops_only_stops_at_content() {
+        let mut doc = Rope::from("\n");
+        let snippet = Snippet::parse("${1:a}$2${3:c}$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (transaction, _, rendered) = snippet.render(
+            &doc,
+            &Selection::point(0),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        let mut active = ActiveSnippet::new(rendered).unwrap();
+        active.set_skip_empty_tabstops(true);
+
+        // `$2` is a pure marker and is passed over in both directions
+        let (selection, last) = active.next_tabstop(&Selection::point(0)).unwrap();
+        assert!(!last);
+        assert_eq!(selection.primary(), Range::new(1, 2));
+        let selection = active.prev_tabstop(&selection).unwrap();
+        assert_eq!(selection.primary(), Range::new(0, 1));
+        // `$0` is empty too but navigation still ends there
+        let (_, last) = active.next_tabstop(&selection).unwrap();
+        let (selection, last_again) = active.next_tabstop(&Selection::point(1)).unwrap();
+        assert!(!last && last_again);
+        assert_eq!(selection.primary(), Range::point(2));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip_resumes_the_session() {
+        let mut doc = Rope::from("\n");
+        let snippet = Snippet::parse("${1|a,b|}: ${2:x}$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (transaction, _, rendered) = snippet.render(
+            &doc,
+            &Selection::point(0),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        let mut active = ActiveSnippet::new(rendered).unwrap();
+
+        let json = serde_json::to_string(&active).unwrap();
+        let mut restored: ActiveSnippet = serde_json::from_str(&json).unwrap();
+
+        // the restored session resumes where the saved one stood
+        assert_eq!(restored, active);
+        let selection = Selection::single(0, 1);
+        assert_eq!(
+            restored.next_tabstop(&selection),
+            active.next_tabstop(&selection)
+        );
+    }
+
+    #[test]
+    fn auto_advance_moves_on_when_a_choice_matches() {
+        let mut doc = Rope::from("\n");
+        let snippet = Snippet::parse("${1|let,const|} ${2:name}$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (transaction, _, rendered) = snippet.render(
+            &doc,
+            &Selection::point(0),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        assert_eq!(doc, "let name\n");
+        let mut active = ActiveSnippet::new(rendered).unwrap();
+        active.set_auto_advance(true);
+
+        // partial input doesn't advance, an exact choice match does
+        let edit = Transaction::change(&doc, [(0, 3, Some("co".into()))].into_iter());
+        assert!(edit.apply(&mut doc));
+        assert!(active.map(edit.changes()));
+        assert!(active.try_auto_advance(&doc, &Selection::point(2)).is_none());
+        let edit = Transaction::change(&doc, [(2, 2, Some("nst".into()))].into_iter());
+        assert!(edit.apply(&mut doc));
+        assert!(active.map(edit.changes()));
+        let (selection, last) = active.try_auto_advance(&doc, &Selection::point(5)).unwrap();
+        assert!(!last);
+        assert_eq!(selection.primary(), Range::new(6, 10));
+    }
+
+    #[test]
+    fn outline_lists_the_tabstops() {
+        let mut doc = Rope::from("\n");
+        let snippet = Snippet::parse("fn ${1:name}(${2:args}) ${3|pub,priv|}$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (transaction, _, rendered) = snippet.render(
+            &doc,
+            &Selection::point(0),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        assert_eq!(doc, "fn name(args) pub\n");
+        let active = ActiveSnippet::new(rendered).unwrap();
+
+        let outline = active.outline(&doc);
+        let entries: Vec<_> = outline
+            .iter()
+            .map(|entry| (entry.idx, &*entry.label, entry.range))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                (TabstopIdx(0), "name", Range::new(3, 7)),
+                (TabstopIdx(1), "args", Range::new(8, 12)),
+                (TabstopIdx(2), "pub|priv", Range::new(14, 17)),
+                (TabstopIdx(3), "", Range::point(17)),
+            ]
+        );
+    }
+
+    #[test]
+    fn savepoint_hint_follows_tabstop_sized_edits() {
+        let mut doc = Rope::from("\n");
+        let snippet = Snippet::parse("${1:a} ${2:b}$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (transaction, _, rendered) = snippet.render(
+            &doc,
+            &Selection::point(0),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        let mut active = ActiveSnippet::new(rendered).unwrap();
+        assert!(!active.savepoint_hint());
+
+        // typing into `$1` calls for a savepoint at the next transition
+        let edit = Transaction::change(&doc, [(0, 1, Some("xy".into()))].into_iter());
+        assert!(edit.apply(&mut doc));
+        assert!(active.map(edit.changes()));
+        assert!(active.savepoint_hint());
+        let (selection, _) = active.next_tabstop(&Selection::point(2)).unwrap();
+        assert!(!active.savepoint_hint());
+        // leaving `$2` untouched does not
+        active.next_tabstop(&selection).unwrap();
+        assert!(!active.savepoint_hint());
+    }
+
+    #[test]
+    fn reformatting_relocates_the_tabstops() {
+        let mut doc = Rope::from("\n");
+        let snippet = Snippet::parse("let ${1:name} = ${2:val};$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (transaction, _, rendered) = snippet.render(
+            &doc,
+            &Selection::point(0),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        assert_eq!(doc, "let name = val;\n");
+        let mut active = ActiveSnippet::new(rendered).unwrap();
+
+        // an external formatter indents the line and rewrites the buffer
+        let formatted = Rope::from("    let name = val;\n");
+        assert!(active.remap_after_format(&doc, &formatted));
+        assert_eq!(
+            active.values(&formatted),
+            [
+                (TabstopIdx(0), "name".into()),
+                (TabstopIdx(1), "val".into()),
+                (TabstopIdx(2), "".into()),
+            ]
+        );
+        // but a rewrite that drops the snippet region kills the session
+        let emptied = Rope::from("\n");
+        assert!(!active.remap_after_format(&formatted, &emptied));
+    }
+
+    #[test]
+    fn staleness_counts_edits_between_interactions() {
+        let mut doc = Rope::from("\n");
+        let snippet = Snippet::parse("${1:a} ${2:b}$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (transaction, _, rendered) = snippet.render(
+            &doc,
+            &Selection::point(0),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        let mut active = ActiveSnippet::new(rendered).unwrap();
+        assert_eq!(active.edits_since_interaction(), 0);
+
+        // every mapped edit counts, wherever in the file it lands
+        for _ in 0..2 {
+            let edit = Transaction::change(&doc, [(3, 3, Some("x".into()))].into_iter());
+            assert!(edit.apply(&mut doc));
+            assert!(active.map(edit.changes()));
+        }
+        assert_eq!(active.edits_since_interaction(), 2);
+        // moving to a tabstop is an interaction and resets the count
+        active.next_tabstop(&Selection::point(0)).unwrap();
+        assert_eq!(active.edits_since_interaction(), 0);
+    }
+
+    #[test]
+    fn recovery_selection_returns_to_the_active_tabstop() {
+        let mut doc = Rope::from("\n");
+        let snippet = Snippet::parse("foo(${1:arg})$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (transaction, _, rendered) = snippet.render(
+            &doc,
+            &Selection::point(0),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        let active = ActiveSnippet::new(rendered).unwrap();
+
+        // the cursor wandered off; the recovery selection leads back
+        let strayed = Selection::point(0);
+        assert!(!active.is_valid(&strayed));
+        let recovery = active.recovery_selection().unwrap();
+        assert_eq!(recovery.primary(), Range::new(4, 7));
+        assert!(active.is_valid(&recovery));
+    }
+
+    #[test]
+    fn nesting_limit_flattens_further_expansions() {
+        let mut doc = Rope::from("\n");
+        let outer = Snippet::parse("match ${1:expr} { $2 }$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (transaction, _, rendered) = outer.render(
+            &doc,
+            &Selection::point(0),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        let mut active = ActiveSnippet::new(rendered).unwrap();
+        active.set_nesting_limit(0, NestingPolicy::Flatten);
+        assert!(!active.can_nest());
+
+        // the nested text stays but no tabstops are spliced in
+        let nested = Snippet::parse("Some($1)$0").unwrap();
+        let (transaction, _, rendered) = nested.render(
+            &doc,
+            &Selection::single(6, 10),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        assert!(active.map(transaction.changes()));
+        let active = active.insert_snippet(rendered).unwrap();
+        assert_eq!(doc, "match Some() {  }\n");
+        let info = active.current_tabstop_info();
+        assert_eq!((info.index, info.total), (0, 3));
+    }
+
+    #[test]
+    fn recorded_snippet_replays_the_entered_values() {
+        let mut doc = Rope::from("\n");
+        let snippet = Snippet::parse("fn ${1:name}($2)$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (transaction, _, rendered) = snippet.render(
+            &doc,
+            &Selection::point(0),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        let mut active = ActiveSnippet::new(rendered).unwrap();
+        let edit = Transaction::change(&doc, [(3, 7, Some("run".into()))].into_iter());
+        assert!(edit.apply(&mut doc));
+        assert!(active.map(edit.changes()));
+
+        // the recorded snippet expands to the filled-in text, with the
+        // entered value as the `$1` placeholder
+        let recorded = active.to_snippet(&doc).unwrap();
+        let mut doc = Rope::from("\n");
+        let (transaction, _, rendered) = recorded.render(
+            &doc,
+            &Selection::point(0),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        assert_eq!(doc, "fn run()\n");
+        let active = ActiveSnippet::new(rendered).unwrap();
+        assert_eq!(
+            active.snapshot(),
+            "0* placeholder 3..6\n1 empty 7..7\n2 empty 8..8\n"
+        );
+    }
+
+    #[test]
+    fn values_capture_what_the_user_entered() {
+        let mut doc = Rope::from("\n");
+        let snippet = Snippet::parse("fn ${1:name}(${2:args})$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (transaction, _, rendered) = snippet.render(
+            &doc,
+            &Selection::point(0),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        assert_eq!(doc, "fn name(args)\n");
+        let mut active = ActiveSnippet::new(rendered).unwrap();
+
+        // type over `$1`, leave `$2` as its default
+        let edit = Transaction::change(&doc, [(3, 7, Some("run".into()))].into_iter());
+        assert!(edit.apply(&mut doc));
+        assert!(active.map(edit.changes()));
+        let values = active.values(&doc);
+        assert_eq!(
+            values,
+            [
+                (TabstopIdx(0), "run".into()),
+                (TabstopIdx(1), "args".into()),
+                (TabstopIdx(2), "".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn read_only_mirrors_reject_and_revert_edits() {
+        let mut doc = Rope::from("\n");
+        let snippet = Snippet::parse("${1:name} = ${1:name}$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (transaction, _, rendered) = snippet.render(
+            &doc,
+            &Selection::point(0),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        assert_eq!(doc, "name = name\n");
+        let mut active = ActiveSnippet::new(rendered).unwrap();
+        assert!(active.read_only_ranges().is_empty());
+        active.set_mirrors_read_only(true);
+        assert_eq!(active.read_only_ranges(), [Range::new(7, 11)]);
+
+        // an edit inside the mirror is flagged, one in the primary is not
+        let violation = Transaction::change(&doc, [(8, 9, Some("x".into()))].into_iter());
+        assert!(active.edit_violates_read_only(violation.changes()));
+        let edit = Transaction::change(&doc, [(0, 4, Some("id".into()))].into_iter());
+        assert!(!active.edit_violates_read_only(edit.changes()));
+
+        // an edit that slipped through is reverted from the primary
+        assert!(violation.apply(&mut doc));
+        assert!(active.map(violation.changes()));
+        let revert = active.revert_mirrors(&doc).unwrap();
+        assert!(revert.apply(&mut doc));
+        assert_eq!(doc, "name = name\n");
+    }
+
+    #[test]
+    fn document_order_avoids_backwards_jumps() {
+        let mut doc = Rope::from("\n");
+        let snippet = Snippet::parse("${1:a} ${3:c} ${2:b}$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (transaction, _, rendered) = snippet.render(
+            &doc,
+            &Selection::point(0),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        assert_eq!(doc, "a c b\n");
+        let mut active = ActiveSnippet::new(rendered).unwrap();
+        active.set_visit_order(VisitOrder::Document);
+
+        // `$3` sits before `$2` in the document and is visited first
+        let (selection, last) = active.next_tabstop(&Selection::point(0)).unwrap();
+        assert!(!last);
+        assert_eq!(selection.primary(), Range::new(2, 3));
+        let (selection, last) = active.next_tabstop(&selection).unwrap();
+        assert!(!last);
+        assert_eq!(selection.primary(), Range::new(4, 5));
+        let (_, last) = active.next_tabstop(&selection).unwrap();
+        assert!(last);
+        // and backwards follows document order too
+        let selection = active.prev_tabstop(&Selection::point(5)).unwrap();
+        assert_eq!(selection.primary(), Range::new(4, 5));
+    }
+
+    #[test]
+    fn linked_ranges_stay_in_sync_while_typing() {
+        let mut doc = Rope::from("let foo = 1;\nfoo + foo\n");
+        let mut linked = ActiveSnippet::from_linked_ranges([
+            Range::new(4, 7),
+            Range::new(13, 16),
+            Range::new(19, 22),
+        ])
+        .unwrap();
+
+        // typing at the end of the first occurrence grows its range
+        let edit = Transaction::change(&doc, [(7, 7, Some("d".into()))].into_iter());
+        assert!(edit.apply(&mut doc));
+        assert!(linked.map(edit.changes()));
+        let sync = linked.sync_mirrors(&doc, edit.changes()).unwrap();
+        assert!(sync.apply(&mut doc));
+        assert!(linked.map(sync.changes()));
+        assert_eq!(doc, "let food = 1;\nfood + food\n");
+    }
+
+    #[test]
+    fn snapshot_describes_the_session() {
+        let mut doc = Rope::from("\n");
+        let snippet = Snippet::parse("${1|a,b|} ${2:x} $2$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (transaction, _, rendered) = snippet.render(
+            &doc,
+            &Selection::point(0),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        assert!(transaction.apply(&mut doc));
+        assert_eq!(doc, "a x x\n");
+        let active = ActiveSnippet::new(rendered).unwrap();
+        assert_eq!(
+            active.snapshot(),
+            "0* choice(a|b) 0..1\n1 placeholder 2..3 4..5\n2 empty 5..5\n"
+        );
+    }
+}
@@ -0,0 +1,1291 @@
+use std::mem::swap;
+use std::ops::Index;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use regex::RegexBuilder;
+
+use crate::snippets::parser::{self, CaseChange, FormatFunction, FormatItem};
+use crate::snippets::render::{VariableContext, VariableResolver};
+use crate::snippets::{TabstopIdx, LAST_TABSTOP_IDX};
+use crate::Tendril;
+
+/// A snippet elaborated from the raw parse tree: tabstops are deduplicated,
+/// sorted and renumbered (with `$0` last) so that the renderer and the
+/// interactive session can index them directly.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Snippet {
+    elements: Vec<SnippetElement>,
+    tabstops: Vec<Tabstop>,
+}
+
+impl Snippet {
+    pub fn parse(snippet: &str) -> Result<Self> {
+        let parsed_snippet = parser::parse(snippet)
+            .map_err(|rest| anyhow!("Failed to parse snippet. Remaining input: {rest}"))?;
+        Ok(Snippet::new(parsed_snippet))
+    }
+
+    pub fn new(elements: Vec<parser::SnippetElement>) -> Snippet {
+        let mut res = Snippet::default();
+        res.elements = res.elaborate(elements, None).into();
+        res.fixup_tabstops();
+        res.ensure_last_tabstop();
+        res.renumber_tabstops();
+        res
+    }
+
+    /// Parses and elaborates many snippets in parallel, returning the result
+    /// for each input in order. Loading a large snippet registry at startup
+    /// is considerably faster this way than parsing one snippet at a time.
+    #[cfg(feature = "rayon")]
+    pub fn parse_batch<'a>(
+        snippets: impl IntoIterator<Item = &'a str>,
+    ) -> Vec<Result<Snippet>> {
+        use rayon::prelude::*;
+        let snippets: Vec<_> = snippets.into_iter().collect();
+        snippets
+            .into_par_iter()
+            .map(Snippet::parse)
+            .collect()
+    }
+
+    pub fn elements(&self) -> &[SnippetElement] {
+        &self.elements
+    }
+
+    /// Whether the snippet body renders on a single line: no literal text
+    /// -- including placeholder defaults and choice values -- contains a
+    /// line break. Multi-line resolver values expanded into a single-line
+    /// snippet can be normalized, see [`SnippetRenderCtx::multiline_values`].
+    ///
+    /// [`SnippetRenderCtx::multiline_values`]: crate::snippets::SnippetRenderCtx::multiline_values
+    pub fn is_single_line(&self) -> bool {
+        fn single_line(elements: &[SnippetElement]) -> bool {
+            elements.iter().all(|element| match element {
+                SnippetElement::Text(text) => !text.contains('\n'),
+                SnippetElement::Variable { default, .. } => single_line(default),
+                SnippetElement::Tabstop { .. } => true,
+            })
+        }
+        single_line(&self.elements)
+            && self.tabstops.iter().all(|tabstop| match &tabstop.kind {
+                TabstopKind::Placeholder { default } => single_line(default),
+                TabstopKind::Choice { choices } => {
+                    choices.iter().all(|choice| !choice.value.contains('\n'))
+                }
+                _ => true,
+            })
+    }
+
+    pub fn tabstops(&self) -> impl Iterator<Item = &Tabstop> {
+        self.tabstops.iter()
+    }
+
+    /// Substitutes variables whose values are known up front (like the
+    /// filename or language id) directly into the element tree. The resulting
+    /// snippet doesn't need to resolve these variables again so it can be
+    /// rendered repeatedly (e.g. for every completion in the same document)
+    /// at a lower cost.
+    pub fn bind_variables(&self, resolve_var: &mut dyn VariableResolver) -> Snippet {
+        let mut res = self.clone();
+        Self::bind_variables_in(&mut res.elements, resolve_var);
+        for tabstop in &mut res.tabstops {
+            if let TabstopKind::Placeholder { default } = &mut tabstop.kind {
+                let mut bound_default = default.to_vec();
+                Self::bind_variables_in(&mut bound_default, resolve_var);
+                *default = bound_default.into();
+            }
+        }
+        res
+    }
+
+    fn bind_variables_in(
+        elements: &mut Vec<SnippetElement>,
+        resolve_var: &mut dyn VariableResolver,
+    ) {
+        for element in elements.iter_mut() {
+            match element {
+                SnippetElement::Variable {
+                    name,
+                    default,
+                    transform,
+                } => {
+                    if let Some(value) = resolve_var.resolve_var(name, &VariableContext::default())
+                    {
+                        let text = match transform {
+                            Some(transform) => transform.apply(&value),
+                            None => value.as_ref().into(),
+                        };
+                        *element = SnippetElement::Text(text);
+                    } else {
+                        let mut bound_default = std::mem::take(default).into_vec();
+                        Self::bind_variables_in(&mut bound_default, resolve_var);
+                        *default = bound_default.into();
+                    }
+                }
+                SnippetElement::Tabstop { .. } | SnippetElement::Text(_) => (),
+            }
+        }
+        // merge text runs that became adjacent after substitution
+        elements.dedup_by(|element, prev| {
+            let (SnippetElement::Text(text), SnippetElement::Text(prev)) = (element, prev) else {
+                return false;
+            };
+            prev.push_str(text);
+            true
+        });
+    }
+
+    /// Summarizes the structure of the snippet, for tooling that audits or
+    /// documents snippet packs.
+    pub fn report(&self) -> SnippetReport {
+        let mut report = SnippetReport {
+            tabstops: self.tabstops.len(),
+            ..SnippetReport::default()
+        };
+        for tabstop in &self.tabstops {
+            match tabstop.kind {
+                TabstopKind::Choice { .. } => report.choices += 1,
+                TabstopKind::Transform(_) => report.transforms += 1,
+                _ => (),
+            }
+        }
+        let mut occurrences = vec![0; self.tabstops.len()];
+        self.scan_elements(&self.elements, 0, &mut report, &mut occurrences);
+        report.mirrors = occurrences
+            .iter()
+            .map(|&occurrences| occurrences.saturating_sub(1))
+            .sum();
+        report
+    }
+
+    fn scan_elements(
+        &self,
+        elements: &[SnippetElement],
+        depth: usize,
+        report: &mut SnippetReport,
+        occurrences: &mut [usize],
+    ) {
+        if elements.is_empty() {
+            return;
+        }
+        report.nesting_depth = report.nesting_depth.max(depth);
+        for element in elements {
+            match element {
+                SnippetElement::Tabstop { idx } => {
+                    occurrences[idx.0] += 1;
+                    // mirrors share the placeholder kind, only descend into
+                    // the default at the first occurrence
+                    if occurrences[idx.0] == 1 {
+                        if let TabstopKind::Placeholder { default } = &self[*idx].kind {
+                            self.scan_elements(default, depth + 1, report, occurrences);
+                        }
+                    }
+                }
+                SnippetElement::Variable {
+                    name,
+                    default,
+                    transform,
+                } => {
+                    if !report.variables.contains(name) {
+                        report.variables.push(name.clone());
+                    }
+                    if transform.is_some() {
+                        report.transforms += 1;
+                    }
+                    self.scan_elements(default, depth + 1, report, occurrences);
+                }
+                SnippetElement::Text(_) => (),
+            }
+        }
+    }
+
+    /// Returns a copy of the snippet where the placeholder of the given
+    /// tabstop is replaced with `text`. Tabstops are numbered as they are
+    /// visited (`$1` is 1, the final tabstop is 0), matching the canonical
+    /// form produced by [`Snippet::normalize`]. Callers like refactoring
+    /// code actions can inject known values up front and leave the
+    /// remaining tabstops interactive.
+    ///
+    /// Returns `None` if the snippet has no such tabstop.
+    pub fn with_tabstop_default(&self, tabstop: usize, text: &str) -> Option<Snippet> {
+        let idx = self.tabstop_idx(tabstop)?.0;
+        let mut res = self.clone();
+        res.tabstops[idx].kind = if text.is_empty() {
+            TabstopKind::Empty
+        } else {
+            TabstopKind::Placeholder {
+                default: vec![SnippetElement::Text(text.into())].into(),
+            }
+        };
+        Some(res)
+    }
+
+    /// Returns a copy of the snippet where the choices of the given tabstop
+    /// (numbered as in [`Snippet::normalize`]) carry descriptions, assigned
+    /// in order. The LSP snippet syntax has no way to express descriptions,
+    /// they are metadata supplied by a snippet pack so the editor's choice
+    /// popup can show an explanation next to each value.
+    ///
+    /// Returns `None` if the snippet has no such tabstop or it is not a
+    /// choice.
+    pub fn with_choice_descriptions(
+        &self,
+        tabstop: usize,
+        descriptions: &[&str],
+    ) -> Option<Snippet> {
+        let idx = self.tabstop_idx(tabstop)?.0;
+        let TabstopKind::Choice { choices } = &self.tabstops[idx].kind else {
+            return None;
+        };
+        let choices: Vec<_> = choices
+            .iter()
+            .enumerate()
+            .map(|(i, choice)| Choice {
+                value: choice.value.clone(),
+                description: descriptions
+                    .get(i)
+                    .map(|&description| description.into())
+                    .or_else(|| choice.description.clone()),
+            })
+            .collect();
+        let mut res = self.clone();
+        res.tabstops[idx].kind = TabstopKind::Choice {
+            choices: choices.into(),
+        };
+        Some(res)
+    }
+
+    /// The tabstop with the given number (`$1` is 1, the final tabstop is
+    /// 0), if any.
+    fn tabstop_idx(&self, tabstop: usize) -> Option<TabstopIdx> {
+        let idx = if tabstop == 0 {
+            self.tabstops.len() - 1
+        } else {
+            tabstop - 1
+        };
+        // the final tabstop always sorts last and is only addressable as 0
+        if idx >= self.tabstops.len() || (tabstop != 0 && idx == self.tabstops.len() - 1) {
+            return None;
+        }
+        Some(TabstopIdx(idx))
+    }
+
+    /// Produces a canonical textual form of the snippet.
+    ///
+    /// Since elaboration renumbers tabstops and serialization uses minimal
+    /// escaping, semantically identical snippets (e.g. `$1 ${2}` and
+    /// `${1} $2`) normalize to the same string, which registries can use to
+    /// deduplicate snippets coming from multiple packs.
+    pub fn normalize(&self) -> Tendril {
+        let mut buf = Tendril::new();
+        let mut emitted = vec![false; self.tabstops.len()];
+        self.write_normalized(&mut buf, &self.elements, &mut emitted);
+        buf
+    }
+
+    fn write_normalized(
+        &self,
+        buf: &mut Tendril,
+        elements: &[SnippetElement],
+        emitted: &mut Vec<bool>,
+    ) {
+        use std::fmt::Write;
+        for element in elements {
+            match element {
+                SnippetElement::Text(text) => write_escaped(buf, text, &['\\', '$', '}']),
+                SnippetElement::Variable {
+                    name,
+                    default,
+                    transform,
+                } => {
+                    if let Some(transform) = transform {
+                        write!(buf, "${{{name}").unwrap();
+                        transform.write_normalized(buf);
+                        buf.push('}');
+                    } else if default.is_empty() {
+                        write!(buf, "${{{name}}}").unwrap();
+                    } else {
+                        write!(buf, "${{{name}:").unwrap();
+                        self.write_normalized(buf, default, emitted);
+                        buf.push('}');
+                    }
+                }
+                &SnippetElement::Tabstop { idx } => {
+                    let number = self.tabstop_number(idx);
+                    let first = !emitted[idx.0];
+                    emitted[idx.0] = true;
+                    // the kind is only serialized at the first occurrence,
+                    // mirrors are plain tabstops
+                    match &self.tabstops[idx.0].kind {
+                        TabstopKind::Placeholder { default } if first => {
+                            write!(buf, "${{{number}:").unwrap();
+                            self.write_normalized(buf, &default.clone(), emitted);
+                            buf.push('}');
+                        }
+                        TabstopKind::Choice { choices } if first => {
+                            write!(buf, "${{{number}|").unwrap();
+                            for (i, choice) in choices.iter().enumerate() {
+                                if i != 0 {
+                                    buf.push(',');
+                                }
+                                write_escaped(buf, &choice.value, &['\\', '|', ',']);
+                            }
+                            buf.push_str("|}");
+                        }
+                        TabstopKind::Transform(transform) if first => {
+                            write!(buf, "${{{number}").unwrap();
+                            transform.write_normalized(buf);
+                            buf.push('}');
+                        }
+                        _ => write!(buf, "${number}").unwrap(),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Re-encodes the snippet as valid LSP snippet syntax so that modified
+    /// snippets (after e.g. [`Snippet::with_tabstop_default`]) can be
+    /// forwarded to clients or written back to snippet files. The result is
+    /// the canonical form produced by [`Snippet::normalize`] and parses back
+    /// to an equal snippet.
+    pub fn to_lsp_string(&self) -> String {
+        self.normalize().to_string()
+    }
+
+    /// Renders a plain display string for completion documentation popups,
+    /// without running the full transaction-producing render: placeholder
+    /// and choice text is kept but marked as `⟨text⟩`, bare tabstops show
+    /// as `⟨⟩` and the final tabstop is omitted. Unresolved variables fall
+    /// back to their default text, or their name if they have none.
+    pub fn preview(&self) -> String {
+        let mut buf = String::new();
+        self.write_preview(&mut buf, &self.elements);
+        buf
+    }
+
+    fn write_preview(&self, buf: &mut String, elements: &[SnippetElement]) {
+        use std::fmt::Write;
+        for element in elements {
+            match element {
+                SnippetElement::Tabstop { idx } => match &self.tabstops[idx.0].kind {
+                    TabstopKind::Placeholder { default } => {
+                        buf.push('⟨');
+                        self.write_preview(buf, default);
+                        buf.push('⟩');
+                    }
+                    TabstopKind::Choice { choices } => {
+                        buf.push('⟨');
+                        if let Some(choice) = choices.first() {
+                            buf.push_str(&choice.value);
+                        }
+                        buf.push('⟩');
+                    }
+                    // transforms have no value to show until the snippet is
+                    // finished, the final (exit) tabstop is invisible anyway
+                    TabstopKind::Transform(_) => (),
+                    TabstopKind::Empty => {
+                        if self.tabstop_number(*idx) != 0 {
+                            buf.push_str("⟨⟩");
+                        }
+                    }
+                },
+                SnippetElement::Variable { name, default, .. } => {
+                    if default.is_empty() {
+                        let _ = write!(buf, "${name}");
+                    } else {
+                        self.write_preview(buf, default);
+                    }
+                }
+                SnippetElement::Text(text) => buf.push_str(text),
+            }
+        }
+    }
+
+    /// The tabstop number a tabstop serializes to: tabstops are numbered
+    /// consecutively from 1 in their visiting order, the final tabstop is
+    /// always `$0`.
+    fn tabstop_number(&self, idx: TabstopIdx) -> usize {
+        if idx.0 + 1 == self.tabstops.len() {
+            0
+        } else {
+            idx.0 + 1
+        }
+    }
+
+    fn elaborate(
+        &mut self,
+        default: Vec<parser::SnippetElement>,
+        parent: Option<TabstopIdx>,
+    ) -> Box<[SnippetElement]> {
+        default
+            .into_iter()
+            .map(|val| match val {
+                parser::SnippetElement::Tabstop {
+                    tabstop,
+                    transform: None,
+                } => SnippetElement::Tabstop {
+                    idx: self.elaborate_placeholder(tabstop, parent, Vec::new()),
+                },
+                parser::SnippetElement::Tabstop {
+                    tabstop,
+                    transform: Some(transform),
+                } => SnippetElement::Tabstop {
+                    idx: self.elaborate_transform(tabstop, parent, transform),
+                },
+                parser::SnippetElement::Placeholder { tabstop, value } => SnippetElement::Tabstop {
+                    idx: self.elaborate_placeholder(tabstop, parent, value),
+                },
+                parser::SnippetElement::Choice { tabstop, choices } => SnippetElement::Tabstop {
+                    idx: self.elaborate_choice(tabstop, parent, choices),
+                },
+                parser::SnippetElement::Variable {
+                    name,
+                    default,
+                    transform,
+                } => SnippetElement::Variable {
+                    name,
+                    default: self.elaborate(default, parent),
+                    transform: transform.and_then(|transform| {
+                        Transform::new(transform)
+                            .map_err(|err| log::error!("invalid snippet transform: {err}"))
+                            .ok()
+                    }),
+                },
+                parser::SnippetElement::Text(text) => SnippetElement::Text(text),
+            })
+            .collect()
+    }
+
+    fn elaborate_placeholder(
+        &mut self,
+        tabstop: usize,
+        parent: Option<TabstopIdx>,
+        default: Vec<parser::SnippetElement>,
+    ) -> TabstopIdx {
+        let idx = TabstopIdx::elaborate(tabstop);
+        let default = self.elaborate(default, Some(idx));
+        let kind = if default.is_empty() {
+            TabstopKind::Empty
+        } else {
+            TabstopKind::Placeholder {
+                default: default.into(),
+            }
+        };
+        self.tabstops.push(Tabstop { idx, parent, kind });
+        idx
+    }
+
+    fn elaborate_transform(
+        &mut self,
+        tabstop: usize,
+        parent: Option<TabstopIdx>,
+        transform: parser::Transform,
+    ) -> TabstopIdx {
+        let idx = TabstopIdx::elaborate(tabstop);
+        let kind = match Transform::new(transform) {
+            Ok(transform) => TabstopKind::Transform(Arc::new(transform)),
+            Err(err) => {
+                log::error!("invalid snippet transform: {err}");
+                TabstopKind::Empty
+            }
+        };
+        self.tabstops.push(Tabstop { idx, parent, kind });
+        idx
+    }
+
+    fn elaborate_choice(
+        &mut self,
+        tabstop: usize,
+        parent: Option<TabstopIdx>,
+        choices: Vec<Tendril>,
+    ) -> TabstopIdx {
+        let idx = TabstopIdx::elaborate(tabstop);
+        let choices: Vec<_> = choices.into_iter().map(Choice::from).collect();
+        self.tabstops.push(Tabstop {
+            idx,
+            parent,
+            kind: TabstopKind::Choice {
+                choices: choices.into(),
+            },
+        });
+        idx
+    }
+
+    fn fixup_tabstops(&mut self) {
+        self.tabstops.sort_by_key(|tabstop| tabstop.idx);
+        self.tabstops.dedup_by(|tabstop1, tabstop2| {
+            if tabstop1.idx != tabstop2.idx {
+                return false;
+            }
+            // use the first non-empty tabstop for all mirrors of a tabstop
+            if matches!(tabstop2.kind, TabstopKind::Empty) {
+                swap(tabstop1, tabstop2)
+            }
+            true
+        })
+    }
+
+    fn ensure_last_tabstop(&mut self) {
+        if matches!(self.tabstops.last(), Some(tabstop) if tabstop.idx == LAST_TABSTOP_IDX) {
+            return;
+        }
+        self.tabstops.push(Tabstop {
+            idx: LAST_TABSTOP_IDX,
+            parent: None,
+            kind: TabstopKind::Empty,
+        });
+        self.elements.push(SnippetElement::Tabstop {
+            idx: LAST_TABSTOP_IDX,
+        });
+    }
+
+    fn renumber_tabstops(&mut self) {
+        Self::renumber_tabstops_in(&self.tabstops, &mut self.elements);
+        for i in 0..self.tabstops.len() {
+            if let Some(parent) = self.tabstops[i].parent {
+                let parent = self
+                    .tabstops
+                    .partition_point(|tabstop| tabstop.idx < parent);
+                self.tabstops[i].parent = Some(TabstopIdx(parent));
+            }
+            // Renumbering mutates every tabstop index in the default, so the
+            // elements are copied out of the (potentially shared) Arc and
+            // only frozen again afterwards. Relying on `Arc::get_mut` here
+            // would panic as soon as a default is shared, e.g. by a clone of
+            // the snippet.
+            if let TabstopKind::Placeholder { default } = &self.tabstops[i].kind {
+                let mut default = default.to_vec();
+                Self::renumber_tabstops_in(&self.tabstops, &mut default);
+                self.tabstops[i].kind = TabstopKind::Placeholder {
+                    default: default.into(),
+                };
+            }
+        }
+        for (i, tabstop) in self.tabstops.iter_mut().enumerate() {
+            tabstop.idx = TabstopIdx(i);
+        }
+    }
+
+    fn renumber_tabstops_in(tabstops: &[Tabstop], elements: &mut [SnippetElement]) {
+        for elem in elements {
+            match elem {
+                SnippetElement::Tabstop { idx } => {
+                    *idx = TabstopIdx(tabstops.partition_point(|tabstop| tabstop.idx < *idx))
+                }
+                SnippetElement::Variable { default, .. } => {
+                    Self::renumber_tabstops_in(tabstops, default)
+                }
+                SnippetElement::Text(_) => (),
+            }
+        }
+    }
+}
+
+impl Index<TabstopIdx> for Snippet {
+    type Output = Tabstop;
+    fn index(&self, index: TabstopIdx) -> &Tabstop {
+        &self.tabstops[index.0]
+    }
+}
+
+/// Constructs a [`Snippet`] programmatically, avoiding a round-trip through
+/// the textual syntax and its escaping rules.
+///
+/// ```
+/// # use helix_core::snippets::SnippetBuilder;
+/// let snippet = SnippetBuilder::new()
+///     .text("fn ")
+///     .placeholder(1, "name")
+///     .text("()")
+///     .build();
+/// assert_eq!(snippet.normalize(), "fn ${1:name}()$0");
+/// ```
+#[derive(Debug, Default)]
+pub struct SnippetBuilder {
+    elements: Vec<parser::SnippetElement>,
+}
+
+impl SnippetBuilder {
+    pub fn new() -> SnippetBuilder {
+        SnippetBuilder::default()
+    }
+
+    pub fn text(mut self, text: &str) -> Self {
+        self.elements.push(parser::SnippetElement::Text(text.into()));
+        self
+    }
+
+    pub fn tabstop(mut self, tabstop: usize) -> Self {
+        self.elements.push(parser::SnippetElement::Tabstop {
+            tabstop,
+            transform: None,
+        });
+        self
+    }
+
+    /// A tabstop whose mirrors render as `regex` applied to the text typed
+    /// into the tabstop, with the replacement described by [`FormatItem`]s.
+    pub fn tabstop_with_transform(
+        mut self,
+        tabstop: usize,
+        regex: &str,
+        replacement: Vec<FormatItem>,
+        options: &str,
+    ) -> Self {
+        self.elements.push(parser::SnippetElement::Tabstop {
+            tabstop,
+            transform: Some(parser::Transform {
+                regex: regex.into(),
+                replacement,
+                options: options.into(),
+            }),
+        });
+        self
+    }
+
+    pub fn placeholder(mut self, tabstop: usize, default: &str) -> Self {
+        self.elements.push(parser::SnippetElement::Placeholder {
+            tabstop,
+            value: vec![parser::SnippetElement::Text(default.into())],
+        });
+        self
+    }
+
+    pub fn choice(mut self, tabstop: usize, choices: &[&str]) -> Self {
+        self.elements.push(parser::SnippetElement::Choice {
+            tabstop,
+            choices: choices.iter().map(|&choice| choice.into()).collect(),
+        });
+        self
+    }
+
+    pub fn variable(mut self, name: &str, default: Option<&str>) -> Self {
+        self.elements.push(parser::SnippetElement::Variable {
+            name: name.into(),
+            default: default
+                .map(|default| vec![parser::SnippetElement::Text(default.into())])
+                .unwrap_or_default(),
+            transform: None,
+        });
+        self
+    }
+
+    pub fn variable_with_transform(
+        mut self,
+        name: &str,
+        regex: &str,
+        replacement: Vec<FormatItem>,
+        options: &str,
+    ) -> Self {
+        self.elements.push(parser::SnippetElement::Variable {
+            name: name.into(),
+            default: Vec::new(),
+            transform: Some(parser::Transform {
+                regex: regex.into(),
+                replacement,
+                options: options.into(),
+            }),
+        });
+        self
+    }
+
+    /// Elaborates the collected elements into a [`Snippet`]. Like parsing,
+    /// an invalid transform regex is logged and degrades into an empty
+    /// tabstop.
+    pub fn build(self) -> Snippet {
+        Snippet::new(self.elements)
+    }
+}
+
+/// Structural summary of a [`Snippet`] as produced by [`Snippet::report`].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SnippetReport {
+    /// Total number of tabstops, including the (implicit) final one.
+    pub tabstops: usize,
+    /// Number of additional occurrences of tabstops that occur more than once.
+    pub mirrors: usize,
+    pub choices: usize,
+    pub transforms: usize,
+    /// Maximum depth of placeholder/variable-default nesting.
+    pub nesting_depth: usize,
+    /// Names of the referenced variables, in order of first reference.
+    pub variables: Vec<Tendril>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SnippetElement {
+    Tabstop {
+        idx: TabstopIdx,
+    },
+    Variable {
+        name: Tendril,
+        default: Box<[SnippetElement]>,
+        transform: Option<Transform>,
+    },
+    Text(Tendril),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tabstop {
+    pub idx: TabstopIdx,
+    pub parent: Option<TabstopIdx>,
+    pub kind: TabstopKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TabstopKind {
+    Choice { choices: Arc<[Choice]> },
+    Placeholder { default: Arc<[SnippetElement]> },
+    Empty,
+    Transform(Arc<Transform>),
+}
+
+/// A single item of a choice tabstop. The description is an extension over
+/// the LSP snippet syntax (see [`Snippet::with_choice_descriptions`]) shown
+/// next to the value in choice popups.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Choice {
+    pub value: Tendril,
+    pub description: Option<Tendril>,
+}
+
+impl From<Tendril> for Choice {
+    fn from(value: Tendril) -> Self {
+        Choice {
+            value,
+            description: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Transform {
+    regex: regex::Regex,
+    regex_str: Box<str>,
+    global: bool,
+    options: Tendril,
+    replacement: Box<[FormatItem]>,
+}
+
+impl PartialEq for Transform {
+    fn eq(&self, other: &Self) -> bool {
+        self.replacement == other.replacement
+            && self.options == other.options
+            && self.regex_str == other.regex_str
+    }
+}
+
+impl Transform {
+    fn new(transform: parser::Transform) -> Result<Transform> {
+        let mut builder = RegexBuilder::new(&transform.regex);
+        let mut global = false;
+        for c in transform.options.chars() {
+            match c {
+                'i' => {
+                    builder.case_insensitive(true);
+                }
+                'm' => {
+                    builder.multi_line(true);
+                }
+                'g' => global = true,
+                // we ignore 'u' since we always operate on unicode
+                'u' => (),
+                c => log::warn!("ignoring unsupported transform option {c}"),
+            }
+        }
+        let regex = builder
+            .build()
+            .map_err(|err| anyhow!("invalid transform regex {:?}: {err}", transform.regex))?;
+        Ok(Transform {
+            regex,
+            regex_str: transform.regex.as_str().into(),
+            global,
+            options: transform.options,
+            replacement: transform.replacement.into_boxed_slice(),
+        })
+    }
+
+    fn write_normalized(&self, buf: &mut Tendril) {
+        buf.push('/');
+        write_escaped(buf, &self.regex_str, &['\\', '/']);
+        buf.push('/');
+        write_format_items(buf, &self.replacement);
+        buf.push('/');
+        buf.push_str(&self.options);
+    }
+
+    pub fn apply(&self, text: &str) -> Tendril {
+        let mut buf = Tendril::new();
+        let mut last_match = 0;
+        for captures in self.regex.captures_iter(text) {
+            let m = captures.get(0).unwrap();
+            buf.push_str(&text[last_match..m.start()]);
+            render_format_items(&mut buf, &captures, &self.replacement);
+            last_match = m.end();
+            if !self.global {
+                break;
+            }
+        }
+        buf.push_str(&text[last_match..]);
+        buf
+    }
+}
+
+// a transform serializes as its source (pattern, replacement, options) and
+// recompiles the regex on deserialization
+#[cfg(feature = "serde")]
+impl serde::Serialize for Transform {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let mut transform = serializer.serialize_struct("Transform", 3)?;
+        transform.serialize_field("regex", &*self.regex_str)?;
+        transform.serialize_field("replacement", &*self.replacement)?;
+        transform.serialize_field("options", self.options.as_str())?;
+        transform.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Transform {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        struct Repr {
+            regex: String,
+            replacement: Vec<FormatItem>,
+            options: String,
+        }
+        let repr = Repr::deserialize(deserializer)?;
+        Transform::new(parser::Transform {
+            regex: repr.regex.into(),
+            replacement: repr.replacement,
+            options: repr.options.into(),
+        })
+        .map_err(serde::de::Error::custom)
+    }
+}
+
+fn render_format_items(buf: &mut Tendril, captures: &regex::Captures, items: &[FormatItem]) {
+    for item in items {
+        match item {
+            FormatItem::Text(text) => buf.push_str(text),
+            FormatItem::Capture(i) => {
+                if let Some(cap) = captures.get(*i) {
+                    buf.push_str(cap.as_str());
+                }
+            }
+            FormatItem::CaseChange(i, change) => {
+                if let Some(cap) = captures.get(*i) {
+                    apply_case_change(buf, cap.as_str(), change);
+                }
+            }
+            FormatItem::Function(i, function) => {
+                if let Some(cap) = captures.get(*i) {
+                    apply_format_function(buf, cap.as_str(), function);
+                }
+            }
+            FormatItem::Conditional(i, if_, else_) => {
+                // branches may contain further conditionals ("else if" chains)
+                let branch = if captures.get(*i).map_or(false, |cap| !cap.as_str().is_empty()) {
+                    if_
+                } else {
+                    else_
+                };
+                render_format_items(buf, captures, branch);
+            }
+        }
+    }
+}
+
+fn write_format_items(buf: &mut Tendril, items: &[FormatItem]) {
+    use std::fmt::Write;
+    for item in items {
+        match item {
+            FormatItem::Text(text) => write_escaped(buf, text, &['\\', '/', '$']),
+            FormatItem::Capture(i) => write!(buf, "${i}").unwrap(),
+            FormatItem::CaseChange(i, change) => {
+                let change = match change {
+                    CaseChange::Upcase => "upcase",
+                    CaseChange::Downcase => "downcase",
+                    CaseChange::Capitalize => "capitalize",
+                };
+                write!(buf, "${{{i}:/{change}}}").unwrap();
+            }
+            FormatItem::Function(i, function) => {
+                write!(buf, "${{{i}:/").unwrap();
+                match function {
+                    FormatFunction::Pad { width, fill: ' ' } => {
+                        write!(buf, "pad({width})").unwrap()
+                    }
+                    FormatFunction::Pad { width, fill } => {
+                        write!(buf, "pad({width},{fill})").unwrap()
+                    }
+                    FormatFunction::UrlEncode => buf.push_str("urlencode"),
+                    FormatFunction::HtmlEscape => buf.push_str("htmlescape"),
+                    FormatFunction::Base64 => buf.push_str("base64"),
+                    FormatFunction::Trim => buf.push_str("trim"),
+                    FormatFunction::TrimStart => buf.push_str("trimstart"),
+                    FormatFunction::TrimEnd => buf.push_str("trimend"),
+                    FormatFunction::Oneline => buf.push_str("oneline"),
+                }
+                buf.push('}');
+            }
+            FormatItem::Conditional(i, if_, else_) if !if_.is_empty() && !else_.is_empty() => {
+                write!(buf, "${{{i}:?").unwrap();
+                write_format_items(buf, if_);
+                buf.push(':');
+                write_format_items(buf, else_);
+                buf.push('}');
+            }
+            FormatItem::Conditional(i, if_, _) if !if_.is_empty() => {
+                write!(buf, "${{{i}:+").unwrap();
+                write_format_items(buf, if_);
+                buf.push('}');
+            }
+            FormatItem::Conditional(i, _, else_) => {
+                write!(buf, "${{{i}:-").unwrap();
+                write_format_items(buf, else_);
+                buf.push('}');
+            }
+        }
+    }
+}
+
+fn write_escaped(buf: &mut Tendril, text: &str, escape_chars: &[char]) {
+    for c in text.chars() {
+        if escape_chars.contains(&c) {
+            buf.push('\\');
+        }
+        buf.push(c);
+    }
+}
+
+fn apply_format_function(buf: &mut Tendril, text: &str, function: &FormatFunction) {
+    use std::fmt::Write;
+    match function {
+        FormatFunction::Pad { width, fill } => {
+            for _ in text.chars().count()..*width {
+                buf.push(*fill);
+            }
+            buf.push_str(text);
+        }
+        FormatFunction::UrlEncode => {
+            for byte in text.bytes() {
+                match byte {
+                    b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                        buf.push(byte as char)
+                    }
+                    byte => write!(buf, "%{byte:02X}").unwrap(),
+                }
+            }
+        }
+        FormatFunction::HtmlEscape => {
+            for c in text.chars() {
+                match c {
+                    '&' => buf.push_str("&amp;"),
+                    '<' => buf.push_str("&lt;"),
+                    '>' => buf.push_str("&gt;"),
+                    '"' => buf.push_str("&quot;"),
+                    '\'' => buf.push_str("&#39;"),
+                    c => buf.push(c),
+                }
+            }
+        }
+        FormatFunction::Base64 => base64_encode(buf, text.as_bytes()),
+        FormatFunction::Trim => buf.push_str(text.trim()),
+        FormatFunction::TrimStart => buf.push_str(text.trim_start()),
+        FormatFunction::TrimEnd => buf.push_str(text.trim_end()),
+        FormatFunction::Oneline => {
+            let mut lines = text.lines().map(str::trim).filter(|line| !line.is_empty());
+            if let Some(line) = lines.next() {
+                buf.push_str(line);
+            }
+            for line in lines {
+                buf.push(' ');
+                buf.push_str(line);
+            }
+        }
+    }
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(buf: &mut Tendril, bytes: &[u8]) {
+    for chunk in bytes.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let group = u32::from(b[0]) << 16 | u32::from(b[1]) << 8 | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                buf.push(BASE64_ALPHABET[(group >> (18 - 6 * i)) as usize & 0x3f] as char);
+            } else {
+                buf.push('=');
+            }
+        }
+    }
+}
+
+fn apply_case_change(buf: &mut Tendril, text: &str, change: &CaseChange) {
+    match change {
+        CaseChange::Upcase => buf.extend(text.chars().flat_map(char::to_uppercase)),
+        CaseChange::Downcase => buf.extend(text.chars().flat_map(char::to_lowercase)),
+        CaseChange::Capitalize => {
+            let mut chars = text.chars();
+            if let Some(c) = chars.next() {
+                buf.extend(c.to_uppercase());
+            }
+            buf.push_str(chars.as_str());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::borrow::Cow;
+
+    use super::*;
+
+    fn tabstop_indices(snippet: &Snippet) -> Vec<usize> {
+        snippet.tabstops().map(|tabstop| tabstop.idx.0).collect()
+    }
+
+    #[test]
+    fn tabstops_are_renumbered() {
+        // tabstop numbers from the source don't survive elaboration, only
+        // their order does (with $0 sorted last)
+        let snippet = Snippet::parse("$4 $2 ${9:foo} $0").unwrap();
+        assert_eq!(tabstop_indices(&snippet), &[0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn last_tabstop_is_ensured() {
+        let snippet = Snippet::parse("fn $1()").unwrap();
+        // an implicit $0 is appended at the end of the snippet
+        assert_eq!(snippet.tabstops().count(), 2);
+        assert!(matches!(
+            snippet.elements().last(),
+            Some(SnippetElement::Tabstop { idx: TabstopIdx(1) })
+        ));
+    }
+
+    #[test]
+    fn mirrors_are_merged() {
+        let snippet = Snippet::parse("${1:foo} $1 $2").unwrap();
+        // tabstops: $1 (placeholder), $2, implicit $0
+        assert_eq!(snippet.tabstops().count(), 3);
+        assert!(matches!(
+            &snippet.tabstops().next().unwrap().kind,
+            TabstopKind::Placeholder { .. }
+        ));
+    }
+
+    #[test]
+    fn with_tabstop_default() {
+        let snippet = Snippet::parse("fn ${1:name}($2) {$0}").unwrap();
+        let bound = snippet.with_tabstop_default(1, "main").unwrap();
+        assert_eq!(bound.normalize(), "fn ${1:main}($2) {$0\\}");
+        // mirrors pick up the injected default as well
+        let snippet = Snippet::parse("${1:a} $1$0").unwrap();
+        let bound = snippet.with_tabstop_default(1, "b").unwrap();
+        assert_eq!(bound.normalize(), "${1:b} $1$0");
+        assert!(snippet.with_tabstop_default(5, "x").is_none());
+    }
+
+    #[test]
+    fn choice_descriptions() {
+        let snippet = Snippet::parse("${1|dbg,info|}$0").unwrap();
+        let snippet = snippet
+            .with_choice_descriptions(1, &["debug level", "info level"])
+            .unwrap();
+        let TabstopKind::Choice { choices } = &snippet.tabstops().next().unwrap().kind else {
+            panic!("expected a choice tabstop")
+        };
+        assert_eq!(choices[0].value, "dbg");
+        assert_eq!(choices[0].description.as_deref(), Some("debug level"));
+        // only choice tabstops can carry descriptions
+        assert!(Snippet::parse("$1")
+            .unwrap()
+            .with_choice_descriptions(1, &[])
+            .is_none());
+    }
+
+    #[test]
+    fn normalize() {
+        // equivalent spellings normalize to the same canonical form
+        let canonical = Snippet::parse("${1:foo} $1 $0").unwrap().normalize();
+        assert_eq!(canonical, "${1:foo} $1 $0");
+        assert_eq!(
+            Snippet::parse("${3:foo} ${3} ${0}").unwrap().normalize(),
+            canonical
+        );
+        // the implicit final tabstop is made explicit
+        assert_eq!(Snippet::parse("fn $2()").unwrap().normalize(), "fn $1()$0");
+        // escaping is minimal
+        assert_eq!(
+            Snippet::parse("\\$x ${2|1,\\,2|}").unwrap().normalize(),
+            "\\$x ${1|1,\\,2|}$0"
+        );
+    }
+
+    #[test]
+    fn to_lsp_string_round_trips() {
+        let snippet = Snippet::parse("fn ${1:name}($2) {$0}")
+            .unwrap()
+            .with_tabstop_default(1, "main")
+            .unwrap();
+        let lsp = snippet.to_lsp_string();
+        assert_eq!(lsp, "fn ${1:main}($2) {$0\\}");
+        assert_eq!(Snippet::parse(&lsp).unwrap(), snippet);
+    }
+
+    #[test]
+    fn preview() {
+        let snippet = Snippet::parse("fn ${1:name}($2) { $0 }").unwrap();
+        assert_eq!(snippet.preview(), "fn ⟨name⟩(⟨⟩) {  }");
+        let snippet = Snippet::parse("${1|pub,pub(crate)|} ${TM_FILENAME:file}$0").unwrap();
+        assert_eq!(snippet.preview(), "⟨pub⟩ file");
+        // variables without a default show their name
+        let snippet = Snippet::parse("$TM_SELECTED_TEXT$0").unwrap();
+        assert_eq!(snippet.preview(), "$TM_SELECTED_TEXT");
+    }
+
+    #[test]
+    fn report() {
+        let snippet =
+            Snippet::parse("${1:foo ${2|a,b|}} $1 ${TM_FILENAME/(.*)/$1/} ${name:$TM_SELECTED_TEXT}$0")
+                .unwrap();
+        let report = snippet.report();
+        // $1, $2 and the implicit $0
+        assert_eq!(report.tabstops, 3);
+        assert_eq!(report.mirrors, 1);
+        assert_eq!(report.choices, 1);
+        assert_eq!(report.transforms, 1);
+        assert_eq!(report.nesting_depth, 1);
+        assert_eq!(report.variables, &["TM_FILENAME", "name", "TM_SELECTED_TEXT"]);
+    }
+
+    #[test]
+    fn builder() {
+        let snippet = SnippetBuilder::new()
+            .placeholder(1, "name")
+            .text(": ")
+            .variable_with_transform(
+                "TM_FILENAME",
+                "(.*)",
+                vec![FormatItem::CaseChange(1, CaseChange::Upcase)],
+                "",
+            )
+            .tabstop(0)
+            .build();
+        assert_eq!(
+            snippet.normalize(),
+            "${1:name}: ${TM_FILENAME/(.*)/${1:/upcase}/}$0"
+        );
+    }
+
+    #[test]
+    fn conditional_chain() {
+        // "if group 1 -> A, else if group 2 -> B, else C" without nesting hacks
+        let apply = |input: &str| {
+            let snippet =
+                Snippet::parse("${var/(a)?(b)?.*/${1:?A:${2:?B:C}}/}$0").unwrap();
+            let bound = snippet.bind_variables(&mut |name: &str| {
+                (name == "var").then(|| Cow::from(input.to_owned()))
+            });
+            let SnippetElement::Text(text) = &bound.elements()[0] else {
+                panic!("expected the variable to be substituted")
+            };
+            text.to_string()
+        };
+        assert_eq!(apply("a"), "A");
+        assert_eq!(apply("b"), "B");
+        assert_eq!(apply("x"), "C");
+        // chains round-trip through the canonical form
+        assert_eq!(
+            Snippet::parse("${var/(a)?(b)?.*/${1:?A:${2:?B:C}}/}$0")
+                .unwrap()
+                .normalize(),
+            "${var/(a)?(b)?.*/${1:?A:${2:?B:C}}/}$0"
+        );
+    }
+
+    #[test]
+    fn pad_format_function() {
+        let snippet = Snippet::parse("${var/(.*)/${1:/pad(5,0)}/}$0").unwrap();
+        let bound =
+            snippet.bind_variables(&mut |name: &str| (name == "var").then(|| Cow::from("42")));
+        assert_eq!(bound.elements()[0], SnippetElement::Text("00042".into()));
+        // round-trips through the canonical form, eliding the default fill
+        assert_eq!(
+            Snippet::parse("${var/(.*)/${1:/pad(4)}/}$0")
+                .unwrap()
+                .normalize(),
+            "${var/(.*)/${1:/pad(4)}/}$0"
+        );
+    }
+
+    #[test]
+    fn encoding_format_functions() {
+        let apply = |snippet: &str, input: &str| {
+            let snippet = Snippet::parse(snippet).unwrap();
+            let bound = snippet.bind_variables(&mut |name: &str| {
+                (name == "var").then(|| Cow::from(input.to_owned()))
+            });
+            let SnippetElement::Text(text) = &bound.elements()[0] else {
+                panic!("expected the variable to be substituted")
+            };
+            text.to_string()
+        };
+        assert_eq!(
+            apply("${var/(.*)/${1:/urlencode}/}$0", "a b&c"),
+            "a%20b%26c"
+        );
+        assert_eq!(
+            apply("${var/(.*)/${1:/htmlescape}/}$0", "<a href=\"x\">&'"),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&#39;"
+        );
+        assert_eq!(apply("${var/(.*)/${1:/base64}/}$0", "light work."), "bGlnaHQgd29yay4=");
+        assert_eq!(apply("${var/(.*)/${1:/base64}/}$0", "light wor"), "bGlnaHQgd29y");
+    }
+
+    #[test]
+    fn trim_format_functions() {
+        let apply = |snippet: &str, input: &str| {
+            let snippet = Snippet::parse(snippet).unwrap();
+            let bound = snippet.bind_variables(&mut |name: &str| {
+                (name == "var").then(|| Cow::from(input.to_owned()))
+            });
+            let SnippetElement::Text(text) = &bound.elements()[0] else {
+                panic!("expected the variable to be substituted")
+            };
+            text.to_string()
+        };
+        assert_eq!(apply("${var/(?s)(.*)/${1:/trim}/}$0", "  foo "), "foo");
+        assert_eq!(apply("${var/(?s)(.*)/${1:/trimstart}/}$0", "  foo "), "foo ");
+        assert_eq!(apply("${var/(?s)(.*)/${1:/trimend}/}$0", "  foo "), "  foo");
+        assert_eq!(
+            apply(
+                "${var/(?s)(.*)/${1:/oneline}/}$0",
+                "if foo {\n    bar();\n\n}\n"
+            ),
+            "if foo { bar(); }"
+        );
+    }
+
+    #[test]
+    fn bind_variables_substitutes_known_values() {
+        let snippet = Snippet::parse("// ${TM_FILENAME}: ${1:$TM_SELECTED_TEXT}$0").unwrap();
+        let bound = snippet.bind_variables(&mut |var: &str| {
+            (var == "TM_FILENAME").then(|| Cow::from("main.rs"))
+        });
+        assert_eq!(
+            bound.elements()[0],
+            SnippetElement::Text("// main.rs: ".into())
+        );
+        // unresolved variables are left for render time
+        assert_eq!(snippet.tabstops().count(), bound.tabstops().count());
+    }
+}
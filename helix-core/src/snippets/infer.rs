@@ -0,0 +1,168 @@
+use std::ops::Range;
+
+use imara_diff::intern::InternedInput;
+use imara_diff::{Algorithm, Sink};
+
+use crate::snippets::{parser, Snippet};
+use crate::Tendril;
+
+impl Snippet {
+    /// Infers a snippet from a before/after example: the edited text becomes
+    /// the snippet body with a tabstop at every spot that differs from the
+    /// original, keeping the edited text as the placeholder. Differing spots
+    /// with identical text are merged into mirrors of a single tabstop. This
+    /// powers "create snippet from selection" style commands.
+    pub fn from_example(original: &str, edited: &str) -> Snippet {
+        let mut input = InternedInput::default();
+        input.update_before(original.chars());
+        input.update_after(edited.chars());
+        // like the char diffs in `diff.rs`: the histogram heuristic does not
+        // work well for characters, use myer diff instead
+        let elements = imara_diff::diff(
+            Algorithm::Myers,
+            &input,
+            ExampleBuilder {
+                input: &input,
+                elements: Vec::new(),
+                placeholders: Vec::new(),
+                pos: 0,
+                tabstop: 0,
+            },
+        );
+        Snippet::new(elements)
+    }
+
+    /// Builds a snippet from `text` with a tabstop at every user-marked
+    /// (char) region, numbered in the order the regions are given.
+    ///
+    /// Returns `None` if the regions are unsorted, overlapping or out of
+    /// bounds.
+    pub fn from_marked_regions(text: &str, regions: &[Range<usize>]) -> Option<Snippet> {
+        let char_to_byte: Vec<_> = text
+            .char_indices()
+            .map(|(pos, _)| pos)
+            .chain(Some(text.len()))
+            .collect();
+        let mut elements = Vec::new();
+        let mut last = 0;
+        for (i, region) in regions.iter().enumerate() {
+            if region.start < last || region.end < region.start || region.end >= char_to_byte.len()
+            {
+                return None;
+            }
+            let unchanged = &text[char_to_byte[last]..char_to_byte[region.start]];
+            if !unchanged.is_empty() {
+                elements.push(parser::SnippetElement::Text(unchanged.into()));
+            }
+            let value = &text[char_to_byte[region.start]..char_to_byte[region.end]];
+            elements.push(if value.is_empty() {
+                parser::SnippetElement::Tabstop {
+                    tabstop: i + 1,
+                    transform: None,
+                }
+            } else {
+                parser::SnippetElement::Placeholder {
+                    tabstop: i + 1,
+                    value: vec![parser::SnippetElement::Text(value.into())],
+                }
+            });
+            last = region.end;
+        }
+        let trailing = &text[char_to_byte[last]..];
+        if !trailing.is_empty() {
+            elements.push(parser::SnippetElement::Text(trailing.into()));
+        }
+        Some(Snippet::new(elements))
+    }
+}
+
+struct ExampleBuilder<'a> {
+    input: &'a InternedInput<char>,
+    elements: Vec<parser::SnippetElement>,
+    /// placeholder texts seen so far and the tabstop they were assigned
+    placeholders: Vec<(Tendril, usize)>,
+    pos: u32,
+    tabstop: usize,
+}
+
+impl ExampleBuilder<'_> {
+    fn text(&self, range: Range<u32>) -> Tendril {
+        self.input.after[range.start as usize..range.end as usize]
+            .iter()
+            .map(|&token| self.input.interner[token])
+            .collect()
+    }
+}
+
+impl Sink for ExampleBuilder<'_> {
+    type Out = Vec<parser::SnippetElement>;
+
+    fn process_change(&mut self, _before: Range<u32>, after: Range<u32>) {
+        let unchanged = self.text(self.pos..after.start);
+        if !unchanged.is_empty() {
+            self.elements.push(parser::SnippetElement::Text(unchanged));
+        }
+        let value = self.text(after.clone());
+        self.pos = after.end;
+        let element = if value.is_empty() {
+            self.tabstop += 1;
+            parser::SnippetElement::Tabstop {
+                tabstop: self.tabstop,
+                transform: None,
+            }
+        } else {
+            // identical placeholders become mirrors of the same tabstop
+            let tabstop = match self
+                .placeholders
+                .iter()
+                .find(|(placeholder, _)| *placeholder == value)
+            {
+                Some(&(_, tabstop)) => tabstop,
+                None => {
+                    self.tabstop += 1;
+                    self.placeholders.push((value.clone(), self.tabstop));
+                    self.tabstop
+                }
+            };
+            parser::SnippetElement::Placeholder {
+                tabstop,
+                value: vec![parser::SnippetElement::Text(value)],
+            }
+        };
+        self.elements.push(element);
+    }
+
+    fn finish(mut self) -> Self::Out {
+        let trailing = self.text(self.pos..self.input.after.len() as u32);
+        if !trailing.is_empty() {
+            self.elements.push(parser::SnippetElement::Text(trailing));
+        }
+        self.elements
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::snippets::Snippet;
+
+    #[test]
+    fn infers_tabstops_from_diff() {
+        let snippet = Snippet::from_example("println!(\"foo\")", "println!(\"bar\")");
+        assert_eq!(snippet.normalize(), "println!(\"${1:bar}\")$0");
+    }
+
+    #[test]
+    fn identical_changes_become_mirrors() {
+        let snippet = Snippet::from_example("let foo = foo;", "let bar = bar;");
+        assert_eq!(snippet.normalize(), "let ${1:bar} = $1;$0");
+    }
+
+    #[test]
+    fn marked_regions() {
+        let snippet = Snippet::from_marked_regions("fn name() {}", &[3..7]).unwrap();
+        assert_eq!(snippet.normalize(), "fn ${1:name}() {\\}$0");
+        // out of bounds and unsorted regions are rejected
+        assert!(Snippet::from_marked_regions("ab", &[1..5]).is_none());
+        assert!(Snippet::from_marked_regions("abc", &[1..2, 0..1]).is_none());
+    }
+}
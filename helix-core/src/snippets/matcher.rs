@@ -0,0 +1,143 @@
+use anyhow::Result;
+use regex::{Regex, RegexBuilder};
+
+use crate::snippets::elaborate::TabstopKind;
+use crate::snippets::{Snippet, SnippetElement, TabstopIdx};
+use crate::{smallvec, SmallVec};
+
+/// A tabstop located in matched text: the tabstop and the (char) ranges of
+/// its occurrences, mirrors included.
+pub type TabstopMatch = (TabstopIdx, SmallVec<[(usize, usize); 1]>);
+
+/// A matcher built from an elaborated [`Snippet`] that recognizes text which
+/// looks like an expansion of that snippet and locates the tabstop regions
+/// within it, see [`Snippet::matcher`].
+#[derive(Debug, Clone)]
+pub struct SnippetMatcher {
+    regex: Regex,
+    /// Maps every capture group (in order) to the tabstop it represents.
+    groups: Vec<TabstopIdx>,
+}
+
+impl SnippetMatcher {
+    /// Tries to interpret `text` as an expansion of the snippet. On success
+    /// returns the (char) ranges each tabstop covers, with mirrors required
+    /// to contain identical text.
+    pub fn matches(&self, text: &str) -> Option<Vec<TabstopMatch>> {
+        let captures = self.regex.captures(text)?;
+        let mut tabstops: Vec<TabstopMatch> = Vec::new();
+        for (group, &idx) in self.groups.iter().enumerate() {
+            let capture = captures.get(group + 1)?;
+            let range = (capture.start(), capture.end());
+            match tabstops.iter_mut().find(|(tabstop, _)| *tabstop == idx) {
+                Some((_, ranges)) => {
+                    let (start, end) = ranges[0];
+                    if text[start..end] != text[range.0..range.1] {
+                        return None;
+                    }
+                    ranges.push(range);
+                }
+                None => tabstops.push((idx, smallvec![range])),
+            }
+        }
+        tabstops.sort_by_key(|&(idx, _)| idx);
+        // report char ranges like the renderer does
+        for (_, ranges) in &mut tabstops {
+            for range in ranges {
+                let start = text[..range.0].chars().count();
+                let len = text[range.0..range.1].chars().count();
+                *range = (start, start + len);
+            }
+        }
+        Some(tabstops)
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        self.matches(text).is_some()
+    }
+}
+
+impl Snippet {
+    /// Builds a regex based matcher that identifies whether a piece of text
+    /// looks like an expansion of this snippet and where the tabstop regions
+    /// are, enabling "re-enter snippet" style features.
+    pub fn matcher(&self) -> Result<SnippetMatcher> {
+        let mut pattern = String::from("^");
+        let mut groups = Vec::new();
+        self.write_pattern(&mut pattern, self.elements(), &mut groups);
+        pattern.push('$');
+        let regex = RegexBuilder::new(&pattern)
+            .dot_matches_new_line(true)
+            .build()?;
+        Ok(SnippetMatcher { regex, groups })
+    }
+
+    fn write_pattern(
+        &self,
+        pattern: &mut String,
+        elements: &[SnippetElement],
+        groups: &mut Vec<TabstopIdx>,
+    ) {
+        for element in elements {
+            match element {
+                SnippetElement::Text(text) => pattern.push_str(&regex::escape(text)),
+                SnippetElement::Variable { default, .. } => {
+                    if default.is_empty() {
+                        // the value the variable resolved to is unknown
+                        pattern.push_str(".*?");
+                    } else {
+                        self.write_pattern(pattern, default, groups);
+                    }
+                }
+                &SnippetElement::Tabstop { idx } => {
+                    groups.push(idx);
+                    if let TabstopKind::Choice { choices } = &self[idx].kind {
+                        pattern.push('(');
+                        for (i, choice) in choices.iter().enumerate() {
+                            if i != 0 {
+                                pattern.push('|');
+                            }
+                            pattern.push_str(&regex::escape(&choice.value));
+                        }
+                        pattern.push(')');
+                    } else {
+                        // the user may have typed over the placeholder so the
+                        // default text is not required to match
+                        pattern.push_str("(.*?)");
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::snippets::Snippet;
+
+    #[test]
+    fn matches_expansion() {
+        let snippet = Snippet::parse("let ${1:name} = ${1:name};$0").unwrap();
+        let matcher = snippet.matcher().unwrap();
+        let tabstops = matcher.matches("let foo = foo;").unwrap();
+        assert_eq!(tabstops.len(), 2);
+        assert_eq!(&tabstops[0].1[..], &[(4, 7), (10, 13)]);
+        assert_eq!(&tabstops[1].1[..], &[(14, 14)]);
+    }
+
+    #[test]
+    fn mirrors_must_agree() {
+        let snippet = Snippet::parse("let ${1:name} = ${1:name};$0").unwrap();
+        let matcher = snippet.matcher().unwrap();
+        assert!(!matcher.is_match("let foo = bar;"));
+        assert!(!matcher.is_match("unrelated"));
+    }
+
+    #[test]
+    fn choices_restrict_matches() {
+        let snippet = Snippet::parse("${1|pub,priv|} fn$0").unwrap();
+        let matcher = snippet.matcher().unwrap();
+        assert!(matcher.is_match("pub fn"));
+        assert!(!matcher.is_match("private fn"));
+    }
+}
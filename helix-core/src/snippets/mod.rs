@@ -0,0 +1,35 @@
+pub mod active;
+pub mod elaborate;
+mod infer;
+mod matcher;
+mod parser;
+pub mod render;
+
+pub use active::{
+    ActiveSnippet, ActiveSnippets, Invalidity, MappingReport, NestingPolicy, OutlineEntry,
+    SnippetEvent, SnippetStack, TabstopInfo, ValidityPolicy, VisitOrder,
+};
+pub use elaborate::{Choice, Snippet, SnippetBuilder, SnippetElement, Transform};
+pub use matcher::{SnippetMatcher, TabstopMatch};
+pub use parser::{CaseChange, FormatFunction, FormatItem};
+#[cfg(feature = "render-metrics")]
+pub use render::RenderMetrics;
+pub use render::{
+    ClipboardProvider, CursorPlacement, CursorPlacementPolicy, DocumentVariables, EditMode,
+    InvisibleCharPolicy, MultilineValuePolicy, PendingVariable, RenderedSnippet,
+    ReplacementVariables, SnippetRenderCtx, SpanKind, StandardVariables, VariableContext,
+    VariableResolver,
+};
+
+#[derive(PartialEq, Eq, Hash, Debug, PartialOrd, Ord, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TabstopIdx(usize);
+pub const LAST_TABSTOP_IDX: TabstopIdx = TabstopIdx(usize::MAX);
+
+impl TabstopIdx {
+    /// Maps the tabstop index used in the snippet source to the internal
+    /// representation where `$0` always sorts last.
+    pub(crate) fn elaborate(idx: usize) -> Self {
+        TabstopIdx(idx.wrapping_sub(1))
+    }
+}
@@ -0,0 +1,792 @@
+//! This module implements parsing of the LSP snippet syntax as described in
+//! the [LSP specification].
+//!
+//! [LSP specification]: https://microsoft.github.io/language-server-protocol/specifications/lsp/3.17/specification/#snippet_syntax
+
+use crate::Tendril;
+use helix_parsec::*;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CaseChange {
+    Upcase,
+    Downcase,
+    Capitalize,
+}
+
+/// Format functions applicable to captures, a helix extension over the LSP
+/// snippet syntax (which only knows the case changes).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FormatFunction {
+    /// `pad(n[,char])`: left-pads the capture to `width` chars with `fill`
+    /// (a space unless given), for numbered lists, IDs or aligned columns.
+    Pad { width: usize, fill: char },
+    /// `urlencode`: percent-encodes everything but RFC 3986 unreserved chars.
+    UrlEncode,
+    /// `htmlescape`: escapes `&`, `<`, `>`, `"` and `'` as HTML entities.
+    HtmlEscape,
+    /// `base64`: standard (padded) base64 of the capture's UTF-8 bytes.
+    Base64,
+    /// `trim` / `trimstart` / `trimend`: strip surrounding whitespace.
+    Trim,
+    TrimStart,
+    TrimEnd,
+    /// `oneline`: trims every line and joins the non-empty ones with a
+    /// single space, so multi-line selections can be embedded inline.
+    Oneline,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FormatItem {
+    Text(Tendril),
+    Capture(usize),
+    CaseChange(usize, CaseChange),
+    Function(usize, FormatFunction),
+    /// `Conditional(capture, if, else)`: renders the `if` items when the
+    /// capture group matched non-empty text, the `else` items otherwise.
+    /// Branches may contain further conditionals, so "else if" chains over
+    /// multiple capture groups can be written as `${1:?A:${2:?B:C}}`.
+    Conditional(usize, Vec<FormatItem>, Vec<FormatItem>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transform {
+    pub regex: Tendril,
+    pub replacement: Vec<FormatItem>,
+    pub options: Tendril,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnippetElement {
+    Tabstop {
+        tabstop: usize,
+        transform: Option<Transform>,
+    },
+    Placeholder {
+        tabstop: usize,
+        value: Vec<SnippetElement>,
+    },
+    Choice {
+        tabstop: usize,
+        choices: Vec<Tendril>,
+    },
+    Variable {
+        name: Tendril,
+        default: Vec<SnippetElement>,
+        transform: Option<Transform>,
+    },
+    Text(Tendril),
+}
+
+pub fn parse(s: &str) -> Result<Vec<SnippetElement>, &str> {
+    snippet().parse(s).and_then(|(remainder, snippet)| {
+        if remainder.is_empty() {
+            Ok(snippet)
+        } else {
+            Err(remainder)
+        }
+    })
+}
+
+/*
+    any         ::= tabstop | placeholder | choice | variable | text
+    tabstop     ::= '$' int | '${' int '}'
+    placeholder ::= '${' int ':' any '}'
+    choice      ::= '${' int '|' text (',' text)* '|}'
+    variable    ::= '$' var | '${' var }'
+                    | '${' var ':' any '}'
+                    | '${' var '/' regex '/' (format | text)+ '/' options '}'
+    format      ::= '$' int | '${' int '}'
+                    | '${' int ':' '/upcase' | '/downcase' | '/capitalize' '}'
+                    | '${' int ':/' function '}'
+                    | '${' int ':+' if '}'
+                    | '${' int ':?' if ':' else '}'
+                    | '${' int ':-' else '}' | '${' int ':' else '}'
+    regex       ::= Regular Expression value (ctor-string)
+    options     ::= Regular Expression option (ctor-options)
+    function    ::= 'pad(' int (',' char)? ')'      (helix extension)
+                    | 'urlencode' | 'htmlescape' | 'base64'
+                    | 'trim' | 'trimstart' | 'trimend' | 'oneline'
+    var         ::= ('env:')? [_a-zA-Z] [_a-zA-Z0-9]*   ('env:' is a helix extension)
+    int         ::= [0-9]+
+    text        ::= .*
+    if          ::= text
+    else        ::= text
+*/
+
+fn var<'a>() -> impl Parser<'a, Output = &'a str> {
+    // var = ('env:')? [_a-zA-Z][_a-zA-Z0-9]*
+    fn ident_len(input: &str) -> usize {
+        input
+            .char_indices()
+            .take_while(|(p, c)| {
+                *c == '_'
+                    || if *p == 0 {
+                        c.is_ascii_alphabetic()
+                    } else {
+                        c.is_ascii_alphanumeric()
+                    }
+            })
+            .last()
+            .map_or(0, |(index, c)| index + c.len_utf8())
+    }
+    move |input: &'a str| {
+        let mut len = ident_len(input);
+        if len == 0 {
+            return Err(input);
+        }
+        // the 'env:' prefix is a helix extension, resolved to an
+        // environment variable by the standard variable resolver
+        if &input[..len] == "env" && input[len..].starts_with(':') {
+            let name = ident_len(&input[len + 1..]);
+            if name != 0 {
+                len += 1 + name;
+            }
+        }
+        Ok((&input[len..], &input[..len]))
+    }
+}
+
+const TEXT_ESCAPE_CHARS: &[char] = &['\\', '}', '$'];
+const CHOICE_TEXT_ESCAPE_CHARS: &[char] = &['\\', '|', ','];
+
+fn text<'a>(
+    escape_chars: &'static [char],
+    term_chars: &'static [char],
+) -> impl Parser<'a, Output = Tendril> {
+    move |input: &'a str| {
+        let mut chars = input.char_indices().peekable();
+        let mut res = Tendril::new();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => {
+                    if let Some(&(_, c)) = chars.peek() {
+                        if escape_chars.contains(&c) {
+                            chars.next();
+                            res.push(c);
+                            continue;
+                        }
+                    }
+                    res.push('\\');
+                }
+                c if term_chars.contains(&c) => return Ok((&input[i..], res)),
+                c => res.push(c),
+            }
+        }
+
+        Ok(("", res))
+    }
+}
+
+fn digit<'a>() -> impl Parser<'a, Output = usize> {
+    filter_map(take_while(|c| c.is_ascii_digit()), |s| s.parse().ok())
+}
+
+fn case_change<'a>() -> impl Parser<'a, Output = CaseChange> {
+    use CaseChange::*;
+
+    choice!(
+        map("upcase", |_| Upcase),
+        map("downcase", |_| Downcase),
+        map("capitalize", |_| Capitalize),
+    )
+}
+
+fn format_function<'a>() -> impl Parser<'a, Output = FormatFunction> {
+    choice!(
+        map(
+            seq!("pad(", digit(), optional(right(",", any_char(&[')']))), ")"),
+            |seq| FormatFunction::Pad {
+                width: seq.1,
+                fill: seq.2.unwrap_or(' '),
+            },
+        ),
+        map("urlencode", |_| FormatFunction::UrlEncode),
+        map("htmlescape", |_| FormatFunction::HtmlEscape),
+        map("base64", |_| FormatFunction::Base64),
+        // longer names first so "trim" doesn't shadow its variants
+        map("trimstart", |_| FormatFunction::TrimStart),
+        map("trimend", |_| FormatFunction::TrimEnd),
+        map("trim", |_| FormatFunction::Trim),
+        map("oneline", |_| FormatFunction::Oneline),
+    )
+}
+
+/// A single char that is not one of `term_chars`.
+fn any_char<'a>(term_chars: &'static [char]) -> impl Parser<'a, Output = char> {
+    move |input: &'a str| {
+        let mut chars = input.chars();
+        match chars.next() {
+            Some(c) if !term_chars.contains(&c) => Ok((chars.as_str(), c)),
+            _ => Err(input),
+        }
+    }
+}
+
+fn format<'a>() -> impl Parser<'a, Output = FormatItem> {
+    use FormatItem::*;
+
+    choice!(
+        // '$' int
+        map(right("$", digit()), Capture),
+        // '${' int '}'
+        map(seq!("${", digit(), "}"), |seq| Capture(seq.1)),
+        // '${' int ':' '/upcase' | '/downcase' | '/capitalize' '}'
+        map(seq!("${", digit(), ":/", case_change(), "}"), |seq| {
+            CaseChange(seq.1, seq.3)
+        }),
+        // '${' int ':/' function '}'
+        map(seq!("${", digit(), ":/", format_function(), "}"), |seq| {
+            Function(seq.1, seq.3)
+        }),
+        // '${' int ':+' if '}'
+        map(
+            seq!("${", digit(), ":+", conditional_branch(&['}', '$']), "}"),
+            |seq| { Conditional(seq.1, seq.3, Vec::new()) }
+        ),
+        // '${' int ':?' if ':' else '}'
+        map(
+            seq!(
+                "${",
+                digit(),
+                ":?",
+                conditional_branch(&[':', '$']),
+                ":",
+                conditional_branch(&['}', '$']),
+                "}"
+            ),
+            |seq| { Conditional(seq.1, seq.3, seq.5) }
+        ),
+        // '${' int ':-' else '}' | '${' int ':' else '}'
+        map(
+            seq!(
+                "${",
+                digit(),
+                ":",
+                optional("-"),
+                conditional_branch(&['}', '$']),
+                "}"
+            ),
+            |seq| { Conditional(seq.1, Vec::new(), seq.4) }
+        ),
+    )
+}
+
+/// The branches of conditionals may contain nested format items (most
+/// importantly further conditionals, which is how "else if" chains are
+/// expressed). The parser is constructed lazily since it is mutually
+/// recursive with [`format`].
+fn conditional_branch<'a>(
+    term_chars: &'static [char],
+) -> impl Parser<'a, Output = Vec<FormatItem>> {
+    move |input: &'a str| {
+        zero_or_more(choice!(
+            format(),
+            // text doesn't parse $, if format fails we just accept the $ as text
+            map("$", |_| FormatItem::Text("$".into())),
+            map(text(TEXT_ESCAPE_CHARS, term_chars), FormatItem::Text),
+        ))
+        .parse(input)
+    }
+}
+
+fn regex<'a>() -> impl Parser<'a, Output = Transform> {
+    map(
+        seq!(
+            "/",
+            // TODO parse as ECMAScript and convert to rust regex
+            text(&['/'], &['/']),
+            "/",
+            zero_or_more(choice!(
+                format(),
+                // text doesn't parse $, if format fails we just accept the $ as text
+                map("$", |_| FormatItem::Text("$".into())),
+                map(text(&['\\', '/'], &['/', '$']), FormatItem::Text),
+            )),
+            "/",
+            // vscode really doesn't allow escaping } here
+            // so it's impossible to write a regex escape containing a }
+            // we can consider deviating here and allowing the escape
+            text(&[], &['}']),
+        ),
+        |(_, value, _, replacement, _, options)| Transform {
+            regex: value,
+            replacement,
+            options,
+        },
+    )
+}
+
+fn tabstop<'a>() -> impl Parser<'a, Output = SnippetElement> {
+    map(
+        or(
+            map(right("$", digit()), |i| (i, None)),
+            map(seq!("${", digit(), optional(regex()), "}"), |values| {
+                (values.1, values.2)
+            }),
+        ),
+        |(tabstop, transform)| SnippetElement::Tabstop { tabstop, transform },
+    )
+}
+
+fn placeholder<'a>() -> impl Parser<'a, Output = SnippetElement> {
+    map(
+        seq!(
+            "${",
+            digit(),
+            ":",
+            // according to the grammar there is just a single anything here.
+            // However in the prose it is explained that placeholders can be nested.
+            // The example there contains both a placeholder text and a nested placeholder
+            // which indicates a list. Looking at the VSCode sourcecode, the placeholder
+            // is indeed parsed as zero_or_more so the grammar is simply incorrect here
+            zero_or_more(anything(TEXT_ESCAPE_CHARS, true)),
+            "}"
+        ),
+        |seq| SnippetElement::Placeholder {
+            tabstop: seq.1,
+            value: seq.3,
+        },
+    )
+}
+
+fn choice<'a>() -> impl Parser<'a, Output = SnippetElement> {
+    map(
+        seq!(
+            "${",
+            digit(),
+            "|",
+            sep(text(CHOICE_TEXT_ESCAPE_CHARS, &['|', ',']), ","),
+            "|}",
+        ),
+        |seq| SnippetElement::Choice {
+            tabstop: seq.1,
+            choices: seq.3,
+        },
+    )
+}
+
+fn variable<'a>() -> impl Parser<'a, Output = SnippetElement> {
+    choice!(
+        // $var
+        map(right("$", var()), |name| SnippetElement::Variable {
+            name: name.into(),
+            default: Vec::new(),
+            transform: None,
+        }),
+        // ${var}
+        map(seq!("${", var(), "}",), |values| SnippetElement::Variable {
+            name: values.1.into(),
+            default: Vec::new(),
+            transform: None,
+        }),
+        // ${var:default}
+        map(
+            seq!(
+                "${",
+                var(),
+                ":",
+                zero_or_more(anything(TEXT_ESCAPE_CHARS, true)),
+                "}",
+            ),
+            |values| SnippetElement::Variable {
+                name: values.1.into(),
+                default: values.3,
+                transform: None,
+            }
+        ),
+        // ${var/value/format/options}
+        map(seq!("${", var(), regex(), "}"), |values| {
+            SnippetElement::Variable {
+                name: values.1.into(),
+                default: Vec::new(),
+                transform: Some(values.2),
+            }
+        }),
+    )
+}
+
+fn anything<'a>(
+    escape_chars: &'static [char],
+    end_at_brace: bool,
+) -> impl Parser<'a, Output = SnippetElement> {
+    let term_chars: &[_] = if end_at_brace { &['$', '}'] } else { &['$'] };
+    move |input: &'a str| {
+        let parser = choice!(
+            tabstop(),
+            placeholder(),
+            choice(),
+            variable(),
+            map("$", |_| SnippetElement::Text("$".into())),
+            map(text(escape_chars, term_chars), SnippetElement::Text),
+        );
+        parser.parse(input)
+    }
+}
+
+fn snippet<'a>() -> impl Parser<'a, Output = Vec<SnippetElement>> {
+    one_or_more(anything(TEXT_ESCAPE_CHARS, false))
+}
+
+#[cfg(test)]
+mod test {
+    use super::SnippetElement::*;
+    use super::*;
+
+    #[test]
+    fn empty_string_is_error() {
+        assert_eq!(Err(""), parse(""));
+    }
+
+    #[test]
+    fn parse_placeholders_in_function_call() {
+        assert_eq!(
+            Ok(vec![
+                Text("match(".into()),
+                Placeholder {
+                    tabstop: 1,
+                    value: vec!(Text("Arg1".into())),
+                },
+                Text(")".into())
+            ]),
+            parse("match(${1:Arg1})")
+        )
+    }
+
+    #[test]
+    fn unterminated_placeholder() {
+        assert_eq!(
+            Ok(vec![
+                Text("match(".into()),
+                Text("$".into()),
+                Text("{1:)".into())
+            ]),
+            parse("match(${1:)")
+        )
+    }
+
+    #[test]
+    fn parse_empty_placeholder() {
+        assert_eq!(
+            Ok(vec![
+                Text("match(".into()),
+                Placeholder {
+                    tabstop: 1,
+                    value: vec![],
+                },
+                Text(")".into())
+            ]),
+            parse("match(${1:})")
+        )
+    }
+
+    #[test]
+    fn parse_placeholders_in_statement() {
+        assert_eq!(
+            Ok(vec![
+                Text("local ".into()),
+                Placeholder {
+                    tabstop: 1,
+                    value: vec!(Text("var".into())),
+                },
+                Text(" = ".into()),
+                Placeholder {
+                    tabstop: 1,
+                    value: vec!(Text("value".into())),
+                },
+            ]),
+            parse("local ${1:var} = ${1:value}")
+        )
+    }
+
+    #[test]
+    fn parse_tabstop_nested_in_placeholder() {
+        assert_eq!(
+            Ok(vec![Placeholder {
+                tabstop: 1,
+                value: vec!(
+                    Text("var, ".into()),
+                    Tabstop {
+                        tabstop: 2,
+                        transform: None
+                    },
+                ),
+            }]),
+            parse("${1:var, $2}")
+        )
+    }
+
+    #[test]
+    fn parse_placeholder_nested_in_placeholder() {
+        assert_eq!(
+            Ok(vec![Placeholder {
+                tabstop: 1,
+                value: vec!(
+                    Text("foo ".into()),
+                    Placeholder {
+                        tabstop: 2,
+                        value: vec!(Text("bar".into())),
+                    },
+                ),
+            }]),
+            parse("${1:foo ${2:bar}}")
+        )
+    }
+
+    #[test]
+    fn parse_all() {
+        assert_eq!(
+            Ok(vec![
+                Text("hello ".into()),
+                Tabstop {
+                    tabstop: 1,
+                    transform: None
+                },
+                Tabstop {
+                    tabstop: 2,
+                    transform: None
+                },
+                Text(" ".into()),
+                Choice {
+                    tabstop: 1,
+                    choices: vec!["one".into(), "two".into(), "three".into()]
+                },
+                Text(" ".into()),
+                Variable {
+                    name: "name".into(),
+                    default: vec![Text("foo".into())],
+                    transform: None
+                },
+                Text(" ".into()),
+                Variable {
+                    name: "var".into(),
+                    default: Vec::new(),
+                    transform: None
+                },
+                Text(" ".into()),
+                Variable {
+                    name: "TM".into(),
+                    default: Vec::new(),
+                    transform: None
+                },
+            ]),
+            parse("hello $1${2} ${1|one,two,three|} ${name:foo} $var $TM")
+        );
+    }
+
+    #[test]
+    fn regex_capture_replace() {
+        assert_eq!(
+            Ok(vec![Variable {
+                name: "TM_FILENAME".into(),
+                default: Vec::new(),
+                transform: Some(Transform {
+                    regex: "(.*).+$".into(),
+                    replacement: vec![FormatItem::Capture(1), FormatItem::Text("$".into())],
+                    options: Tendril::new(),
+                }),
+            }]),
+            parse("${TM_FILENAME/(.*).+$/$1$/}")
+        );
+    }
+
+    #[test]
+    fn parse_env_variable() {
+        assert_eq!(
+            Ok(vec![Variable {
+                name: "env:PATH".into(),
+                default: Vec::new(),
+                transform: None,
+            }]),
+            parse("${env:PATH}")
+        );
+        // without a name after the prefix, 'env' is an ordinary variable
+        assert_eq!(
+            Ok(vec![
+                Variable {
+                    name: "env".into(),
+                    default: Vec::new(),
+                    transform: None,
+                },
+                Text(": ".into()),
+            ]),
+            parse("$env: ")
+        );
+    }
+
+    #[test]
+    fn tabstop_transform() {
+        assert_eq!(
+            Ok(vec![Tabstop {
+                tabstop: 1,
+                transform: Some(Transform {
+                    regex: "(.*)".into(),
+                    replacement: vec![
+                        FormatItem::CaseChange(1, CaseChange::Upcase),
+                        FormatItem::Text("!".into()),
+                    ],
+                    options: Tendril::new(),
+                }),
+            }]),
+            parse("${1/(.*)/${1:/upcase}!/}")
+        );
+    }
+
+    #[test]
+    fn rust_macro() {
+        assert_eq!(
+            Ok(vec![
+                Text("macro_rules! ".into()),
+                Tabstop {
+                    tabstop: 1,
+                    transform: None
+                },
+                Text(" {\n    (".into()),
+                Tabstop {
+                    tabstop: 2,
+                    transform: None
+                },
+                Text(") => {\n        ".into()),
+                Tabstop {
+                    tabstop: 0,
+                    transform: None
+                },
+                Text("\n    };\n}".into())
+            ]),
+            parse("macro_rules! $1 {\n    ($2) => {\n        $0\n    };\n}")
+        );
+    }
+
+    #[test]
+    fn parse_variable_transform() {
+        assert_eq!(
+            Ok(vec![Variable {
+                name: "foo".into(),
+                default: Vec::new(),
+                transform: Some(Transform {
+                    regex: "regex".into(),
+                    replacement: vec![FormatItem::Text("format".into())],
+                    options: "gmi".into(),
+                }),
+            }]),
+            parse("${foo/regex/format/gmi}")
+        );
+        assert_eq!(
+            Ok(vec![Variable {
+                name: "foo".into(),
+                default: Vec::new(),
+                transform: Some(Transform {
+                    regex: "m/atch".into(),
+                    replacement: vec![FormatItem::Capture(1)],
+                    options: "i".into(),
+                }),
+            }]),
+            parse("${foo/m\\/atch/$1/i}")
+        );
+        assert_eq!(
+            Ok(vec![Variable {
+                name: "foo".into(),
+                default: Vec::new(),
+                transform: Some(Transform {
+                    regex: ".*".into(),
+                    replacement: vec![
+                        FormatItem::Text("complex".into()),
+                        FormatItem::Conditional(
+                            1,
+                            vec![FormatItem::Text("if".into())],
+                            vec![FormatItem::Text("else".into())],
+                        ),
+                    ],
+                    options: "i".into(),
+                }),
+            }]),
+            parse("${foo/.*/complex${1:?if:else}/i}")
+        );
+    }
+
+    #[test]
+    fn parse_format_function() {
+        assert_eq!(
+            Ok(vec![Tabstop {
+                tabstop: 1,
+                transform: Some(Transform {
+                    regex: "(.*)".into(),
+                    replacement: vec![FormatItem::Function(
+                        1,
+                        FormatFunction::Pad {
+                            width: 3,
+                            fill: '0'
+                        }
+                    )],
+                    options: Tendril::new(),
+                }),
+            }]),
+            parse("${1/(.*)/${1:/pad(3,0)}/}")
+        );
+        // the fill char defaults to a space
+        assert_eq!(
+            Ok(vec![Tabstop {
+                tabstop: 1,
+                transform: Some(Transform {
+                    regex: "(.*)".into(),
+                    replacement: vec![FormatItem::Function(
+                        1,
+                        FormatFunction::Pad {
+                            width: 8,
+                            fill: ' '
+                        }
+                    )],
+                    options: Tendril::new(),
+                }),
+            }]),
+            parse("${1/(.*)/${1:/pad(8)}/}")
+        );
+    }
+
+    #[test]
+    fn parse_conditional_chain() {
+        // "if group 1 -> A, else if group 2 -> B, else C"
+        assert_eq!(
+            Ok(vec![Variable {
+                name: "foo".into(),
+                default: Vec::new(),
+                transform: Some(Transform {
+                    regex: "(a)?(b)?.*".into(),
+                    replacement: vec![FormatItem::Conditional(
+                        1,
+                        vec![FormatItem::Text("A".into())],
+                        vec![FormatItem::Conditional(
+                            2,
+                            vec![FormatItem::Text("B".into())],
+                            vec![FormatItem::Text("C".into())],
+                        )],
+                    )],
+                    options: Tendril::new(),
+                }),
+            }]),
+            parse("${foo/(a)?(b)?.*/${1:?A:${2:?B:C}}/}")
+        );
+        // captures are allowed inside branches as well
+        assert_eq!(
+            Ok(vec![Variable {
+                name: "foo".into(),
+                default: Vec::new(),
+                transform: Some(Transform {
+                    regex: "(x)?".into(),
+                    replacement: vec![FormatItem::Conditional(
+                        1,
+                        vec![
+                            FormatItem::Text("got ".into()),
+                            FormatItem::Capture(1),
+                        ],
+                        Vec::new(),
+                    )],
+                    options: Tendril::new(),
+                }),
+            }]),
+            parse("${foo/(x)?/${1:+got $1}/}")
+        );
+    }
+}
@@ -0,0 +1,3554 @@
+use std::borrow::Cow;
+use std::hash::{BuildHasher, Hasher};
+use std::ops::{Index, IndexMut};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::NaiveDateTime;
+
+use crate::graphemes::{ensure_grapheme_boundary_next, ensure_grapheme_boundary_prev};
+use crate::indent::{indent_level_for_line, IndentStyle};
+use crate::movement::Direction;
+use crate::snippets::elaborate::{self, Choice};
+use crate::snippets::TabstopIdx;
+use crate::snippets::{Snippet, SnippetElement, Transform};
+use crate::{Range, Rope, RopeSlice, Selection, SmallVec, Tendril, Transaction};
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TabstopKind {
+    Choice { choices: Arc<[Choice]> },
+    Placeholder,
+    Empty,
+    Transform(Arc<Transform>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Tabstop {
+    pub ranges: SmallVec<[Range; 1]>,
+    /// Byte ranges parallel to `ranges`, tracked during rendering so that
+    /// consumers indexing by bytes (tree-sitter, LSP offset encodings)
+    /// don't have to re-walk the rendered text. For [`Snippet::render`]
+    /// these are positions in the changed document, for
+    /// [`Snippet::render_at`] they are relative to the replacement text.
+    pub byte_ranges: SmallVec<[(usize, usize); 1]>,
+    pub parent: Option<TabstopIdx>,
+    pub kind: TabstopKind,
+}
+
+impl Tabstop {
+    pub fn has_placeholder(&self) -> bool {
+        matches!(
+            self.kind,
+            TabstopKind::Choice { .. } | TabstopKind::Placeholder
+        )
+    }
+
+    pub fn selection(
+        &self,
+        direction: Direction,
+        primary_idx: usize,
+        snippet_ranges: usize,
+    ) -> Selection {
+        self.selection_with_placement(
+            direction,
+            primary_idx,
+            snippet_ranges,
+            CursorPlacement::Select,
+        )
+    }
+
+    /// Like [`Tabstop::selection`] but placing the cursor according to
+    /// `placement` instead of always selecting the tabstop text.
+    pub fn selection_with_placement(
+        &self,
+        direction: Direction,
+        primary_idx: usize,
+        snippet_ranges: usize,
+        placement: CursorPlacement,
+    ) -> Selection {
+        // each snippet instance contributes the same number of ranges,
+        // so scale the primary index accordingly
+        let primary_idx = primary_idx * (self.ranges.len() / snippet_ranges);
+        Selection::new(
+            self.ranges
+                .iter()
+                .map(|&range| match placement {
+                    CursorPlacement::Select => range.with_direction(direction),
+                    CursorPlacement::Start => Range::point(range.from()),
+                    CursorPlacement::End => Range::point(range.to()),
+                })
+                .collect(),
+            primary_idx,
+        )
+    }
+}
+
+/// Where the cursor goes when a tabstop is entered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CursorPlacement {
+    /// Select the tabstop text.
+    #[default]
+    Select,
+    /// Collapse the cursor to the start of the tabstop.
+    Start,
+    /// Collapse the cursor to the end of the tabstop.
+    End,
+}
+
+/// Cursor placement per tabstop kind. The default selects everywhere;
+/// users coming from VSCode typically want mirrors to collapse instead of
+/// being selected.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CursorPlacementPolicy {
+    pub placeholder: CursorPlacement,
+    pub choice: CursorPlacement,
+    pub empty: CursorPlacement,
+    /// Placement for mirrors with a transform.
+    pub mirror: CursorPlacement,
+}
+
+impl CursorPlacementPolicy {
+    pub fn placement(&self, kind: &TabstopKind) -> CursorPlacement {
+        match kind {
+            TabstopKind::Choice { .. } => self.choice,
+            TabstopKind::Placeholder => self.placeholder,
+            TabstopKind::Empty => self.empty,
+            TabstopKind::Transform(_) => self.mirror,
+        }
+    }
+}
+
+/// What a rendered span of the replacement text came from, see
+/// [`Snippet::render_at_with_spans`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SpanKind {
+    /// Literal snippet text.
+    Text,
+    /// The resolved value of a variable.
+    Variable,
+    /// A tabstop region, including its placeholder or choice text.
+    Tabstop,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RenderedSnippet {
+    pub tabstops: Vec<Tabstop>,
+    pub ranges: Vec<Range>,
+    /// Byte ranges parallel to `ranges`, see [`Tabstop::byte_ranges`].
+    pub byte_ranges: Vec<(usize, usize)>,
+    /// Variables the resolver marked [pending](VariableResolver::is_pending)
+    /// during rendering, to be patched in later with
+    /// [`RenderedSnippet::resolve_pending`].
+    pub pending_variables: Vec<PendingVariable>,
+    /// Every variable occurrence whose text came (or will come) from the
+    /// variable's value rather than its default, so the text can be patched
+    /// when the value changes, see [`RenderedSnippet::update_variable`].
+    pub variables: Vec<PendingVariable>,
+    /// Positions of `${ALIGN}` markers as `(char_idx, byte_idx, column)`
+    /// triples, consumed by the alignment pass of
+    /// [`Snippet::render_aligned`]. The marker itself renders as nothing.
+    pub align_points: Vec<(usize, usize, usize)>,
+    /// The (char) positions immediately inside a bracket or quote the
+    /// snippet inserted, in document order. Auto-pairs can consult these to
+    /// avoid doubling up a closer the snippet already provides.
+    pub pair_positions: Vec<usize>,
+    /// Non-overlapping spans describing what each piece of the replacement
+    /// text came from, in document order. Only recorded by
+    /// [`Snippet::render_at_with_spans`], empty otherwise.
+    pub spans: Vec<(Range, SpanKind)>,
+    /// What this render cost, summed over all instances.
+    #[cfg(feature = "render-metrics")]
+    pub metrics: RenderMetrics,
+}
+
+/// Counters collected while rendering, so embedders can spot pathological
+/// snippets (huge transforms, slow resolvers) slowing down completion
+/// accept. Only available with the `render-metrics` feature.
+#[cfg(feature = "render-metrics")]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RenderMetrics {
+    /// Snippet elements rendered, counting every mirror and every element
+    /// of an expanded default.
+    pub elements: usize,
+    /// Bytes of replacement text produced.
+    pub bytes: usize,
+    /// Time spent applying regex transforms.
+    pub transform_time: std::time::Duration,
+    /// Time spent in [`VariableResolver::resolve_var`].
+    pub resolver_time: std::time::Duration,
+}
+
+#[cfg(feature = "render-metrics")]
+impl std::ops::AddAssign for RenderMetrics {
+    fn add_assign(&mut self, rhs: RenderMetrics) {
+        self.elements += rhs.elements;
+        self.bytes += rhs.bytes;
+        self.transform_time += rhs.transform_time;
+        self.resolver_time += rhs.resolver_time;
+    }
+}
+
+/// A variable occurrence and the (char) range of the text it rendered:
+/// its resolved value, or the default text for
+/// [pending](VariableResolver::is_pending) variables whose value wasn't
+/// available yet (clipboard, shell output, ...).
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PendingVariable {
+    pub name: Tendril,
+    /// The (char) range the default text occupies, a document range for
+    /// [`Snippet::render`] and replacement relative for [`Snippet::render_at`].
+    pub range: Range,
+    pub ctx: VariableContext,
+    transform: Option<Transform>,
+}
+
+impl PendingVariable {
+    /// The text this occurrence displays for `value`, applying the
+    /// occurrence's transform if it carries one.
+    pub fn render_value(&self, value: &str) -> Tendril {
+        match &self.transform {
+            Some(transform) => transform.apply(value),
+            None => value.into(),
+        }
+    }
+}
+
+impl RenderedSnippet {
+    pub fn first_selection(&self, direction: Direction, primary_idx: usize) -> Selection {
+        self.tabstops[0].selection(direction, primary_idx, self.ranges.len())
+    }
+
+    /// The selection for jumping straight to an arbitrary tabstop right
+    /// after expansion (e.g. "expand and go to body"). Returns `None` for
+    /// an unknown tabstop or one that rendered no ranges.
+    pub fn selection_for_tabstop(
+        &self,
+        idx: TabstopIdx,
+        direction: Direction,
+        primary_idx: usize,
+    ) -> Option<Selection> {
+        let tabstop = self.tabstops.get(idx.0)?;
+        (!tabstop.ranges.is_empty())
+            .then(|| tabstop.selection(direction, primary_idx, self.ranges.len()))
+    }
+
+    /// Snaps all rendered (char) ranges outward to grapheme cluster
+    /// boundaries of `text` (the document after applying the render
+    /// transaction), so selections built from them are cursor-safe even
+    /// when the snippet text joins with combining characters around the
+    /// insert position.
+    pub fn snap_to_graphemes(&mut self, text: RopeSlice) {
+        let snap = |range: &mut Range| {
+            *range = Range::new(
+                ensure_grapheme_boundary_prev(text, range.from()),
+                ensure_grapheme_boundary_next(text, range.to()),
+            );
+        };
+        for tabstop in &mut self.tabstops {
+            tabstop.ranges.iter_mut().for_each(snap);
+        }
+        self.ranges.iter_mut().for_each(snap);
+    }
+
+    /// Merges the tabstop ranges of a snippet rendered at another selection
+    /// into `self`.
+    fn push(&mut self, snippet: RenderedSnippet) {
+        if self.tabstops.is_empty() {
+            *self = snippet;
+            return;
+        }
+        for (dst, src) in self.tabstops.iter_mut().zip(snippet.tabstops) {
+            dst.ranges.extend(src.ranges);
+            dst.byte_ranges.extend(src.byte_ranges);
+        }
+        self.ranges.extend(snippet.ranges);
+        self.byte_ranges.extend(snippet.byte_ranges);
+        self.pending_variables.extend(snippet.pending_variables);
+        self.variables.extend(snippet.variables);
+        self.align_points.extend(snippet.align_points);
+        self.pair_positions.extend(snippet.pair_positions);
+        self.spans.extend(snippet.spans);
+        #[cfg(feature = "render-metrics")]
+        {
+            self.metrics += snippet.metrics;
+        }
+    }
+
+    /// Produces the follow-up transaction replacing the default text of
+    /// [pending variables](RenderedSnippet::pending_variables) with their
+    /// now-available values, applying any transform the variable carries.
+    /// `doc` is the document the render transaction was applied to.
+    /// Returns `None` when nothing is pending or the resolver still has no
+    /// values.
+    pub fn resolve_pending(
+        &self,
+        doc: &Rope,
+        resolve_var: &mut dyn VariableResolver,
+    ) -> Option<Transaction> {
+        let mut changes = Vec::new();
+        for pending in &self.pending_variables {
+            let Some(value) = resolve_var.resolve_var(&pending.name, &pending.ctx) else {
+                continue;
+            };
+            changes.push((
+                pending.range.from(),
+                pending.range.to(),
+                Some(pending.render_value(&value)),
+            ));
+        }
+        if changes.is_empty() {
+            return None;
+        }
+        Some(Transaction::change(doc, changes.into_iter()))
+    }
+
+    /// Produces the transaction replacing the text of every
+    /// [occurrence](RenderedSnippet::variables) of the variable `name` with
+    /// `value`, leaving everything else -- in particular text the user typed
+    /// into tabstops -- intact. `doc` is the document the render transaction
+    /// was applied to. Returns `None` when the snippet contains no such
+    /// occurrence.
+    pub fn update_variable(&self, doc: &Rope, name: &str, value: &str) -> Option<Transaction> {
+        let changes: Vec<_> = self
+            .variables
+            .iter()
+            .filter(|var| &*var.name == name)
+            .map(|var| {
+                (
+                    var.range.from(),
+                    var.range.to(),
+                    Some(var.render_value(value)),
+                )
+            })
+            .collect();
+        if changes.is_empty() {
+            return None;
+        }
+        Some(Transaction::change(doc, changes.into_iter()))
+    }
+
+    /// Locates the tabstop covering the rendered (char) position, returning
+    /// its index and which of its mirror ranges contains `char_idx` -- e.g.
+    /// for mouse-clicking into a specific placeholder. When tabstops nest
+    /// the innermost covering range wins.
+    pub fn tabstop_at(&self, char_idx: usize) -> Option<(TabstopIdx, usize)> {
+        let mut best: Option<(TabstopIdx, usize, usize)> = None;
+        for (idx, tabstop) in self.tabstops.iter().enumerate() {
+            for (range_idx, range) in tabstop.ranges.iter().enumerate() {
+                if range.from() <= char_idx && char_idx <= range.to() {
+                    let width = range.to() - range.from();
+                    if best.map_or(true, |(_, _, best_width)| width <= best_width) {
+                        best = Some((TabstopIdx(idx), range_idx, width));
+                    }
+                }
+            }
+        }
+        best.map(|(idx, range_idx, _)| (idx, range_idx))
+    }
+
+    /// Splits the render into one snippet per instance (selection), each
+    /// keeping only the tabstop ranges, variables and other recorded
+    /// positions falling inside its instance range. Used to run independent
+    /// [sessions](crate::snippets::ActiveSnippets) per cursor instead of
+    /// one shared one. The metrics (with the `render-metrics` feature) stay
+    /// those of the whole render on every instance.
+    pub fn split_per_instance(self) -> Vec<RenderedSnippet> {
+        let instance_of = |from: usize, to: usize| {
+            self.ranges
+                .iter()
+                .position(|range| range.from() <= from && to <= range.to())
+        };
+        let mut instances: Vec<RenderedSnippet> = self
+            .ranges
+            .iter()
+            .zip(&self.byte_ranges)
+            .map(|(&range, &byte_range)| RenderedSnippet {
+                ranges: vec![range],
+                byte_ranges: vec![byte_range],
+                tabstops: self
+                    .tabstops
+                    .iter()
+                    .map(|tabstop| Tabstop {
+                        ranges: SmallVec::new(),
+                        byte_ranges: SmallVec::new(),
+                        parent: tabstop.parent,
+                        kind: tabstop.kind.clone(),
+                    })
+                    .collect(),
+                #[cfg(feature = "render-metrics")]
+                metrics: self.metrics,
+                ..RenderedSnippet::default()
+            })
+            .collect();
+        for (idx, tabstop) in self.tabstops.iter().enumerate() {
+            for (range, byte_range) in tabstop.ranges.iter().zip(&tabstop.byte_ranges) {
+                if let Some(i) = instance_of(range.from(), range.to()) {
+                    instances[i].tabstops[idx].ranges.push(*range);
+                    instances[i].tabstops[idx].byte_ranges.push(*byte_range);
+                }
+            }
+        }
+        for var in &self.pending_variables {
+            if let Some(i) = instance_of(var.range.from(), var.range.to()) {
+                instances[i].pending_variables.push(var.clone());
+            }
+        }
+        for var in &self.variables {
+            if let Some(i) = instance_of(var.range.from(), var.range.to()) {
+                instances[i].variables.push(var.clone());
+            }
+        }
+        for &point in &self.align_points {
+            if let Some(i) = instance_of(point.0, point.0) {
+                instances[i].align_points.push(point);
+            }
+        }
+        for &pos in &self.pair_positions {
+            if let Some(i) = instance_of(pos, pos) {
+                instances[i].pair_positions.push(pos);
+            }
+        }
+        for &(range, kind) in &self.spans {
+            if let Some(i) = instance_of(range.from(), range.to()) {
+                instances[i].spans.push((range, kind));
+            }
+        }
+        instances
+    }
+
+    /// Clears all recorded ranges, keeping the allocations for reuse, see
+    /// [`Snippet::render_at_into`].
+    fn clear(&mut self) {
+        self.tabstops.clear();
+        self.ranges.clear();
+        self.byte_ranges.clear();
+        self.pending_variables.clear();
+        self.variables.clear();
+        self.align_points.clear();
+        self.pair_positions.clear();
+        self.spans.clear();
+        #[cfg(feature = "render-metrics")]
+        {
+            self.metrics = RenderMetrics::default();
+        }
+    }
+
+    /// Shifts all recorded (char) positions to document positions, used
+    /// when a snippet rendered replacement relative is merged into a
+    /// document transaction, see [`Snippet::render_aligned`].
+    fn offset_char_positions(&mut self, offset: usize) {
+        let offset_range = |range: &mut Range| {
+            range.anchor += offset;
+            range.head += offset;
+        };
+        for tabstop in &mut self.tabstops {
+            tabstop.ranges.iter_mut().for_each(offset_range);
+        }
+        self.ranges.iter_mut().for_each(offset_range);
+        for var in self
+            .pending_variables
+            .iter_mut()
+            .chain(&mut self.variables)
+        {
+            offset_range(&mut var.range);
+        }
+        for (char_idx, ..) in &mut self.align_points {
+            *char_idx += offset;
+        }
+        for pos in &mut self.pair_positions {
+            *pos += offset;
+        }
+        for (range, _) in &mut self.spans {
+            offset_range(range);
+        }
+    }
+
+    /// Shifts every position at or after an insertion point, used when the
+    /// alignment pass of [`Snippet::render_aligned`] pads an expansion.
+    fn shift_for_insertion(&mut self, char_idx: usize, chars: usize, byte_idx: usize, bytes: usize) {
+        let shift_char = |pos: &mut usize| {
+            if *pos >= char_idx {
+                *pos += chars;
+            }
+        };
+        let shift_range = |range: &mut Range| {
+            shift_char(&mut range.anchor);
+            shift_char(&mut range.head);
+        };
+        let shift_byte = |pos: &mut usize| {
+            if *pos >= byte_idx {
+                *pos += bytes;
+            }
+        };
+        for tabstop in &mut self.tabstops {
+            tabstop.ranges.iter_mut().for_each(shift_range);
+            for (start, end) in &mut tabstop.byte_ranges {
+                shift_byte(start);
+                shift_byte(end);
+            }
+        }
+        self.ranges.iter_mut().for_each(shift_range);
+        for (start, end) in &mut self.byte_ranges {
+            shift_byte(start);
+            shift_byte(end);
+        }
+        for var in self
+            .pending_variables
+            .iter_mut()
+            .chain(&mut self.variables)
+        {
+            shift_range(&mut var.range);
+        }
+        for (point_char, point_byte, _) in &mut self.align_points {
+            shift_char(point_char);
+            shift_byte(point_byte);
+        }
+        self.pair_positions.iter_mut().for_each(shift_char);
+        for (range, _) in &mut self.spans {
+            shift_range(range);
+        }
+    }
+
+    /// Shifts the (replacement relative) byte ranges to document positions.
+    fn offset_byte_ranges(&mut self, offset: usize) {
+        for tabstop in &mut self.tabstops {
+            for (start, end) in &mut tabstop.byte_ranges {
+                *start += offset;
+                *end += offset;
+            }
+        }
+        for (start, end) in &mut self.byte_ranges {
+            *start += offset;
+            *end += offset;
+        }
+        for (_, byte_idx, _) in &mut self.align_points {
+            *byte_idx += offset;
+        }
+    }
+}
+
+impl Index<TabstopIdx> for RenderedSnippet {
+    type Output = Tabstop;
+    fn index(&self, index: TabstopIdx) -> &Tabstop {
+        &self.tabstops[index.0]
+    }
+}
+
+impl IndexMut<TabstopIdx> for RenderedSnippet {
+    fn index_mut(&mut self, index: TabstopIdx) -> &mut Tabstop {
+        &mut self.tabstops[index.0]
+    }
+}
+
+/// The context a variable is resolved in, so resolvers can produce
+/// per-selection values (`TM_SELECTED_TEXT` and friends differ per cursor).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VariableContext {
+    /// Index of the snippet instance (selection) being rendered.
+    pub selection_idx: usize,
+    /// The (char) range of the original document the instance replaces,
+    /// if known (it isn't when binding variables outside of a render).
+    pub replacement: Option<(usize, usize)>,
+}
+
+pub trait VariableResolver {
+    fn resolve_var(&mut self, name: &str, ctx: &VariableContext) -> Option<Cow<'static, str>>;
+
+    /// Whether `name` is known but its value isn't available yet (clipboard,
+    /// shell output, ...). Pending variables render as their default text
+    /// immediately and are patched in later via
+    /// [`RenderedSnippet::resolve_pending`], so a slow resolver never blocks
+    /// the render.
+    fn is_pending(&mut self, _name: &str) -> bool {
+        false
+    }
+}
+
+/// Context-oblivious resolvers can stay plain closures.
+impl<F: FnMut(&str) -> Option<Cow<'static, str>>> VariableResolver for F {
+    fn resolve_var(&mut self, name: &str, _ctx: &VariableContext) -> Option<Cow<'static, str>> {
+        self(name)
+    }
+}
+
+pub struct SnippetRenderCtx {
+    pub resolve_var: Box<dyn VariableResolver>,
+    pub tab_width: usize,
+    pub indent_style: IndentStyle,
+    pub line_ending: &'static str,
+    /// When set, [`Snippet::render`] asks this hook for the indentation to
+    /// use at the (char) position a snippet instance is expanded at --
+    /// typically backed by the language's indent queries, see
+    /// [`treesitter_snippet_indent`] -- instead of copying the existing
+    /// indentation of that line. Returning `None` falls back to the copy.
+    pub resolve_indent: Option<Box<dyn FnMut(usize) -> Option<String>>>,
+    /// When set, every rendered line after the first continues this comment
+    /// leader (e.g. `"// "`, `"/// "`, `"# "`) after its indentation, so
+    /// snippets expanded inside line or doc comments stay inside them.
+    pub continue_comment: Option<String>,
+    /// When set, rendered text is hard-wrapped at this width (in chars):
+    /// instead of letting a word cross the limit, rendering breaks the line
+    /// at the preceding space and restates the indentation (and comment
+    /// leader) on the continuation line. Meant for prose and doc comment
+    /// snippets.
+    pub text_width: Option<usize>,
+    /// When `true`, lines whose only content was an empty tabstop or
+    /// variable lose their indentation instead of keeping it as trailing
+    /// whitespace: the indentation of a line is only emitted once some
+    /// content ends up on it.
+    pub trim_trailing_whitespace: bool,
+    /// When `true` and the final tabstop sits on an otherwise empty,
+    /// unindented line, rendering indents that line one level past the
+    /// shared indentation, so accepting a block snippet like
+    /// `if $1 {\n$0\n}` leaves the cursor indented for the next statement.
+    /// Lines the snippet author indented themselves are left alone.
+    pub auto_indent_final_tabstop: bool,
+    /// How resolver values containing line breaks are normalized when the
+    /// variable sits in a single-line context, see [`MultilineValuePolicy`].
+    pub multiline_values: MultilineValuePolicy,
+    /// How Unicode bidi control and zero-width characters in resolver
+    /// values are handled, see [`InvisibleCharPolicy`].
+    pub invisible_chars: InvisibleCharPolicy,
+    /// When `true`, lines that became entirely empty because every element
+    /// on them (variables without values, empty placeholders) rendered to
+    /// nothing are removed, including their line ending. Literal blank
+    /// lines the snippet author wrote stay, and so does a line holding only
+    /// the final tabstop. Meant for license-header and config-file
+    /// templates with optional sections.
+    pub collapse_blank_lines: bool,
+    /// The selected text of every range, bound for the duration of a
+    /// [`Snippet::render_around_selection`] call. While non-empty it wins
+    /// over the resolver for `TM_SELECTED_TEXT`.
+    selected_texts: Vec<String>,
+}
+
+/// How a resolver value containing line breaks is normalized when the
+/// variable sits in a single-line context: a snippet whose own body is a
+/// single line ([`Snippet::is_single_line`]), or a value about to be fed to
+/// a regex transform. Without normalization such values (clipboard contents,
+/// selected text, ...) silently turn a one-line expansion into a multi-line
+/// one.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MultilineValuePolicy {
+    /// Insert the value as is, rendering the line breaks like multi-line
+    /// snippet text (the default).
+    #[default]
+    Keep,
+    /// Join the lines with single spaces, trimming each line.
+    Collapse,
+    /// Escape every line break as the two characters `\n`.
+    Escape,
+    /// Discard the value and render the variable's default text instead,
+    /// for contexts where a multi-line value can only be a mistake.
+    Reject,
+}
+
+/// How Unicode bidi control and zero-width characters in resolver values
+/// (clipboard contents, environment variables, ...) are handled. Such
+/// characters are invisible in most editors and can reorder how the
+/// surrounding code is displayed, so pasting them into source code enables
+/// trojan-source-style surprises.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum InvisibleCharPolicy {
+    /// Insert the value as is (the default).
+    #[default]
+    Keep,
+    /// Remove bidi control and zero-width characters from the value.
+    Strip,
+    /// Replace each of them with its visible `\u{XXXX}` escape.
+    Escape,
+}
+
+impl SnippetRenderCtx {
+    pub fn builder() -> SnippetRenderCtxBuilder {
+        SnippetRenderCtxBuilder {
+            ctx: SnippetRenderCtx {
+                resolve_var: Box::new(|_: &str| -> Option<Cow<'static, str>> { None }),
+                tab_width: 4,
+                indent_style: IndentStyle::Spaces(4),
+                line_ending: crate::line_ending::NATIVE_LINE_ENDING.as_str(),
+                resolve_indent: None,
+                continue_comment: None,
+                text_width: None,
+                trim_trailing_whitespace: false,
+                auto_indent_final_tabstop: false,
+                multiline_values: MultilineValuePolicy::Keep,
+                invisible_chars: InvisibleCharPolicy::Keep,
+                collapse_blank_lines: false,
+                selected_texts: Vec::new(),
+            },
+        }
+    }
+
+    /// Swaps the variable resolver, keeping the rest of the context. Useful
+    /// when the same (per-language) context is reused across documents that
+    /// resolve variables differently.
+    pub fn set_resolver(&mut self, resolve_var: Box<dyn VariableResolver>) {
+        self.resolve_var = resolve_var;
+    }
+
+    #[cfg(test)]
+    pub(super) fn test_ctx() -> SnippetRenderCtx {
+        SnippetRenderCtx {
+            resolve_var: Box::new(|_: &str| -> Option<Cow<'static, str>> { None }),
+            tab_width: 4,
+            indent_style: IndentStyle::Spaces(4),
+            line_ending: "\n",
+            resolve_indent: None,
+            continue_comment: None,
+            text_width: None,
+            trim_trailing_whitespace: false,
+            auto_indent_final_tabstop: false,
+            multiline_values: MultilineValuePolicy::Keep,
+            invisible_chars: InvisibleCharPolicy::Keep,
+            collapse_blank_lines: false,
+            selected_texts: Vec::new(),
+        }
+    }
+}
+
+/// Builds a [`SnippetRenderCtx`] starting from editor-wide defaults (4-wide
+/// space indentation, native line ending, no variables), see
+/// [`SnippetRenderCtx::builder`].
+pub struct SnippetRenderCtxBuilder {
+    ctx: SnippetRenderCtx,
+}
+
+impl SnippetRenderCtxBuilder {
+    pub fn tab_width(mut self, tab_width: usize) -> Self {
+        self.ctx.tab_width = tab_width;
+        self
+    }
+
+    pub fn indent_style(mut self, indent_style: IndentStyle) -> Self {
+        self.ctx.indent_style = indent_style;
+        self
+    }
+
+    pub fn line_ending(mut self, line_ending: &'static str) -> Self {
+        self.ctx.line_ending = line_ending;
+        self
+    }
+
+    pub fn resolve_var(mut self, resolve_var: impl VariableResolver + 'static) -> Self {
+        self.ctx.resolve_var = Box::new(resolve_var);
+        self
+    }
+
+    pub fn resolve_indent(
+        mut self,
+        resolve_indent: impl FnMut(usize) -> Option<String> + 'static,
+    ) -> Self {
+        self.ctx.resolve_indent = Some(Box::new(resolve_indent));
+        self
+    }
+
+    pub fn continue_comment(mut self, leader: impl Into<String>) -> Self {
+        self.ctx.continue_comment = Some(leader.into());
+        self
+    }
+
+    pub fn text_width(mut self, text_width: usize) -> Self {
+        self.ctx.text_width = Some(text_width);
+        self
+    }
+
+    pub fn trim_trailing_whitespace(mut self, trim: bool) -> Self {
+        self.ctx.trim_trailing_whitespace = trim;
+        self
+    }
+
+    pub fn auto_indent_final_tabstop(mut self, auto_indent: bool) -> Self {
+        self.ctx.auto_indent_final_tabstop = auto_indent;
+        self
+    }
+
+    pub fn multiline_values(mut self, policy: MultilineValuePolicy) -> Self {
+        self.ctx.multiline_values = policy;
+        self
+    }
+
+    pub fn invisible_chars(mut self, policy: InvisibleCharPolicy) -> Self {
+        self.ctx.invisible_chars = policy;
+        self
+    }
+
+    pub fn collapse_blank_lines(mut self, collapse: bool) -> Self {
+        self.ctx.collapse_blank_lines = collapse;
+        self
+    }
+
+    /// Layers a set of (typically per-language) overrides over the current
+    /// settings. May be called multiple times, later layers win.
+    pub fn overrides(mut self, overrides: &SnippetRenderOverrides) -> Self {
+        if let Some(tab_width) = overrides.tab_width {
+            self.ctx.tab_width = tab_width;
+        }
+        if let Some(indent_style) = overrides.indent_style {
+            self.ctx.indent_style = indent_style;
+        }
+        if let Some(line_ending) = overrides.line_ending {
+            self.ctx.line_ending = line_ending;
+        }
+        self
+    }
+
+    pub fn build(self) -> SnippetRenderCtx {
+        self.ctx
+    }
+}
+
+/// Partial render settings, layered over the defaults with
+/// [`SnippetRenderCtxBuilder::overrides`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SnippetRenderOverrides {
+    pub tab_width: Option<usize>,
+    pub indent_style: Option<IndentStyle>,
+    pub line_ending: Option<&'static str>,
+}
+
+/// Which of the two ranges of an LSP `InsertReplaceEdit` an expansion
+/// replaces, see [`Snippet::render_insert_replace`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditMode {
+    /// Replace only the text typed so far, keeping what follows the cursor.
+    Insert,
+    /// Replace the whole overlapped word.
+    Replace,
+}
+
+/// Backs the `CLIPBOARD` variable without tying helix-core to a clipboard
+/// implementation: terminal builds plug in their system clipboard,
+/// embedders whatever they use. Plain closures work too.
+pub trait ClipboardProvider {
+    /// The current clipboard content, `None` when empty or unavailable.
+    fn get_contents(&mut self) -> Option<String>;
+}
+
+impl<F: FnMut() -> Option<String>> ClipboardProvider for F {
+    fn get_contents(&mut self) -> Option<String> {
+        self()
+    }
+}
+
+/// Resolves the standard snippet variables (`TM_FILENAME`, `CURRENT_YEAR`,
+/// `RANDOM`, `UUID`, ...) so embedders don't have to reimplement them. The
+/// path and cursor position are plain fields, the clock and random source
+/// are pluggable so the resolver stays deterministic in tests.
+pub struct StandardVariables {
+    /// Path of the current document, if any.
+    pub path: Option<PathBuf>,
+    /// Root of the current workspace, if any. Drives `WORKSPACE_NAME`,
+    /// `WORKSPACE_FOLDER` and `RELATIVE_FILEPATH`.
+    pub workspace: Option<PathBuf>,
+    /// Environment variables that `${env:VAR}` may read. `None` (the
+    /// default) allows any variable; set this for untrusted snippets.
+    pub env_allowlist: Option<Vec<String>>,
+    /// Zero based line index of the cursor.
+    pub line_idx: usize,
+    now: Box<dyn FnMut() -> NaiveDateTime>,
+    random: Box<dyn FnMut() -> u32>,
+    clipboard: Option<Box<dyn ClipboardProvider>>,
+}
+
+impl StandardVariables {
+    pub fn new() -> StandardVariables {
+        // std's hash seed is drawn from OS entropy, which beats seeding
+        // from the clock without pulling in a randomness dependency
+        let entropy = std::collections::hash_map::RandomState::new()
+            .build_hasher()
+            .finish();
+        let seed = (entropy ^ (entropy >> 32)) as u32;
+        StandardVariables {
+            path: None,
+            workspace: None,
+            env_allowlist: None,
+            line_idx: 0,
+            now: Box::new(|| {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                NaiveDateTime::from_timestamp_opt(now.as_secs() as i64, now.subsec_nanos())
+                    .unwrap_or_default()
+            }),
+            random: Box::new(xorshift(seed)),
+            clipboard: None,
+        }
+    }
+
+    /// Plugs in a clipboard for the `CLIPBOARD` variable, which resolves
+    /// to nothing without one.
+    pub fn with_clipboard(mut self, clipboard: impl ClipboardProvider + 'static) -> Self {
+        self.clipboard = Some(Box::new(clipboard));
+        self
+    }
+
+    /// Seeds the random source used by `RANDOM`, `RANDOM_HEX` and `UUID`,
+    /// so the same seed always produces the same sequence -- for tests and
+    /// reproducible template generation. The default (unseeded) source is
+    /// seeded from OS entropy instead.
+    pub fn with_seed(self, seed: u32) -> Self {
+        // xorshift has a fixed point at zero
+        let seed = if seed == 0 { 0x9E3779B9 } else { seed };
+        self.with_random(xorshift(seed))
+    }
+
+    /// Replaces the time source (the system clock in UTC by default).
+    pub fn with_clock(mut self, now: impl FnMut() -> NaiveDateTime + 'static) -> Self {
+        self.now = Box::new(now);
+        self
+    }
+
+    /// Replaces the random source used by `RANDOM`, `RANDOM_HEX` and
+    /// `UUID` (an entropy-seeded xorshift by default), see also
+    /// [`StandardVariables::with_seed`].
+    pub fn with_random(mut self, random: impl FnMut() -> u32 + 'static) -> Self {
+        self.random = Box::new(random);
+        self
+    }
+}
+
+impl Default for StandardVariables {
+    fn default() -> Self {
+        StandardVariables::new()
+    }
+}
+
+fn xorshift(mut state: u32) -> impl FnMut() -> u32 {
+    move || {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state
+    }
+}
+
+impl VariableResolver for StandardVariables {
+    fn resolve_var(&mut self, name: &str, _ctx: &VariableContext) -> Option<Cow<'static, str>> {
+        use std::fmt::Write;
+
+        if let Some(var) = name.strip_prefix("env:") {
+            let allowed = self
+                .env_allowlist
+                .as_ref()
+                .map_or(true, |allowlist| allowlist.iter().any(|allowed| allowed == var));
+            return allowed
+                .then(|| std::env::var(var).ok().map(Cow::from))
+                .flatten();
+        }
+
+        // `${date:FORMAT}` is a helix extension, the renderer hands the
+        // strftime format over as part of the name
+        if let Some(format) = name.strip_prefix("date:") {
+            let mut formatted = String::new();
+            // formatting with an invalid specifier errors instead of
+            // panicking through `to_string`, the snippet then renders the
+            // format string as the default text
+            write!(formatted, "{}", (self.now)().format(format)).ok()?;
+            return Some(Cow::from(formatted));
+        }
+
+        let path = self.path.as_deref();
+        let res = match name {
+            "WORKSPACE_FOLDER" => self.workspace.as_deref()?.to_string_lossy().into_owned(),
+            "WORKSPACE_NAME" => {
+                let workspace = self.workspace.as_deref()?;
+                workspace.file_name()?.to_string_lossy().into_owned()
+            }
+            "RELATIVE_FILEPATH" => {
+                let path = path?;
+                self.workspace
+                    .as_deref()
+                    .and_then(|workspace| path.strip_prefix(workspace).ok())
+                    .unwrap_or(path)
+                    .to_string_lossy()
+                    .into_owned()
+            }
+            "TM_FILENAME" => path?.file_name()?.to_string_lossy().into_owned(),
+            "TM_FILENAME_BASE" => path?.file_stem()?.to_string_lossy().into_owned(),
+            "TM_DIRECTORY" => path?.parent()?.to_string_lossy().into_owned(),
+            "TM_FILEPATH" => path?.to_string_lossy().into_owned(),
+            "CLIPBOARD" => self.clipboard.as_mut()?.get_contents()?,
+            "TM_LINE_INDEX" => self.line_idx.to_string(),
+            "TM_LINE_NUMBER" => (self.line_idx + 1).to_string(),
+            "CURRENT_YEAR" => (self.now)().format("%Y").to_string(),
+            "CURRENT_YEAR_SHORT" => (self.now)().format("%y").to_string(),
+            "CURRENT_MONTH" => (self.now)().format("%m").to_string(),
+            "CURRENT_MONTH_NAME" => (self.now)().format("%B").to_string(),
+            "CURRENT_MONTH_NAME_SHORT" => (self.now)().format("%b").to_string(),
+            "CURRENT_DATE" => (self.now)().format("%d").to_string(),
+            "CURRENT_DAY_NAME" => (self.now)().format("%A").to_string(),
+            "CURRENT_DAY_NAME_SHORT" => (self.now)().format("%a").to_string(),
+            "CURRENT_HOUR" => (self.now)().format("%H").to_string(),
+            "CURRENT_MINUTE" => (self.now)().format("%M").to_string(),
+            "CURRENT_SECOND" => (self.now)().format("%S").to_string(),
+            "CURRENT_SECONDS_UNIX" => (self.now)().timestamp().to_string(),
+            "RANDOM" => format!("{:06}", (self.random)() % 1_000_000),
+            "RANDOM_HEX" => format!("{:06x}", (self.random)() % 0x100_0000),
+            "UUID" => {
+                let mut bytes = [0; 16];
+                for chunk in bytes.chunks_mut(4) {
+                    chunk.copy_from_slice(&(self.random)().to_be_bytes());
+                }
+                // version 4, RFC 4122 variant
+                bytes[6] = (bytes[6] & 0x0f) | 0x40;
+                bytes[8] = (bytes[8] & 0x3f) | 0x80;
+                let mut res = String::with_capacity(36);
+                for (i, byte) in bytes.iter().enumerate() {
+                    if matches!(i, 4 | 6 | 8 | 10) {
+                        res.push('-');
+                    }
+                    write!(res, "{byte:02x}").unwrap();
+                }
+                res
+            }
+            _ => return None,
+        };
+        Some(res.into())
+    }
+}
+
+/// Resolves the selection dependent variables (`TM_SELECTED_TEXT`,
+/// `TM_CURRENT_LINE`, `TM_CURRENT_WORD`) against a document. [`Snippet::render`]
+/// passes the selection index of the cursor it is currently expanding in the
+/// [`VariableContext`], so with multiple cursors every cursor sees its own
+/// values. Ropes are cheap to clone, holding one does not copy the document.
+pub struct DocumentVariables {
+    pub text: Rope,
+    pub selection: Selection,
+}
+
+impl VariableResolver for DocumentVariables {
+    fn resolve_var(&mut self, name: &str, ctx: &VariableContext) -> Option<Cow<'static, str>> {
+        use crate::chars::char_is_word;
+        use crate::line_ending::line_end_char_index;
+
+        let text = self.text.slice(..);
+        let range = *self.selection.ranges().get(ctx.selection_idx)?;
+        let res = match name {
+            "TM_SELECTED_TEXT" => {
+                if range.is_empty() {
+                    return None;
+                }
+                text.slice(range.from()..range.to()).to_string()
+            }
+            "TM_CURRENT_LINE" => {
+                let line = range.cursor_line(text);
+                let start = text.line_to_char(line);
+                text.slice(start..line_end_char_index(&text, line)).to_string()
+            }
+            "TM_CURRENT_WORD" => {
+                let cursor = range.cursor(text);
+                let mut start = cursor;
+                while start > 0 && char_is_word(text.char(start - 1)) {
+                    start -= 1;
+                }
+                let mut end = cursor;
+                while end < text.len_chars() && char_is_word(text.char(end)) {
+                    end += 1;
+                }
+                if start == end {
+                    return None;
+                }
+                text.slice(start..end).to_string()
+            }
+            _ => return None,
+        };
+        Some(res.into())
+    }
+}
+
+/// Resolves `TM_CURRENT_LINE`, `TM_CURRENT_WORD`, `TM_LINE_INDEX` and
+/// `TM_LINE_NUMBER` against the position each snippet instance replaces.
+/// Unlike [`DocumentVariables`] this needs no precomputed selection: the
+/// positions come from the [`VariableContext`], which [`Snippet::render`]
+/// fills in with the range the instance replaces, so every cursor sees the
+/// values at its own expansion point. Ropes are cheap to clone, holding one
+/// does not copy the document.
+pub struct ReplacementVariables {
+    pub text: Rope,
+}
+
+impl VariableResolver for ReplacementVariables {
+    fn resolve_var(&mut self, name: &str, ctx: &VariableContext) -> Option<Cow<'static, str>> {
+        use crate::chars::char_is_word;
+        use crate::line_ending::line_end_char_index;
+
+        let text = self.text.slice(..);
+        let (replacement_start, _) = ctx.replacement?;
+        let res = match name {
+            "TM_CURRENT_LINE" => {
+                let line = text.char_to_line(replacement_start);
+                let start = text.line_to_char(line);
+                text.slice(start..line_end_char_index(&text, line)).to_string()
+            }
+            "TM_CURRENT_WORD" => {
+                let mut start = replacement_start;
+                while start > 0 && char_is_word(text.char(start - 1)) {
+                    start -= 1;
+                }
+                let mut end = replacement_start;
+                while end < text.len_chars() && char_is_word(text.char(end)) {
+                    end += 1;
+                }
+                if start == end {
+                    return None;
+                }
+                text.slice(start..end).to_string()
+            }
+            "TM_LINE_INDEX" => text.char_to_line(replacement_start).to_string(),
+            "TM_LINE_NUMBER" => (text.char_to_line(replacement_start) + 1).to_string(),
+            _ => return None,
+        };
+        Some(res.into())
+    }
+}
+
+/// Computes the indentation for expanding a snippet at `pos` from the
+/// language's tree-sitter indent queries, as a backend for
+/// [`SnippetRenderCtx::resolve_indent`]. With this, snippets expanded
+/// inside nested blocks indent correctly even when the trigger line's own
+/// indentation doesn't match its syntactic depth.
+pub fn treesitter_snippet_indent(
+    query: &tree_sitter::Query,
+    syntax: &crate::syntax::Syntax,
+    indent_style: IndentStyle,
+    tab_width: usize,
+    text: RopeSlice,
+    pos: usize,
+) -> Option<String> {
+    let indent = crate::indent::treesitter_indent_for_pos(
+        query,
+        syntax,
+        tab_width,
+        indent_style.indent_width(tab_width),
+        text,
+        text.char_to_line(pos),
+        pos,
+        false,
+    )?;
+    Some(indent.to_string(&indent_style, tab_width))
+}
+
+impl Snippet {
+    /// Expands the snippet at every range of `selection`, producing a
+    /// transaction, the mapped selection and the rendered tabstop ranges.
+    /// `change_range` maps each selection range to the range of text it
+    /// replaces (for completions this is usually the trigger prefix rather
+    /// than the selection itself).
+    pub fn render(
+        &self,
+        doc: &Rope,
+        selection: &Selection,
+        change_range: impl FnMut(&Range) -> (usize, usize),
+        ctx: &mut SnippetRenderCtx,
+    ) -> (Transaction, Selection, RenderedSnippet) {
+        let text = doc.slice(..);
+        let mut off = 0i128;
+        let mut byte_off = 0i128;
+        let mut selection_idx = 0;
+        let mut rendered_snippet = RenderedSnippet::default();
+        // with thousands of cursors rebuilding the same indentation prefix
+        // for every selection shows up in profiles, so prefixes are cached
+        // per indent level for the duration of the call
+        let mut indent_cache: Vec<String> = Vec::new();
+        let mut hook_indent = String::new();
+        let (transaction, selection) = Transaction::change_by_selection_ignore_overlapping(
+            doc,
+            selection,
+            change_range,
+            |replacement_start, replacement_end| {
+                let newline_with_offset: &str = match ctx
+                    .resolve_indent
+                    .as_mut()
+                    .and_then(|resolve_indent| resolve_indent(replacement_start))
+                {
+                    Some(indent) => {
+                        hook_indent.clear();
+                        hook_indent.push_str(ctx.line_ending);
+                        hook_indent.push_str(&indent);
+                        &hook_indent
+                    }
+                    None => {
+                        let line_idx = text.char_to_line(replacement_start);
+                        let indent_level = indent_level_for_line(
+                            text.line(line_idx),
+                            ctx.tab_width,
+                            ctx.indent_style.indent_width(ctx.tab_width),
+                        );
+                        while indent_cache.len() <= indent_level {
+                            let mut prefix = String::from(ctx.line_ending);
+                            for _ in 0..indent_cache.len() {
+                                prefix.push_str(ctx.indent_style.as_str());
+                            }
+                            indent_cache.push(prefix);
+                        }
+                        &indent_cache[indent_level]
+                    }
+                };
+
+                let pos = (replacement_start as i128 + off) as usize;
+                let var_ctx = VariableContext {
+                    selection_idx,
+                    replacement: Some((replacement_start, replacement_end)),
+                };
+                selection_idx += 1;
+                let (replacement, mut snippet) =
+                    self.render_into(Tendril::new(), newline_with_offset, ctx, pos, var_ctx, false);
+                off +=
+                    replacement.chars().count() as i128 - (replacement_end - replacement_start) as i128;
+                let byte_start = text.char_to_byte(replacement_start);
+                snippet.offset_byte_ranges((byte_start as i128 + byte_off) as usize);
+                byte_off += replacement.len() as i128
+                    - (text.char_to_byte(replacement_end) - byte_start) as i128;
+                rendered_snippet.push(snippet);
+                Some(replacement)
+            },
+        );
+        (transaction, selection, rendered_snippet)
+    }
+
+    /// Like [`Snippet::render`] but generates the replacement text of the
+    /// selections in parallel, for documents with thousands of cursors.
+    /// Resolvers are stateful, so every worker renders with its own context
+    /// produced by `make_ctx`. The final transaction is assembled
+    /// sequentially in selection order, so the result is deterministic. The
+    /// ranges produced by `change_range` must not overlap.
+    #[cfg(feature = "rayon")]
+    pub fn render_parallel(
+        &self,
+        doc: &Rope,
+        selection: &Selection,
+        change_range: impl Fn(&Range) -> (usize, usize) + Sync,
+        make_ctx: impl Fn() -> SnippetRenderCtx + Sync,
+    ) -> (Transaction, Selection, RenderedSnippet) {
+        use rayon::prelude::*;
+
+        let text = doc.slice(..);
+        let instances: Vec<_> = selection
+            .ranges()
+            .par_iter()
+            .enumerate()
+            .map(|(selection_idx, range)| {
+                let mut ctx = make_ctx();
+                let (replacement_start, replacement_end) = change_range(range);
+                let prefix = newline_with_offset(&mut ctx, text, replacement_start);
+                let var_ctx = VariableContext {
+                    selection_idx,
+                    replacement: Some((replacement_start, replacement_end)),
+                };
+                let (replacement, rendered) =
+                    self.render_into(Tendril::new(), &prefix, &mut ctx, 0, var_ctx, false);
+                (replacement_start, replacement_end, replacement, rendered)
+            })
+            .collect();
+
+        let mut off = 0i128;
+        let mut byte_off = 0i128;
+        let mut merged = RenderedSnippet::default();
+        let mut changes = Vec::with_capacity(instances.len());
+        for (replacement_start, replacement_end, replacement, mut rendered) in instances {
+            rendered.offset_char_positions((replacement_start as i128 + off) as usize);
+            off += replacement.chars().count() as i128
+                - (replacement_end - replacement_start) as i128;
+            let byte_start = text.char_to_byte(replacement_start);
+            rendered.offset_byte_ranges((byte_start as i128 + byte_off) as usize);
+            byte_off += replacement.len() as i128
+                - (text.char_to_byte(replacement_end) - byte_start) as i128;
+            merged.push(rendered);
+            changes.push((replacement_start, replacement_end, Some(replacement)));
+        }
+        let transaction = Transaction::change(doc, changes.into_iter());
+        let selection = selection.clone().map(transaction.changes());
+        (transaction, selection, merged)
+    }
+
+    /// Like [`Snippet::render`] but expands only at the primary selection,
+    /// leaving the other selections untouched (mapped through the change).
+    /// Some users expect this from completion-accept with multiple cursors.
+    pub fn render_primary(
+        &self,
+        doc: &Rope,
+        selection: &Selection,
+        mut change_range: impl FnMut(&Range) -> (usize, usize),
+        ctx: &mut SnippetRenderCtx,
+    ) -> (Transaction, Selection, RenderedSnippet) {
+        let text = doc.slice(..);
+        let (replacement_start, replacement_end) = change_range(&selection.primary());
+        let newline_with_offset = newline_with_offset(ctx, text, replacement_start);
+        let var_ctx = VariableContext {
+            selection_idx: selection.primary_index(),
+            replacement: Some((replacement_start, replacement_end)),
+        };
+        let (replacement, mut snippet) = self.render_into(
+            Tendril::new(),
+            &newline_with_offset,
+            ctx,
+            replacement_start,
+            var_ctx,
+            false,
+        );
+        snippet.offset_byte_ranges(text.char_to_byte(replacement_start));
+        let transaction = Transaction::change(
+            doc,
+            std::iter::once((replacement_start, replacement_end, Some(replacement))),
+        );
+        let selection = selection.clone().map(transaction.changes());
+        (transaction, selection, snippet)
+    }
+
+    /// Like [`Snippet::render`] for completions that come with both an
+    /// insert and a replace range (LSP `InsertReplaceEdit`): `ranges` maps
+    /// each selection range to the pair of (char) ranges and `mode` picks
+    /// which of the two each expansion replaces, typically from user
+    /// configuration. The rendered tabstop ranges map accordingly.
+    pub fn render_insert_replace(
+        &self,
+        doc: &Rope,
+        selection: &Selection,
+        mut ranges: impl FnMut(&Range) -> ((usize, usize), (usize, usize)),
+        mode: EditMode,
+        ctx: &mut SnippetRenderCtx,
+    ) -> (Transaction, Selection, RenderedSnippet) {
+        self.render(
+            doc,
+            selection,
+            |range| {
+                let (insert, replace) = ranges(range);
+                match mode {
+                    EditMode::Insert => insert,
+                    EditMode::Replace => replace,
+                }
+            },
+            ctx,
+        )
+    }
+
+    /// Like [`Snippet::render`] but additionally lines up the `${ALIGN}`
+    /// marker across all expansions: after rendering, every expansion is
+    /// padded with spaces at its marker until the marker columns match the
+    /// widest one -- the classic "align assignments" use case. Columns are
+    /// counted in chars and markers are aligned pairwise in order; a line
+    /// should contain at most one marker. Unlike [`Snippet::render`] the
+    /// expansion ranges must not overlap. Snippets without a marker render
+    /// as with [`Snippet::render`].
+    pub fn render_aligned(
+        &self,
+        doc: &Rope,
+        selection: &Selection,
+        mut change_range: impl FnMut(&Range) -> (usize, usize),
+        ctx: &mut SnippetRenderCtx,
+    ) -> (Transaction, Selection, RenderedSnippet) {
+        struct Expansion {
+            start: usize,
+            end: usize,
+            /// The column (in chars) the expansion starts at, markers on
+            /// the first line of a replacement sit relative to it.
+            start_col: usize,
+            replacement: Tendril,
+            snippet: RenderedSnippet,
+        }
+        impl Expansion {
+            fn column(&self, marker: usize) -> usize {
+                let (_, byte_idx, col) = self.snippet.align_points[marker];
+                let first_line = !self.replacement[..byte_idx].contains('\n');
+                col + if first_line { self.start_col } else { 0 }
+            }
+        }
+
+        let text = doc.slice(..);
+        // first phase: render every selection replacement relative, keeping
+        // the per-selection results apart so the alignment pass can still
+        // patch them
+        let mut expansions = Vec::with_capacity(selection.len());
+        for (selection_idx, range) in selection.ranges().iter().enumerate() {
+            let (start, end) = change_range(range);
+            let newline_with_offset = newline_with_offset(ctx, text, start);
+            let var_ctx = VariableContext {
+                selection_idx,
+                replacement: Some((start, end)),
+            };
+            let (replacement, snippet) =
+                self.render_into(Tendril::new(), &newline_with_offset, ctx, 0, var_ctx, false);
+            let start_col = start - text.line_to_char(text.char_to_line(start));
+            expansions.push(Expansion {
+                start,
+                end,
+                start_col,
+                replacement,
+                snippet,
+            });
+        }
+
+        // the alignment pass: pad every expansion so the k-th marker sits
+        // at the same column everywhere
+        let markers = expansions
+            .iter()
+            .map(|expansion| expansion.snippet.align_points.len())
+            .min()
+            .unwrap_or(0);
+        for marker in 0..markers {
+            let target = expansions
+                .iter()
+                .map(|expansion| expansion.column(marker))
+                .max()
+                .unwrap_or(0);
+            for expansion in &mut expansions {
+                let pad = target - expansion.column(marker);
+                if pad == 0 {
+                    continue;
+                }
+                let (char_idx, byte_idx, _) = expansion.snippet.align_points[marker];
+                expansion.replacement.insert_str(byte_idx, &" ".repeat(pad));
+                expansion
+                    .snippet
+                    .shift_for_insertion(char_idx, pad, byte_idx, pad);
+            }
+        }
+
+        // second phase: shift everything to document positions and build
+        // the transaction, mirroring [`Snippet::render`]
+        let mut rendered_snippet = RenderedSnippet::default();
+        let mut changes = Vec::with_capacity(expansions.len());
+        let mut off = 0i128;
+        let mut byte_off = 0i128;
+        for expansion in expansions {
+            let Expansion {
+                start,
+                end,
+                replacement,
+                mut snippet,
+                ..
+            } = expansion;
+            snippet.offset_char_positions((start as i128 + off) as usize);
+            off += replacement.chars().count() as i128 - (end - start) as i128;
+            let byte_start = text.char_to_byte(start);
+            snippet.offset_byte_ranges((byte_start as i128 + byte_off) as usize);
+            byte_off += replacement.len() as i128 - (text.char_to_byte(end) - byte_start) as i128;
+            rendered_snippet.push(snippet);
+            changes.push((start, end, Some(replacement)));
+        }
+        let transaction = Transaction::change(doc, changes.into_iter());
+        let selection = selection.clone().map(transaction.changes());
+        (transaction, selection, rendered_snippet)
+    }
+
+    /// Renders a unified-diff-like preview of what expanding the snippet at
+    /// `selection` would change: per instance a `@@ line N @@` header, the
+    /// affected document lines prefixed with `-` and their replacement
+    /// prefixed with `+`. Meant for "confirm template expansion" UIs in
+    /// destructive cases like file templates; the document itself is left
+    /// untouched. The ranges produced by `change_range` must not overlap.
+    /// (The completion-docs one-line rendering lives in
+    /// [`Snippet::preview`](crate::snippets::Snippet::preview).)
+    pub fn preview_diff(
+        &self,
+        doc: &Rope,
+        selection: &Selection,
+        mut change_range: impl FnMut(&Range) -> (usize, usize),
+        ctx: &mut SnippetRenderCtx,
+    ) -> String {
+        use crate::line_ending::line_end_char_index;
+        use std::fmt::Write;
+
+        let old_ranges: Vec<_> = selection.ranges().iter().map(&mut change_range).collect();
+        let mut next = 0;
+        let (transaction, _, rendered) = self.render(
+            doc,
+            selection,
+            |_| {
+                let range = old_ranges[next];
+                next += 1;
+                range
+            },
+            ctx,
+        );
+        let mut new_doc = doc.clone();
+        if !transaction.apply(&mut new_doc) {
+            return String::new();
+        }
+        let old_text = doc.slice(..);
+        let new_text = new_doc.slice(..);
+        let mut res = String::new();
+        for ((old_start, old_end), new_range) in old_ranges.iter().zip(&rendered.ranges) {
+            let old_first = old_text.char_to_line(*old_start);
+            writeln!(res, "@@ line {} @@", old_first + 1).unwrap();
+            for line_idx in old_first..=old_text.char_to_line(*old_end) {
+                let start = old_text.line_to_char(line_idx);
+                let line = old_text.slice(start..line_end_char_index(&old_text, line_idx));
+                writeln!(res, "- {line}").unwrap();
+            }
+            let new_lines =
+                new_text.char_to_line(new_range.from())..=new_text.char_to_line(new_range.to());
+            for line_idx in new_lines {
+                let start = new_text.line_to_char(line_idx);
+                let line = new_text.slice(start..line_end_char_index(&new_text, line_idx));
+                writeln!(res, "+ {line}").unwrap();
+            }
+        }
+        res
+    }
+
+    /// Expands a (possibly different) snippet at every range in a single
+    /// transaction, for structural-editing tools that fill different
+    /// templates at different cursors atomically. Tabstops merge by
+    /// visiting order: the n-th tabstop of every snippet is visited
+    /// together and the final tabstops always collapse into one, so
+    /// tabbing through the session stays consistent. Where the snippets
+    /// disagree on a tabstop's kind the first contributor wins. The ranges
+    /// must be sorted and non-overlapping.
+    pub fn render_mixed<'a>(
+        doc: &Rope,
+        snippets: impl IntoIterator<Item = (Range, &'a Snippet)>,
+        ctx: &mut SnippetRenderCtx,
+    ) -> (Transaction, RenderedSnippet) {
+        let text = doc.slice(..);
+        let mut instances = Vec::new();
+        let mut changes = Vec::new();
+        let mut off = 0i128;
+        let mut byte_off = 0i128;
+        for (selection_idx, (range, snippet)) in snippets.into_iter().enumerate() {
+            let (replacement_start, replacement_end) = (range.from(), range.to());
+            let newline_with_offset = newline_with_offset(ctx, text, replacement_start);
+            let var_ctx = VariableContext {
+                selection_idx,
+                replacement: Some((replacement_start, replacement_end)),
+            };
+            let pos = (replacement_start as i128 + off) as usize;
+            let (replacement, mut rendered) =
+                snippet.render_into(Tendril::new(), &newline_with_offset, ctx, pos, var_ctx, false);
+            off += replacement.chars().count() as i128
+                - (replacement_end - replacement_start) as i128;
+            let byte_start = text.char_to_byte(replacement_start);
+            rendered.offset_byte_ranges((byte_start as i128 + byte_off) as usize);
+            byte_off += replacement.len() as i128
+                - (text.char_to_byte(replacement_end) - byte_start) as i128;
+            instances.push(rendered);
+            changes.push((replacement_start, replacement_end, Some(replacement)));
+        }
+
+        let tabstop_count = instances
+            .iter()
+            .map(|instance| instance.tabstops.len())
+            .max()
+            .unwrap_or(0);
+        let remap = |idx: usize, len: usize| {
+            if idx + 1 == len {
+                tabstop_count - 1
+            } else {
+                idx
+            }
+        };
+        let mut merged = RenderedSnippet::default();
+        merged.tabstops.resize_with(tabstop_count, || Tabstop {
+            ranges: SmallVec::new(),
+            byte_ranges: SmallVec::new(),
+            parent: None,
+            kind: TabstopKind::Empty,
+        });
+        for instance in instances {
+            let len = instance.tabstops.len();
+            for (i, tabstop) in instance.tabstops.into_iter().enumerate() {
+                let slot = &mut merged.tabstops[remap(i, len)];
+                if slot.ranges.is_empty() {
+                    slot.kind = tabstop.kind;
+                    slot.parent = tabstop.parent.map(|parent| TabstopIdx(remap(parent.0, len)));
+                }
+                slot.ranges.extend(tabstop.ranges);
+                slot.byte_ranges.extend(tabstop.byte_ranges);
+            }
+            merged.ranges.extend(instance.ranges);
+            merged.byte_ranges.extend(instance.byte_ranges);
+            merged.pending_variables.extend(instance.pending_variables);
+            merged.variables.extend(instance.variables);
+            merged.align_points.extend(instance.align_points);
+            merged.pair_positions.extend(instance.pair_positions);
+            merged.spans.extend(instance.spans);
+            #[cfg(feature = "render-metrics")]
+            {
+                merged.metrics += instance.metrics;
+            }
+        }
+        (Transaction::change(doc, changes.into_iter()), merged)
+    }
+
+    /// A dry run of [`Snippet::render`]: computes the rendered length (in
+    /// chars), the selection the cursors would land on and all tabstop
+    /// ranges -- everything [`Snippet::render`] would report -- without
+    /// building the replacement text or a transaction. Useful when a UI
+    /// needs to pre-compute layout, e.g. for an inline preview popup.
+    pub fn measure(
+        &self,
+        doc: &Rope,
+        selection: &Selection,
+        ctx: &mut SnippetRenderCtx,
+    ) -> (usize, Selection, RenderedSnippet) {
+        let text = doc.slice(..);
+        let mut rendered_snippet = RenderedSnippet::default();
+        let mut len = 0;
+        let mut off = 0i128;
+        let mut byte_off = 0i128;
+        for (selection_idx, range) in selection.ranges().iter().enumerate() {
+            let (replacement_start, replacement_end) = (range.from(), range.to());
+            let newline_with_offset = newline_with_offset(ctx, text, replacement_start);
+            let var_ctx = VariableContext {
+                selection_idx,
+                replacement: Some((replacement_start, replacement_end)),
+            };
+            let pos = (replacement_start as i128 + off) as usize;
+            let ((), mut snippet) =
+                self.render_into((), &newline_with_offset, ctx, pos, var_ctx, false);
+            let instance = *snippet.ranges.last().unwrap();
+            let (_, instance_bytes) = *snippet.byte_ranges.last().unwrap();
+            len += instance.to() - instance.from();
+            off += (instance.to() - instance.from()) as i128
+                - (replacement_end - replacement_start) as i128;
+            let byte_start = text.char_to_byte(replacement_start);
+            snippet.offset_byte_ranges((byte_start as i128 + byte_off) as usize);
+            byte_off += instance_bytes as i128
+                - (text.char_to_byte(replacement_end) - byte_start) as i128;
+            rendered_snippet.push(snippet);
+        }
+        let landing =
+            rendered_snippet.first_selection(Direction::Forward, selection.primary_index());
+        (len, landing, rendered_snippet)
+    }
+
+    /// Expands the snippet around the selection in a single transaction:
+    /// each range's text is bound to `TM_SELECTED_TEXT` for its instance
+    /// and the expansion replaces the range, so a surround snippet like
+    /// `(${TM_SELECTED_TEXT})$0` wraps every selection in one call.
+    pub fn render_around_selection(
+        &self,
+        doc: &Rope,
+        selection: &Selection,
+        ctx: &mut SnippetRenderCtx,
+    ) -> (Transaction, Selection, RenderedSnippet) {
+        let text = doc.slice(..);
+        ctx.selected_texts = selection
+            .ranges()
+            .iter()
+            .map(|range| text.slice(range.from()..range.to()).to_string())
+            .collect();
+        let result = self.render(doc, selection, |range| (range.from(), range.to()), ctx);
+        ctx.selected_texts.clear();
+        result
+    }
+
+    /// Like [`Snippet::render`] but expands at a single `(line, column)`
+    /// position instead of a selection of absolute char indices, since most
+    /// embedders work in line/column space. Coordinates are clamped to the
+    /// document like [`pos_at_coords`].
+    ///
+    /// [`pos_at_coords`]: crate::pos_at_coords
+    pub fn render_coords(
+        &self,
+        doc: &Rope,
+        coords: crate::Position,
+        ctx: &mut SnippetRenderCtx,
+    ) -> (Transaction, Selection, RenderedSnippet) {
+        let pos = crate::pos_at_coords(doc.slice(..), coords, true);
+        let selection = Selection::point(pos);
+        self.render(doc, &selection, |range| (range.from(), range.to()), ctx)
+    }
+
+    /// Like [`Snippet::render_at`] but takes the document and a
+    /// `(line, column)` position, deriving the char position and the shared
+    /// indentation prefix internally.
+    pub fn render_at_coords(
+        &self,
+        text: RopeSlice,
+        coords: crate::Position,
+        ctx: &mut SnippetRenderCtx,
+    ) -> (Tendril, RenderedSnippet) {
+        let pos = crate::pos_at_coords(text, coords, true);
+        let newline_with_offset = newline_with_offset(ctx, text, pos);
+        self.render_at(&newline_with_offset, ctx, pos)
+    }
+
+    /// Renders the snippet as if inserted at (char) position `pos`,
+    /// returning the replacement text and the tabstop ranges within it.
+    /// `newline_with_offset` is inserted in place of `\n` so that all lines
+    /// of the snippet share the indentation of the line it's inserted at.
+    pub fn render_at(
+        &self,
+        newline_with_offset: &str,
+        ctx: &mut SnippetRenderCtx,
+        pos: usize,
+    ) -> (Tendril, RenderedSnippet) {
+        self.render_into(
+            Tendril::new(),
+            newline_with_offset,
+            ctx,
+            pos,
+            VariableContext::default(),
+            false,
+        )
+    }
+
+    /// Like [`Snippet::render_at`] but additionally records
+    /// [spans](RenderedSnippet::spans) describing where literal text,
+    /// resolved variable values and tabstop regions ended up in the
+    /// replacement, so the editor can highlight snippet-inserted regions
+    /// distinctly.
+    pub fn render_at_with_spans(
+        &self,
+        newline_with_offset: &str,
+        ctx: &mut SnippetRenderCtx,
+        pos: usize,
+    ) -> (Tendril, RenderedSnippet) {
+        self.render_into(
+            Tendril::new(),
+            newline_with_offset,
+            ctx,
+            pos,
+            VariableContext::default(),
+            true,
+        )
+    }
+
+    /// Like [`Snippet::render_at`] but renders into caller-provided
+    /// buffers: `text` is cleared and receives the replacement, `snippet`
+    /// is cleared and refilled, keeping its allocations. Useful when many
+    /// snippets are rendered in a row (e.g. a completion preview for every
+    /// candidate while scrolling).
+    pub fn render_at_into(
+        &self,
+        text: &mut Tendril,
+        snippet: &mut RenderedSnippet,
+        newline_with_offset: &str,
+        ctx: &mut SnippetRenderCtx,
+        pos: usize,
+    ) {
+        text.clear();
+        self.render_in(
+            text,
+            snippet,
+            newline_with_offset,
+            ctx,
+            pos,
+            VariableContext::default(),
+            false,
+        );
+    }
+
+    /// Like [`Snippet::render_at`] but streams the replacement into a rope
+    /// builder, avoiding one large contiguous allocation when expanding
+    /// multi-kilobyte templates (possibly at many cursors).
+    pub fn render_rope_at(
+        &self,
+        newline_with_offset: &str,
+        ctx: &mut SnippetRenderCtx,
+        pos: usize,
+    ) -> (Rope, RenderedSnippet) {
+        let (builder, snippet) = self.render_into(
+            ropey::RopeBuilder::new(),
+            newline_with_offset,
+            ctx,
+            pos,
+            VariableContext::default(),
+            false,
+        );
+        (builder.finish(), snippet)
+    }
+
+    fn render_into<T: RenderTarget>(
+        &self,
+        mut text: T,
+        newline_with_offset: &str,
+        ctx: &mut SnippetRenderCtx,
+        pos: usize,
+        var_ctx: VariableContext,
+        record_spans: bool,
+    ) -> (T, RenderedSnippet) {
+        let mut snippet = RenderedSnippet::default();
+        self.render_in(
+            &mut text,
+            &mut snippet,
+            newline_with_offset,
+            ctx,
+            pos,
+            var_ctx,
+            record_spans,
+        );
+        (text, snippet)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn render_in<T: RenderTarget>(
+        &self,
+        text: &mut T,
+        dst: &mut RenderedSnippet,
+        newline_with_offset: &str,
+        ctx: &mut SnippetRenderCtx,
+        pos: usize,
+        var_ctx: VariableContext,
+        record_spans: bool,
+    ) {
+        dst.clear();
+        dst.tabstops.extend(self.tabstops().map(|tabstop| Tabstop {
+            ranges: SmallVec::new(),
+            byte_ranges: SmallVec::new(),
+            parent: tabstop.parent,
+            kind: match &tabstop.kind {
+                elaborate::TabstopKind::Choice { choices } => TabstopKind::Choice {
+                    choices: choices.clone(),
+                },
+                elaborate::TabstopKind::Placeholder { .. } => TabstopKind::Placeholder,
+                elaborate::TabstopKind::Empty => TabstopKind::Empty,
+                elaborate::TabstopKind::Transform(transform) => {
+                    TabstopKind::Transform(transform.clone())
+                }
+            },
+        }));
+        let newline_with_offset_chars = newline_with_offset.chars().count();
+        let mut render = SnippetRender {
+            dst,
+            src: self,
+            text,
+            off: pos,
+            byte_off: 0,
+            newline_with_offset,
+            newline_with_offset_chars,
+            // the first line starts right after the shared indentation, so
+            // its column is the prefix minus the line ending
+            col: newline_with_offset_chars.saturating_sub(ctx.line_ending.chars().count()),
+            ctx,
+            line_indent: String::new(),
+            nested_indent: String::new(),
+            pending_indent: String::new(),
+            last_flush: (usize::MAX, 0, usize::MAX, 0),
+            line_has_content: true,
+            pending_eol_bytes: 0,
+            line_had_element: false,
+            var_ctx,
+            spans: record_spans.then(Vec::new),
+        };
+        render.render_elements(self.elements());
+        render.finish();
+        let end = render.off;
+        let byte_end = render.byte_off;
+        let spans = render.spans;
+        dst.ranges.push(Range::new(pos, end));
+        dst.byte_ranges.push((0, byte_end));
+        #[cfg(feature = "render-metrics")]
+        {
+            dst.metrics.bytes += byte_end;
+        }
+        if let Some(spans) = spans {
+            dst.spans = spans;
+        }
+    }
+}
+
+/// The line ending plus the indentation that all lines of an instance
+/// expanded at (char) position `pos` share, either from the indent hook or
+/// copied from the line at `pos`.
+fn newline_with_offset(ctx: &mut SnippetRenderCtx, text: RopeSlice, pos: usize) -> String {
+    let indent = ctx
+        .resolve_indent
+        .as_mut()
+        .and_then(|resolve_indent| resolve_indent(pos))
+        .unwrap_or_else(|| {
+            let line_idx = text.char_to_line(pos);
+            let indent_level = indent_level_for_line(
+                text.line(line_idx),
+                ctx.tab_width,
+                ctx.indent_style.indent_width(ctx.tab_width),
+            );
+            ctx.indent_style.as_str().repeat(indent_level)
+        });
+    format!("{}{indent}", ctx.line_ending)
+}
+
+/// Whether the char is a Unicode bidi control or zero-width character, see
+/// [`InvisibleCharPolicy`].
+fn is_invisible_char(c: char) -> bool {
+    matches!(
+        c,
+        // explicit bidi embeddings, overrides and isolates plus the
+        // implicit marks
+        '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}' | '\u{200e}' | '\u{200f}' | '\u{061c}'
+        // zero-width space, (non-)joiners, word joiner and the BOM
+        | '\u{200b}'..='\u{200d}' | '\u{2060}' | '\u{feff}'
+    )
+}
+
+/// Where a [`SnippetRender`] writes the replacement text: either a plain
+/// [`Tendril`] or a rope builder that stores large templates in chunks.
+trait RenderTarget {
+    fn push_str(&mut self, text: &str);
+}
+
+impl RenderTarget for Tendril {
+    fn push_str(&mut self, text: &str) {
+        Tendril::push_str(self, text)
+    }
+}
+
+impl RenderTarget for ropey::RopeBuilder {
+    fn push_str(&mut self, text: &str) {
+        self.append(text)
+    }
+}
+
+/// [`Snippet::measure`] discards the text and keeps only the positions.
+impl RenderTarget for () {
+    fn push_str(&mut self, _text: &str) {}
+}
+
+struct SnippetRender<'a, T> {
+    ctx: &'a mut SnippetRenderCtx,
+    src: &'a Snippet,
+    dst: &'a mut RenderedSnippet,
+    text: &'a mut T,
+    off: usize,
+    byte_off: usize,
+    newline_with_offset: &'a str,
+    /// The char length of `newline_with_offset`, precomputed since the
+    /// prefix is pushed for every rendered line and may contain non-ASCII
+    /// whitespace copied from the document (or comment leaders).
+    newline_with_offset_chars: usize,
+    /// The current output column (in chars), tracked so hard-wrapping knows
+    /// when a word would cross [`SnippetRenderCtx::text_width`].
+    col: usize,
+    /// The (converted) indentation of the current output line, tracked so
+    /// nested defaults know the indentation context they start in.
+    line_indent: String,
+    /// Indentation prepended to every new line while rendering a nested
+    /// placeholder default, preserving the author's relative indentation.
+    nested_indent: String,
+    /// Indentation of the current line that hasn't been emitted yet, see
+    /// [`SnippetRenderCtx::trim_trailing_whitespace`]: it's only flushed
+    /// once some content ends up on the line, and dropped when none does.
+    pending_indent: String,
+    /// The `(char_pos, chars, byte_pos, bytes)` of the most recent
+    /// [`SnippetRender::flush_indent`], so positions captured before the
+    /// flush can be adjusted after it, see
+    /// [`SnippetRender::flushed_position`].
+    last_flush: (usize, usize, usize, usize),
+    /// Whether anything besides indentation was emitted on the current
+    /// output line, see [`SnippetRenderCtx::auto_indent_final_tabstop`].
+    /// The first line conservatively counts as having content, the
+    /// expansion position may be preceded by document text.
+    line_has_content: bool,
+    /// The byte length of the line ending sitting at the start of
+    /// `pending_indent`, or zero once it was flushed. Only non-zero with
+    /// [`SnippetRenderCtx::collapse_blank_lines`], where the line ending is
+    /// deferred along with the indentation so a line that stays blank can
+    /// be dropped entirely.
+    pending_eol_bytes: usize,
+    /// Whether a variable or (non-final) tabstop started on the current
+    /// output line, distinguishing a line that became blank because its
+    /// elements rendered to nothing from a literal blank line, see
+    /// [`SnippetRenderCtx::collapse_blank_lines`].
+    line_had_element: bool,
+    var_ctx: VariableContext,
+    /// `Some` when the caller asked for span metadata, see
+    /// [`Snippet::render_at_with_spans`]. Taken out while rendering a
+    /// tabstop's default so the tabstop records one span for its whole
+    /// region instead of overlapping with its contents.
+    spans: Option<Vec<(Range, SpanKind)>>,
+}
+
+impl<T: RenderTarget> SnippetRender<'_, T> {
+    fn render_elements(&mut self, elements: &[SnippetElement]) {
+        for element in elements {
+            self.render_element(element)
+        }
+    }
+
+    fn render_element(&mut self, element: &SnippetElement) {
+        #[cfg(feature = "render-metrics")]
+        {
+            self.dst.metrics.elements += 1;
+        }
+        match element {
+            SnippetElement::Tabstop { idx } => self.render_tabstop(*idx),
+            SnippetElement::Variable {
+                name,
+                default,
+                transform,
+            } => {
+                // `${ALIGN}` is a helix extension: it renders as nothing
+                // and records the column it sits at, so the alignment pass
+                // of [`Snippet::render_aligned`] can pad it out
+                if &**name == "ALIGN" {
+                    self.dst
+                        .align_points
+                        .push((self.off, self.byte_off, self.col));
+                    return;
+                }
+                self.line_had_element = true;
+                // `${date:FORMAT}` is a helix extension: the "default" is
+                // an strftime format string, handed to the resolver as part
+                // of the name. When the resolver doesn't know `date:` the
+                // format renders as ordinary default text.
+                if &**name == "date" && !default.is_empty() {
+                    let mut lookup = String::from("date:");
+                    for element in default.iter() {
+                        if let SnippetElement::Text(text) = element {
+                            lookup.push_str(text);
+                        }
+                    }
+                    if let Some(value) = self
+                        .resolve(&lookup)
+                        .and_then(|value| self.normalize_value(value, transform.is_some()))
+                    {
+                        let start = self.off;
+                        match transform {
+                            Some(transform) => {
+                                let value = self.apply_transform(transform, &value);
+                                self.push_str(&value);
+                            }
+                            None => self.push_str(&value),
+                        }
+                        let (start, _) = self.flushed_position(start, 0);
+                        self.dst.variables.push(PendingVariable {
+                            name: lookup.as_str().into(),
+                            range: Range::new(start, self.off),
+                            ctx: self.var_ctx,
+                            transform: transform.clone(),
+                        });
+                        self.record_span(start, SpanKind::Variable);
+                        return;
+                    }
+                }
+                // TODO: allow resolve_var access to the document so that
+                // document-dependent variables can be resolved per cursor
+                let bound = &**name == "TM_SELECTED_TEXT"
+                    && self.var_ctx.selection_idx < self.ctx.selected_texts.len();
+                if !bound && self.ctx.resolve_var.is_pending(name) {
+                    let start = self.off;
+                    self.render_elements(default);
+                    let (start, _) = self.flushed_position(start, 0);
+                    let occurrence = PendingVariable {
+                        name: name.clone(),
+                        range: Range::new(start, self.off),
+                        ctx: self.var_ctx,
+                        transform: transform.clone(),
+                    };
+                    self.dst.pending_variables.push(occurrence.clone());
+                    self.dst.variables.push(occurrence);
+                } else if let Some(value) = self
+                    .resolve(name)
+                    .and_then(|value| self.normalize_value(value, transform.is_some()))
+                {
+                    let start = self.off;
+                    match transform {
+                        Some(transform) => {
+                            let value = self.apply_transform(transform, &value);
+                            self.push_str(&value);
+                        }
+                        None => self.push_str(&value),
+                    }
+                    let (start, _) = self.flushed_position(start, 0);
+                    self.dst.variables.push(PendingVariable {
+                        name: name.clone(),
+                        range: Range::new(start, self.off),
+                        ctx: self.var_ctx,
+                        transform: transform.clone(),
+                    });
+                    self.record_span(start, SpanKind::Variable);
+                } else {
+                    self.render_elements(default)
+                }
+            }
+            SnippetElement::Text(text) => {
+                let start = self.off;
+                self.push_str(text);
+                self.record_span(start, SpanKind::Text);
+            }
+        }
+    }
+
+    /// Resolves a variable, letting the selected text bound by
+    /// [`Snippet::render_around_selection`] win over the resolver.
+    fn resolve(&mut self, name: &str) -> Option<Cow<'static, str>> {
+        if name == "TM_SELECTED_TEXT" {
+            if let Some(selected) = self.ctx.selected_texts.get(self.var_ctx.selection_idx) {
+                return Some(self.sanitize_value(Cow::from(selected.clone())));
+            }
+        }
+        #[cfg(feature = "render-metrics")]
+        let resolve_start = std::time::Instant::now();
+        let value = self.ctx.resolve_var.resolve_var(name, &self.var_ctx);
+        #[cfg(feature = "render-metrics")]
+        {
+            self.dst.metrics.resolver_time += resolve_start.elapsed();
+        }
+        value.map(|value| self.sanitize_value(value))
+    }
+
+    /// Applies [`SnippetRenderCtx::invisible_chars`] to a resolved value,
+    /// stripping or escaping bidi control and zero-width characters.
+    fn sanitize_value(&self, value: Cow<'static, str>) -> Cow<'static, str> {
+        use std::fmt::Write;
+
+        if matches!(self.ctx.invisible_chars, InvisibleCharPolicy::Keep)
+            || !value.chars().any(is_invisible_char)
+        {
+            return value;
+        }
+        let mut res = String::with_capacity(value.len());
+        for c in value.chars() {
+            if !is_invisible_char(c) {
+                res.push(c);
+            } else if matches!(self.ctx.invisible_chars, InvisibleCharPolicy::Escape) {
+                write!(res, "\\u{{{:04x}}}", c as u32).unwrap();
+            }
+        }
+        Cow::from(res)
+    }
+
+    /// Applies [`SnippetRenderCtx::multiline_values`] to a resolved value:
+    /// in a single-line context (a transform input, or a snippet whose body
+    /// is one line) a value containing line breaks is collapsed, escaped or
+    /// rejected. Returns `None` for a rejected value, so the variable's
+    /// default text renders instead.
+    fn normalize_value(
+        &self,
+        value: Cow<'static, str>,
+        has_transform: bool,
+    ) -> Option<Cow<'static, str>> {
+        if !value.contains('\n') || (!has_transform && !self.src.is_single_line()) {
+            return Some(value);
+        }
+        match self.ctx.multiline_values {
+            MultilineValuePolicy::Keep => Some(value),
+            MultilineValuePolicy::Collapse => {
+                let mut res = String::with_capacity(value.len());
+                for line in value.lines() {
+                    let line = line.trim();
+                    if line.is_empty() {
+                        continue;
+                    }
+                    if !res.is_empty() {
+                        res.push(' ');
+                    }
+                    res.push_str(line);
+                }
+                Some(Cow::from(res))
+            }
+            MultilineValuePolicy::Escape => {
+                Some(Cow::from(value.replace("\r\n", "\\n").replace('\n', "\\n")))
+            }
+            MultilineValuePolicy::Reject => None,
+        }
+    }
+
+    /// Applies a regex transform to a resolved value, timing it when the
+    /// `render-metrics` feature is enabled.
+    fn apply_transform(&mut self, transform: &Transform, value: &str) -> Tendril {
+        #[cfg(feature = "render-metrics")]
+        let transform_start = std::time::Instant::now();
+        let res = transform.apply(value);
+        #[cfg(feature = "render-metrics")]
+        {
+            self.dst.metrics.transform_time += transform_start.elapsed();
+        }
+        res
+    }
+
+    /// Records that `start..self.off` was rendered from `kind`. Adjacent
+    /// literal text spans merge; tabstops and variables stay distinct so
+    /// their boundaries survive.
+    fn record_span(&mut self, start: usize, kind: SpanKind) {
+        if self.spans.is_none() {
+            return;
+        }
+        let (start, _) = self.flushed_position(start, 0);
+        let Some(spans) = &mut self.spans else { return };
+        if start == self.off {
+            return;
+        }
+        if let Some((last, last_kind)) = spans.last_mut() {
+            if *last_kind == kind && kind == SpanKind::Text && last.to() == start {
+                *last = Range::new(last.from(), self.off);
+                return;
+            }
+        }
+        spans.push((Range::new(start, self.off), kind));
+    }
+
+    fn render_tabstop(&mut self, idx: TabstopIdx) {
+        // the final tabstop is a cursor position rather than content: a
+        // line holding only `$0` is not collapsed
+        if idx.0 + 1 != self.dst.tabstops.len() {
+            self.line_had_element = true;
+        }
+        // the final tabstop on an otherwise empty, unindented line gets one
+        // indent level so the cursor lands indented for the next statement,
+        // see [`SnippetRenderCtx::auto_indent_final_tabstop`]
+        if self.ctx.auto_indent_final_tabstop
+            && idx.0 + 1 == self.dst.tabstops.len()
+            && !self.line_has_content
+            && self.line_indent.is_empty()
+        {
+            self.flush_indent();
+            let indent = self.ctx.indent_style.as_str();
+            self.push_raw(indent);
+            self.line_indent.push_str(indent);
+            self.line_has_content = true;
+        }
+        let start = self.off;
+        let byte_start = self.byte_off;
+        // one span covers the whole tabstop region, its contents don't
+        // record spans of their own
+        let spans = self.spans.take();
+        match &self.src[idx].kind {
+            elaborate::TabstopKind::Placeholder { default } => {
+                let default = default.clone();
+                // lines of a multi-line default keep their position relative
+                // to the line the placeholder starts on
+                let outer_indent =
+                    std::mem::replace(&mut self.nested_indent, self.line_indent.clone());
+                self.render_elements(&default);
+                self.nested_indent = outer_indent;
+            }
+            // a choice renders its first value as the placeholder text, the
+            // full list stays available on the rendered tabstop so the UI
+            // can pop a picker on activation
+            elaborate::TabstopKind::Choice { choices } => {
+                if let Some(choice) = choices.first() {
+                    let value = choice.value.clone();
+                    self.push_str(&value);
+                }
+            }
+            _ => (),
+        }
+        self.spans = spans;
+        self.record_span(start, SpanKind::Tabstop);
+        let (start, byte_start) = self.flushed_position(start, byte_start);
+        let end = self.off;
+        self.dst[idx].ranges.push(Range::new(start, end));
+        self.dst[idx].byte_ranges.push((byte_start, self.byte_off));
+    }
+
+    fn push_str(&mut self, text: &str) {
+        // small optimization to avoid splitting when it's unnecessary
+        if !text.contains('\n') {
+            self.push_chunk(text);
+            return;
+        }
+        let newline_with_offset = self.newline_with_offset;
+        let continue_comment = self.ctx.continue_comment.clone();
+        let nested_indent = self.nested_indent.clone();
+        let mut lines = text.split('\n');
+        let mut next = lines.next();
+        let mut first = true;
+        while let Some(line) = next {
+            next = lines.next();
+            // a snippet body may use CRLF line endings, the carriage return
+            // is covered by `newline_with_offset` like the newline itself
+            let line = match next {
+                Some(_) => line.strip_suffix('\r').unwrap_or(line),
+                None => line,
+            };
+            if first {
+                self.push_chunk(line);
+                first = false;
+                continue;
+            }
+            // the prefix's char length is known, no need to recount it for
+            // every line
+            if self.defers_indent() {
+                // the previous line stayed blank, decide what survives of it
+                self.handle_blank_line();
+                let (eol, line_offset) = match newline_with_offset
+                    .strip_prefix(self.ctx.line_ending)
+                {
+                    Some(line_offset) => (self.ctx.line_ending, line_offset),
+                    // `render_at` callers may pass a prefix with a
+                    // different line ending than the context
+                    None => (newline_with_offset, ""),
+                };
+                if self.ctx.collapse_blank_lines {
+                    // the line ending (and comment leader) are deferred
+                    // along with the indentation, a line that stays blank
+                    // may be dropped entirely
+                    self.pending_indent.push_str(eol);
+                    self.pending_eol_bytes = eol.len();
+                    self.col = 0;
+                    if let Some(leader) = &continue_comment {
+                        self.pending_indent.push_str(leader);
+                    }
+                } else {
+                    // only the line ending is emitted eagerly, the
+                    // indentation is deferred until some content ends up on
+                    // the line
+                    self.push_raw(eol);
+                    self.col = 0;
+                    if let Some(leader) = &continue_comment {
+                        self.push_raw(leader);
+                    }
+                }
+                self.pending_indent.push_str(line_offset);
+            } else {
+                self.off += self.newline_with_offset_chars;
+                self.byte_off += newline_with_offset.len();
+                self.text.push_str(newline_with_offset);
+                self.col = self
+                    .newline_with_offset_chars
+                    .saturating_sub(self.ctx.line_ending.chars().count());
+                // inside a (doc) comment every line must restate the
+                // comment leader or the following lines fall out of the
+                // comment
+                if let Some(leader) = &continue_comment {
+                    self.push_raw(leader);
+                }
+            }
+            self.line_has_content = false;
+            self.line_had_element = false;
+            if !nested_indent.is_empty() {
+                self.push_indent(&nested_indent);
+            }
+            self.line_indent.clear();
+            self.line_indent.push_str(&nested_indent);
+            // the snippet author's indentation is converted to the
+            // document's indent style, mixed indentation inserted verbatim
+            // looks broken
+            let body = line.trim_start_matches([' ', '\t']);
+            let whitespace = &line[..line.len() - body.len()];
+            if !whitespace.is_empty() {
+                let tab_width = self.ctx.tab_width;
+                let indent_width = self.ctx.indent_style.indent_width(tab_width);
+                let mut width = 0;
+                for c in whitespace.chars() {
+                    width += match c {
+                        '\t' => tab_width - (width % tab_width),
+                        _ => 1,
+                    };
+                }
+                let indent = self.ctx.indent_style.as_str();
+                for _ in 0..width / indent_width {
+                    self.push_indent(indent);
+                    self.line_indent.push_str(indent);
+                }
+                for _ in 0..width % indent_width {
+                    self.push_indent(" ");
+                    self.line_indent.push(' ');
+                }
+            }
+            self.push_chunk(body);
+        }
+    }
+
+    fn push_chunk(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        self.line_has_content = true;
+        self.flush_indent();
+        match self.ctx.text_width {
+            Some(text_width) => self.push_wrapped(text, text_width),
+            None => self.push_raw(text),
+        }
+    }
+
+    /// Whether line indentation (and with
+    /// [`SnippetRenderCtx::collapse_blank_lines`] the line ending) is held
+    /// back until some content ends up on the line.
+    fn defers_indent(&self) -> bool {
+        self.ctx.trim_trailing_whitespace || self.ctx.collapse_blank_lines
+    }
+
+    /// Emits line indentation, deferring it while trailing whitespace
+    /// trimming or blank line collapsing is on so a line that stays blank
+    /// doesn't keep it, see [`SnippetRender::flush_indent`].
+    fn push_indent(&mut self, text: &str) {
+        if self.defers_indent() {
+            self.pending_indent.push_str(text);
+        } else {
+            self.push_raw(text);
+        }
+    }
+
+    /// Emits the deferred indentation once content ends up on the line.
+    /// Positions recorded on the still-blank line -- empty tabstops waiting
+    /// at its start -- move past the indentation.
+    fn flush_indent(&mut self) {
+        if self.pending_indent.is_empty() {
+            return;
+        }
+        let pending = std::mem::take(&mut self.pending_indent);
+        let (chars, bytes) = (pending.chars().count(), pending.len());
+        self.dst
+            .shift_for_insertion(self.off, chars, self.byte_off, bytes);
+        self.last_flush = (self.off, chars, self.byte_off, bytes);
+        self.push_raw(&pending);
+        self.pending_indent = pending;
+        self.pending_indent.clear();
+        self.pending_eol_bytes = 0;
+    }
+
+    /// Disposes of the deferred prefix of a line that ended without
+    /// content: with [`SnippetRenderCtx::collapse_blank_lines`] a line
+    /// whose elements all rendered to nothing is dropped entirely, while a
+    /// literal blank line keeps its line ending. Without collapsing only
+    /// the deferred indentation is dropped (the line ending was emitted
+    /// eagerly).
+    fn handle_blank_line(&mut self) {
+        if self.pending_eol_bytes != 0 && !self.line_had_element {
+            let pending = std::mem::take(&mut self.pending_indent);
+            self.push_raw(&pending[..self.pending_eol_bytes]);
+            self.pending_indent = pending;
+        }
+        self.pending_indent.clear();
+        self.pending_eol_bytes = 0;
+    }
+
+    /// Settles the last rendered line, which may still hold a deferred
+    /// line ending, see [`SnippetRender::handle_blank_line`].
+    fn finish(&mut self) {
+        if self.defers_indent() {
+            self.handle_blank_line();
+        }
+    }
+
+    /// Adjusts a `(char, byte)` position captured before the content that
+    /// followed it was rendered: when the line's deferred indentation was
+    /// flushed right at the captured position, the position moves past it.
+    /// A flush at the same position strictly before the capture can't
+    /// happen, a flush always emits at least one char.
+    fn flushed_position(&self, start: usize, byte_start: usize) -> (usize, usize) {
+        let (char_pos, chars, _, bytes) = self.last_flush;
+        if char_pos == start {
+            (start + chars, byte_start + bytes)
+        } else {
+            (start, byte_start)
+        }
+    }
+
+    /// Pushes content that hard-wrapping must never break inside of, like
+    /// indentation or comment leaders.
+    fn push_raw(&mut self, text: &str) {
+        let mut chars = 0;
+        for c in text.chars() {
+            chars += 1;
+            // the position just inside an inserted bracket or quote, where
+            // auto-pairs may want to skip a duplicate closer
+            if matches!(c, '(' | '[' | '{' | '\'' | '"' | '`') {
+                self.dst.pair_positions.push(self.off + chars);
+            }
+        }
+        self.off += chars;
+        self.col += chars;
+        self.byte_off += text.len();
+        self.text.push_str(text);
+    }
+
+    /// Greedy hard-wrapping: a space becomes a line break when the word it
+    /// precedes would cross the text width. Words are only considered
+    /// within a chunk, a word split across a text element boundary (like a
+    /// mirror directly following text) may still cross the limit.
+    fn push_wrapped(&mut self, text: &str, text_width: usize) {
+        for (i, word) in text.split(' ').enumerate() {
+            if i != 0 {
+                if !word.is_empty() && self.col + 1 + word.chars().count() > text_width {
+                    self.break_line();
+                } else {
+                    self.push_raw(" ");
+                }
+            }
+            self.push_raw(word);
+        }
+    }
+
+    /// Starts a continuation line for hard-wrapping, restating the shared
+    /// indentation, the comment leader and the current line's indentation.
+    fn break_line(&mut self) {
+        let newline_with_offset = self.newline_with_offset;
+        self.off += self.newline_with_offset_chars;
+        self.byte_off += newline_with_offset.len();
+        self.text.push_str(newline_with_offset);
+        self.col = self
+            .newline_with_offset_chars
+            .saturating_sub(self.ctx.line_ending.chars().count());
+        if let Some(leader) = self.ctx.continue_comment.clone() {
+            self.push_raw(&leader);
+        }
+        if !self.line_indent.is_empty() {
+            let line_indent = std::mem::take(&mut self.line_indent);
+            self.push_raw(&line_indent);
+            self.line_indent = line_indent;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::snippets::{Snippet, SnippetRenderCtx};
+
+    fn render(snippet: &str) -> (String, Vec<Vec<(usize, usize)>>) {
+        let snippet = Snippet::parse(snippet).unwrap();
+        let (text, rendered) = snippet.render_at("\n", &mut SnippetRenderCtx::test_ctx(), 0);
+        let tabstops = rendered
+            .tabstops
+            .iter()
+            .map(|tabstop| {
+                tabstop
+                    .ranges
+                    .iter()
+                    .map(|range| (range.from(), range.to()))
+                    .collect()
+            })
+            .collect();
+        (text.to_string(), tabstops)
+    }
+
+    fn assert_text(snippet: &str, expect: &str) {
+        assert_eq!(render(snippet).0, expect);
+    }
+
+    #[test]
+    fn rendered_tabstop_ranges() {
+        let (text, tabstops) = render("match(${1:Arg1})");
+        assert_eq!(text, "match(Arg1)");
+        // $1 covers the placeholder, the implicit $0 sits at the end
+        assert_eq!(tabstops, &[vec![(6, 10)], vec![(11, 11)]]);
+    }
+
+    #[test]
+    fn rendered_mirror_ranges() {
+        // mirrors of a tabstop all render the same (first) placeholder text
+        let (text, tabstops) = render("local ${1:var} = ${1:value}");
+        assert_eq!(text, "local var = var");
+        assert_eq!(tabstops, &[vec![(6, 9), (12, 15)], vec![(15, 15)]]);
+    }
+
+    #[test]
+    fn span_metadata() {
+        use std::borrow::Cow;
+
+        use crate::snippets::render::SpanKind;
+        use crate::Range;
+
+        let snippet = Snippet::parse("fn ${1:name}() { $VAR }$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        ctx.set_resolver(Box::new(|name: &str| {
+            (name == "VAR").then(|| Cow::from("value"))
+        }));
+        let (text, rendered) = snippet.render_at_with_spans("\n", &mut ctx, 0);
+        assert_eq!(text, "fn name() { value }");
+        assert_eq!(
+            rendered.spans,
+            &[
+                (Range::new(0, 3), SpanKind::Text),
+                (Range::new(3, 7), SpanKind::Tabstop),
+                (Range::new(7, 12), SpanKind::Text),
+                (Range::new(12, 17), SpanKind::Variable),
+                (Range::new(17, 19), SpanKind::Text),
+            ]
+        );
+        // the plain entry points don't pay for span collection
+        let (_, rendered) = snippet.render_at("\n", &mut ctx, 0);
+        assert!(rendered.spans.is_empty());
+    }
+
+    #[test]
+    fn choice_renders_first_choice() {
+        use crate::snippets::render::TabstopKind;
+
+        let snippet = Snippet::parse("${1|public,private,protected|} fn$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (text, rendered) = snippet.render_at("\n", &mut ctx, 0);
+        assert_eq!(text, "public fn");
+        let tabstop = &rendered.tabstops[0];
+        assert_eq!(tabstop.ranges[0].from(), 0);
+        assert_eq!(tabstop.ranges[0].to(), 6);
+        assert!(tabstop.has_placeholder());
+        // the choices stay available for a picker
+        let TabstopKind::Choice { choices } = &tabstop.kind else {
+            panic!("expected a choice tabstop")
+        };
+        let values: Vec<_> = choices.iter().map(|choice| choice.value.as_str()).collect();
+        assert_eq!(values, ["public", "private", "protected"]);
+    }
+
+    #[test]
+    fn resolve_indent_overrides_line_indent() {
+        use crate::{Rope, Selection};
+
+        let doc = Rope::from("  x");
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        ctx.resolve_indent = Some(Box::new(|_| Some("\t".to_string())));
+        let snippet = Snippet::parse("a\nb$0").unwrap();
+        let (transaction, _, _) = snippet.render(
+            &doc,
+            &Selection::point(3),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        let mut doc = doc;
+        assert!(transaction.apply(&mut doc));
+        // the hook's indentation is used instead of the line's two spaces
+        assert_eq!(doc, "  xa\n\tb");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        use crate::snippets::render::RenderedSnippet;
+
+        // covers all tabstop kinds, including the transform that has to
+        // recompile its regex on the way back in
+        let snippet =
+            Snippet::parse("${1|a,b|} ${2:x} ${2/(.*)/-$1-/g}$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (_, rendered) = snippet.render_at("\n", &mut ctx, 0);
+        let json = serde_json::to_string(&rendered).unwrap();
+        let restored: RenderedSnippet = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, rendered);
+    }
+
+    #[test]
+    fn selection_for_arbitrary_tabstop() {
+        use crate::movement::Direction;
+        use crate::snippets::TabstopIdx;
+        use crate::Range;
+
+        let snippet = Snippet::parse("fn ${1:name}() { ${2:body} }$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (text, rendered) = snippet.render_at("\n", &mut ctx, 0);
+        assert_eq!(text, "fn name() { body }");
+        let selection = rendered
+            .selection_for_tabstop(TabstopIdx(1), Direction::Forward, 0)
+            .unwrap();
+        assert_eq!(selection.primary(), Range::new(12, 16));
+        assert!(rendered
+            .selection_for_tabstop(TabstopIdx(7), Direction::Forward, 0)
+            .is_none());
+    }
+
+    #[test]
+    fn tabstop_at_position() {
+        use crate::snippets::TabstopIdx;
+
+        let snippet = Snippet::parse("${1:ab} $1 ${2:cd}$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (text, rendered) = snippet.render_at("\n", &mut ctx, 0);
+        assert_eq!(text, "ab ab cd");
+        // inside the first placeholder
+        assert_eq!(rendered.tabstop_at(1), Some((TabstopIdx(0), 0)));
+        // inside its mirror
+        assert_eq!(rendered.tabstop_at(4), Some((TabstopIdx(0), 1)));
+        // inside the second tabstop
+        assert_eq!(rendered.tabstop_at(7), Some((TabstopIdx(1), 0)));
+        // between tabstops only the zero-width final tabstop at the very
+        // end matches
+        assert_eq!(rendered.tabstop_at(8), Some((TabstopIdx(2), 0)));
+        assert_eq!(rendered.tabstop_at(9), None);
+    }
+
+    #[test]
+    fn indent_prefixes_per_cursor() {
+        use crate::{smallvec, Range, Rope, Selection};
+
+        // cursors on lines with different indent levels each get their own
+        // (cached) prefix
+        let doc = Rope::from("a\n    b");
+        let selection = Selection::new(smallvec![Range::point(1), Range::point(7)], 0);
+        let snippet = Snippet::parse("x\ny$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (transaction, _, _) = snippet.render(
+            &doc,
+            &selection,
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        let mut doc = doc;
+        assert!(transaction.apply(&mut doc));
+        assert_eq!(doc, "ax\ny\n    bx\n    y");
+    }
+
+    #[test]
+    fn render_into_reused_buffers() {
+        use crate::snippets::render::RenderedSnippet;
+        use crate::Tendril;
+
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let mut text = Tendril::new();
+        let mut scratch = RenderedSnippet::default();
+        for body in ["fn ${1:name}()$0", "let ${1:x} = $2;$0"] {
+            let snippet = Snippet::parse(body).unwrap();
+            snippet.render_at_into(&mut text, &mut scratch, "\n", &mut ctx, 0);
+            let (expected_text, expected) = snippet.render_at("\n", &mut ctx, 0);
+            assert_eq!(text, expected_text);
+            assert_eq!(scratch, expected);
+        }
+    }
+
+    #[test]
+    fn cursor_placement() {
+        use crate::movement::Direction;
+        use crate::snippets::render::{CursorPlacement, CursorPlacementPolicy};
+        use crate::Range;
+
+        let snippet = Snippet::parse("${1:foo}$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (_, rendered) = snippet.render_at("\n", &mut ctx, 0);
+        let tabstop = &rendered.tabstops[0];
+        let policy = CursorPlacementPolicy {
+            placeholder: CursorPlacement::End,
+            ..Default::default()
+        };
+        let placement = policy.placement(&tabstop.kind);
+        assert_eq!(placement, CursorPlacement::End);
+        let selection =
+            tabstop.selection_with_placement(Direction::Forward, 0, 1, placement);
+        assert_eq!(selection.primary(), Range::point(3));
+        // the default policy keeps the selecting behavior
+        let selection = tabstop.selection(Direction::Forward, 0, 1);
+        assert_eq!(selection.primary(), Range::new(0, 3));
+    }
+
+    #[test]
+    fn render_primary_leaves_other_selections() {
+        use crate::{smallvec, Range, Rope, Selection};
+
+        let doc = Rope::from("a b c");
+        let selection = Selection::new(
+            smallvec![Range::point(0), Range::point(2), Range::point(4)],
+            1,
+        );
+        let snippet = Snippet::parse("X${1:mid}Y$0").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let (transaction, mapped, rendered) = snippet.render_primary(
+            &doc,
+            &selection,
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        let mut doc = doc;
+        assert!(transaction.apply(&mut doc));
+        // only the primary cursor expanded
+        assert_eq!(doc, "a XmidYb c");
+        assert_eq!(rendered.ranges, &[Range::new(2, 7)]);
+        assert_eq!(rendered.tabstops[0].ranges[0], Range::new(3, 6));
+        // the other cursors moved with the change
+        assert_eq!(mapped.ranges()[0], Range::point(0));
+        assert_eq!(mapped.ranges()[2], Range::point(9));
+    }
+
+    #[test]
+    fn insert_vs_replace() {
+        use crate::snippets::render::EditMode;
+        use crate::{Rope, Selection};
+
+        // the cursor sits between "pri" and "vacy", the completion matched
+        // the whole word
+        let doc = Rope::from("pub privacy");
+        let snippet = Snippet::parse("println!($1)$0").unwrap();
+        let ranges = |range: &crate::Range| ((4, range.from()), (4, 11));
+        for (mode, expected) in [
+            (EditMode::Insert, "pub println!()vacy"),
+            (EditMode::Replace, "pub println!()"),
+        ] {
+            let mut ctx = SnippetRenderCtx::test_ctx();
+            let (transaction, _, _) = snippet.render_insert_replace(
+                &doc,
+                &Selection::point(7),
+                ranges,
+                mode,
+                &mut ctx,
+            );
+            let mut doc = doc.clone();
+            assert!(transaction.apply(&mut doc));
+            assert_eq!(doc, expected);
+        }
+    }
+
+    #[test]
+    fn pending_variables_resolve_in_two_phases() {
+        use std::borrow::Cow;
+
+        use crate::snippets::render::{VariableContext, VariableResolver};
+        use crate::{Range, Rope, Selection};
+
+        struct Clipboard {
+            value: Option<Cow<'static, str>>,
+        }
+        impl VariableResolver for Clipboard {
+            fn resolve_var(
+                &mut self,
+                name: &str,
+                _ctx: &VariableContext,
+            ) -> Option<Cow<'static, str>> {
+                (name == "CLIPBOARD").then(|| self.value.clone()).flatten()
+            }
+            fn is_pending(&mut self, name: &str) -> bool {
+                name == "CLIPBOARD" && self.value.is_none()
+            }
+        }
+
+        let doc = Rope::from("");
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        ctx.set_resolver(Box::new(Clipboard { value: None }));
+        let snippet = Snippet::parse("paste ${CLIPBOARD:nothing}$0").unwrap();
+        let (transaction, _, rendered) = snippet.render(
+            &doc,
+            &Selection::point(0),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        let mut doc = doc;
+        assert!(transaction.apply(&mut doc));
+        // the first phase renders the default text and reports the variable
+        assert_eq!(doc, "paste nothing");
+        assert_eq!(rendered.pending_variables.len(), 1);
+        assert_eq!(&*rendered.pending_variables[0].name, "CLIPBOARD");
+        assert_eq!(rendered.pending_variables[0].range, Range::new(6, 13));
+
+        // nothing to patch while the value still isn't available
+        let mut empty = Clipboard { value: None };
+        assert!(rendered.resolve_pending(&doc, &mut empty).is_none());
+
+        // the second phase swaps the default for the arrived value
+        let mut clipboard = Clipboard {
+            value: Some(Cow::from("yanked")),
+        };
+        let follow_up = rendered.resolve_pending(&doc, &mut clipboard).unwrap();
+        assert!(follow_up.apply(&mut doc));
+        assert_eq!(doc, "paste yanked");
+    }
+
+    #[test]
+    fn align_marker_lines_up_columns() {
+        use crate::{smallvec, Range, Rope, Selection};
+
+        let doc = Rope::from("a\nlong\n");
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let snippet = Snippet::parse("${ALIGN}= $1;$0").unwrap();
+        // one cursor at the end of each word
+        let selection = Selection::new(smallvec![Range::point(1), Range::point(6)], 0);
+        let (transaction, _, rendered) =
+            snippet.render_aligned(&doc, &selection, |range| (range.from(), range.to()), &mut ctx);
+        let mut doc = doc;
+        assert!(transaction.apply(&mut doc));
+        // the shorter line is padded until the `=` columns match
+        assert_eq!(doc, "a   = ;\nlong= ;\n");
+        assert_eq!(
+            &rendered.tabstops[0].ranges[..],
+            &[Range::point(6), Range::point(14)]
+        );
+    }
+
+    #[test]
+    fn update_variable_patches_only_variable_regions() {
+        use std::borrow::Cow;
+
+        use crate::{Rope, Selection};
+
+        let doc = Rope::from("");
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        ctx.set_resolver(Box::new(|name: &str| {
+            (name == "TM_FILENAME").then(|| Cow::from("old.rs"))
+        }));
+        let snippet = Snippet::parse("// $TM_FILENAME\nfn ${1:name}() {}$0").unwrap();
+        let (transaction, _, rendered) = snippet.render(
+            &doc,
+            &Selection::point(0),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        let mut doc = doc;
+        assert!(transaction.apply(&mut doc));
+        assert_eq!(doc, "// old.rs\nfn name() {}");
+
+        // only the variable region changes, the tabstop default stays
+        let patch = rendered.update_variable(&doc, "TM_FILENAME", "new.rs").unwrap();
+        assert!(patch.apply(&mut doc));
+        assert_eq!(doc, "// new.rs\nfn name() {}");
+
+        // a variable the snippet doesn't contain produces no transaction
+        assert!(rendered.update_variable(&doc, "TM_DIRECTORY", "x").is_none());
+    }
+
+    #[test]
+    fn non_ascii_indentation_keeps_offsets() {
+        use crate::{Range, Rope, Selection};
+
+        // the indent hook hands back non-ASCII whitespace
+        let doc = Rope::from("x");
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        ctx.resolve_indent = Some(Box::new(|_| Some("\u{3000}".to_string())));
+        let snippet = Snippet::parse("a\nb$0").unwrap();
+        let (transaction, _, rendered) = snippet.render(
+            &doc,
+            &Selection::point(1),
+            |range| (range.from(), range.to()),
+            &mut ctx,
+        );
+        let mut doc = doc;
+        assert!(transaction.apply(&mut doc));
+        assert_eq!(doc, "xa\n\u{3000}b");
+        assert_eq!(rendered.tabstops[0].ranges[0], Range::point(5));
+        assert_eq!(rendered.tabstops[0].byte_ranges[0], (7, 7));
+    }
+
+    #[test]
+    fn snippet_indentation_is_converted() {
+        use crate::indent::IndentStyle;
+
+        // the snippet author used tabs, the document indents with 4 spaces
+        let (text, _) = render("if ($1) {\n\tfoo;\n}");
+        assert_eq!(text, "if () {\n    foo;\n}");
+        // and the other way around
+        let snippet = Snippet::parse("while {\n        body\n}").unwrap();
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        ctx.indent_style = IndentStyle::Tabs;
+        let (text, _) = snippet.render_at("\n", &mut ctx, 0);
+        assert_eq!(text, "while {\n\t\tbody\n}");
+    }
+
+    #[test]
+    fn ctx_builder() {
+        use std::borrow::Cow;
+
+        use crate::indent::IndentStyle;
+        use crate::snippets::render::{SnippetRenderOverrides, VariableContext};
+
+        let language = SnippetRenderOverrides {
+            indent_style: Some(IndentStyle::Tabs),
+            ..Default::default()
+        };
+        let mut ctx = SnippetRenderCtx::builder()
+            .tab_width(8)
+            .overrides(&language)
+            .line_ending("\n")
+            .resolve_var(|name: &str| (name == "var").then(|| Cow::from("value")))
+            .build();
+        let var_ctx = VariableContext::default();
+        assert_eq!(ctx.tab_width, 8);
+        assert_eq!(ctx.indent_style, IndentStyle::Tabs);
+        assert_eq!(
+            ctx.resolve_var.resolve_var("var", &var_ctx).as_deref(),
+            Some("value")
+        );
+        // the resolver can be swapped without rebuilding the context
+        ctx.set_resolver(Box::new(|_: &str| -> Option<Cow<'static, str>> { None }));
+        assert_eq!(ctx.resolve_var.resolve_var("var", &var_ctx), None);
+        assert_eq!(ctx.tab_width, 8);
+    }
+
+    #[test]
+    fn standard_variables() {
+        use chrono::NaiveDate;
+
+        use crate::snippets::render::{StandardVariables, VariableContext, VariableResolver};
+
+        let mut vars = StandardVariables::new()
+            .with_clock(|| {
+                NaiveDate::from_ymd_opt(2024, 2, 29)
+                    .unwrap()
+                    .and_hms_opt(13, 37, 5)
+                    .unwrap()
+            })
+            .with_random({
+                let mut counter = 0;
+                move || {
+                    counter += 1;
+                    counter
+                }
+            });
+        vars.path = Some("/tmp/src/main.rs".into());
+        vars.line_idx = 9;
+
+        let ctx = VariableContext::default();
+        assert_eq!(
+            vars.resolve_var("TM_FILENAME", &ctx).as_deref(),
+            Some("main.rs")
+        );
+        assert_eq!(
+            vars.resolve_var("TM_FILENAME_BASE", &ctx).as_deref(),
+            Some("main")
+        );
+        assert_eq!(
+            vars.resolve_var("TM_DIRECTORY", &ctx).as_deref(),
+            Some("/tmp/src")
+        );
+        assert_eq!(
+            vars.resolve_var("TM_FILEPATH", &ctx).as_deref(),
+            Some("/tmp/src/main.rs")
+        );
+        assert_eq!(vars.resolve_var("TM_LINE_INDEX", &ctx).as_deref(), Some("9"));
+        assert_eq!(
+            vars.resolve_var("TM_LINE_NUMBER", &ctx).as_deref(),
+            Some("10")
+        );
+        assert_eq!(
+            vars.resolve_var("CURRENT_YEAR", &ctx).as_deref(),
+            Some("2024")
+        );
+        assert_eq!(
+            vars.resolve_var("CURRENT_MONTH_NAME", &ctx).as_deref(),
+            Some("February")
+        );
+        assert_eq!(
+            vars.resolve_var("CURRENT_DAY_NAME_SHORT", &ctx).as_deref(),
+            Some("Thu")
+        );
+        assert_eq!(vars.resolve_var("CURRENT_HOUR", &ctx).as_deref(), Some("13"));
+        assert_eq!(vars.resolve_var("RANDOM", &ctx).as_deref(), Some("000001"));
+        assert_eq!(
+            vars.resolve_var("RANDOM_HEX", &ctx).as_deref(),
+            Some("000002")
+        );
+        let uuid = vars.resolve_var("UUID", &ctx).unwrap();
+        assert_eq!(uuid.len(), 36);
+        assert_eq!(&uuid[14..15], "4");
+        assert_eq!(vars.resolve_var("DOES_NOT_EXIST", &ctx), None);
+    }
+
+    #[test]
+    fn per_selection_variables() {
+        use crate::snippets::render::{DocumentVariables, VariableContext, VariableResolver};
+        use crate::{smallvec, Range, Rope, Selection};
+
+        let text = Rope::from("let foo = 1;\nlet bar = 2;\n");
+        let selection = Selection::new(smallvec![Range::new(4, 7), Range::new(17, 20)], 0);
+        let mut vars = DocumentVariables { text, selection };
+
+        let first = VariableContext {
+            selection_idx: 0,
+            ..Default::default()
+        };
+        let second = VariableContext {
+            selection_idx: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            vars.resolve_var("TM_SELECTED_TEXT", &first).as_deref(),
+            Some("foo")
+        );
+        assert_eq!(
+            vars.resolve_var("TM_SELECTED_TEXT", &second).as_deref(),
+            Some("bar")
+        );
+        assert_eq!(
+            vars.resolve_var("TM_CURRENT_LINE", &first).as_deref(),
+            Some("let foo = 1;")
+        );
+        assert_eq!(
+            vars.resolve_var("TM_CURRENT_LINE", &second).as_deref(),
+            Some("let bar = 2;")
+        );
+        assert_eq!(
+            vars.resolve_var("TM_CURRENT_WORD", &second).as_deref(),
+            Some("bar")
+        );
+        // out of bounds selection indices resolve to nothing
+        let missing = VariableContext {
+            selection_idx: 2,
+            ..Default::default()
+        };
+        assert_eq!(vars.resolve_var("TM_SELECTED_TEXT", &missing), None);
+    }
+
+    #[test]
+    fn environment_and_workspace_variables() {
+        use crate::snippets::render::{StandardVariables, VariableContext, VariableResolver};
+
+        std::env::set_var("HELIX_SNIPPET_TEST_VAR", "value");
+        let mut vars = StandardVariables::new();
+        vars.path = Some("/tmp/src/main.rs".into());
+        vars.workspace = Some("/tmp".into());
+
+        let ctx = VariableContext::default();
+        assert_eq!(
+            vars.resolve_var("env:HELIX_SNIPPET_TEST_VAR", &ctx).as_deref(),
+            Some("value")
+        );
+        assert_eq!(vars.resolve_var("WORKSPACE_NAME", &ctx).as_deref(), Some("tmp"));
+        assert_eq!(
+            vars.resolve_var("WORKSPACE_FOLDER", &ctx).as_deref(),
+            Some("/tmp")
+        );
+        assert_eq!(
+            vars.resolve_var("RELATIVE_FILEPATH", &ctx).as_deref(),
+            Some("src/main.rs")
+        );
+
+        // an allowlist blocks everything not explicitly listed
+        vars.env_allowlist = Some(vec!["OTHER".into()]);
+        assert_eq!(vars.resolve_var("env:HELIX_SNIPPET_TEST_VAR", &ctx), None);
+        vars.env_allowlist = Some(vec!["HELIX_SNIPPET_TEST_VAR".into()]);
+        assert_eq!(
+            vars.resolve_var("env:HELIX_SNIPPET_TEST_VAR", &ctx).as_deref(),
+            Some("value")
+        );
+    }
+
+    #[test]
+    fn crlf_line_endings() {
+        use crate::Range;
+
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        ctx.line_ending = "\r\n";
+        let snippet = Snippet::parse("a\r\nb\nc$0").unwrap();
+        let (text, rendered) = snippet.render_at("\r\n", &mut ctx, 0);
+        // both LF and CRLF in the body render as the document line ending
+        assert_eq!(text, "a\r\nb\r\nc");
+        assert_eq!(rendered.tabstops[0].ranges[0], Range::point(7));
+        assert_eq!(rendered.tabstops[0].byte_ranges[0], (7, 7));
+    }
+
+    #[test]
+    fn comment_leader_continues_on_every_line() {
+        use crate::Range;
+
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        ctx.continue_comment = Some("/// ".into());
+        let snippet = Snippet::parse("Errors\n\nReturns $1 on failure.").unwrap();
+        // `\n    ` mirrors an expansion inside an indented doc comment
+        let (text, rendered) = snippet.render_at("\n    ", &mut ctx, 0);
+        assert_eq!(text, "Errors\n    /// \n    /// Returns  on failure.");
+        assert_eq!(rendered.tabstops[0].ranges[0], Range::point(32));
+    }
+
+    #[test]
+    fn clipboard_provider_backs_the_clipboard_variable() {
+        use crate::snippets::render::StandardVariables;
+
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        ctx.set_resolver(Box::new(
+            StandardVariables::new().with_clipboard(|| Some("yanked".to_string())),
+        ));
+        let snippet = Snippet::parse("paste ${CLIPBOARD:nothing}$0").unwrap();
+        let (text, _) = snippet.render_at("\n", &mut ctx, 0);
+        assert_eq!(text, "paste yanked");
+
+        // without a provider the default text renders instead
+        ctx.set_resolver(Box::new(StandardVariables::new()));
+        let (text, _) = snippet.render_at("\n", &mut ctx, 0);
+        assert_eq!(text, "paste nothing");
+    }
+
+    #[test]
+    fn date_variable_formats_with_strftime() {
+        use crate::snippets::render::StandardVariables;
+        use chrono::NaiveDate;
+
+        let clock = || {
+            NaiveDate::from_ymd_opt(2024, 1, 31)
+                .unwrap()
+                .and_hms_opt(13, 37, 0)
+                .unwrap()
+        };
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        ctx.set_resolver(Box::new(StandardVariables::new().with_clock(clock)));
+        let snippet = Snippet::parse("${date:%Y-%m-%d %H:%M}$0").unwrap();
+        let (text, _) = snippet.render_at("\n", &mut ctx, 0);
+        assert_eq!(text, "2024-01-31 13:37");
+
+        // an invalid format specifier renders as ordinary default text
+        let snippet = Snippet::parse("${date:%Q}$0").unwrap();
+        let (text, _) = snippet.render_at("\n", &mut ctx, 0);
+        assert_eq!(text, "%Q");
+    }
+
+    #[test]
+    fn seeded_random_is_deterministic() {
+        use crate::snippets::render::StandardVariables;
+
+        let render = |seed| {
+            let mut ctx = SnippetRenderCtx::test_ctx();
+            ctx.set_resolver(Box::new(StandardVariables::new().with_seed(seed)));
+            let snippet = Snippet::parse("$RANDOM $RANDOM_HEX $UUID$0").unwrap();
+            let (text, _) = snippet.render_at("\n", &mut ctx, 0);
+            text
+        };
+        assert_eq!(render(42), render(42));
+        assert_ne!(render(42), render(43));
+        // seeding doesn't break the UUID shape
+        let text = render(7);
+        let uuid = text.rsplit(' ').next().unwrap();
+        assert_eq!(uuid.len(), 36);
+        assert_eq!(uuid.as_bytes()[14], b'4');
+    }
+
+    #[test]
+    fn measure_matches_render() {
+        use crate::movement::Direction;
+        use crate::{smallvec, Range, Rope, Selection};
+
+        let doc = Rope::from("x\ny");
+        let selection = Selection::new(smallvec![Range::point(1), Range::point(3)], 0);
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        let snippet = Snippet::parse("fn ${1:name}() {$0}").unwrap();
+        let (len, landing, measured) = snippet.measure(&doc, &selection, &mut ctx);
+        let (_, _, rendered) =
+            snippet.render(&doc, &selection, |range| (range.from(), range.to()), &mut ctx);
+        // the dry run reports exactly what a real render would
+        assert_eq!(measured, rendered);
+        assert_eq!(len, 2 * "fn name() {}".chars().count());
+        assert_eq!(landing, rendered.first_selection(Direction::Forward, 0));
+    }
+
+    #[test]
+    #[cfg(feature = "render-metrics")]
+    fn metrics_count_elements_and_bytes() {
+        let snippet = Snippet::parse("fn ${1:name}() {$0}").unwrap();
+        let (text, rendered) = snippet.render_at("\n", &mut SnippetRenderCtx::test_ctx(), 0);
+        assert_eq!(rendered.metrics.bytes, text.len());
+        // "fn ", tabstop 1, its default "name", "() {", the final tabstop
+        // and "}"
+        assert_eq!(rendered.metrics.elements, 6);
+    }
+
+    #[test]
+    #[cfg(feature = "rayon")]
+    fn parallel_render_matches_sequential() {
+        use crate::{smallvec, Range, Rope, Selection};
+
+        let doc = Rope::from("a\nb\nc\n");
+        let selection = Selection::new(
+            smallvec![Range::point(0), Range::point(2), Range::point(4)],
+            0,
+        );
+        let snippet = Snippet::parse("fn ${1:name}() {$0}").unwrap();
+        let (transaction, _, rendered) = snippet.render_parallel(
+            &doc,
+            &selection,
+            |range| (range.from(), range.to()),
+            SnippetRenderCtx::test_ctx,
+        );
+        let (seq_transaction, _, seq_rendered) = snippet.render(
+            &doc,
+            &selection,
+            |range| (range.from(), range.to()),
+            &mut SnippetRenderCtx::test_ctx(),
+        );
+        assert_eq!(rendered, seq_rendered);
+        let (mut doc, mut seq_doc) = (doc.clone(), doc);
+        assert!(transaction.apply(&mut doc));
+        assert!(seq_transaction.apply(&mut seq_doc));
+        assert_eq!(doc, seq_doc);
+    }
+
+    #[test]
+    fn replacement_variables_resolve_per_expansion_point() {
+        use crate::snippets::render::ReplacementVariables;
+        use crate::{smallvec, Range, Rope, Selection};
+
+        let doc = Rope::from("foo\nbar\n");
+        let selection = Selection::new(smallvec![Range::point(1), Range::point(5)], 0);
+        let mut ctx = SnippetRenderCtx::builder()
+            .resolve_var(ReplacementVariables { text: doc.clone() })
+            .build();
+        let snippet = Snippet::parse("/* ${TM_CURRENT_WORD}:${TM_LINE_NUMBER} */$0").unwrap();
+        let (transaction, _, _) =
+            snippet.render(&doc, &selection, |range| (range.from(), range.to()), &mut ctx);
+        let mut doc = doc;
+        assert!(transaction.apply(&mut doc));
+        // each cursor sees the word and line at its own expansion point
+        assert_eq!(doc, "f/* foo:1 */oo\nb/* bar:2 */ar\n");
+    }
+
+    #[test]
+    fn multiline_values_normalize_in_single_line_snippets() {
+        use std::borrow::Cow;
+
+        use crate::snippets::render::MultilineValuePolicy;
+
+        let resolver = |name: &str| -> Option<Cow<'static, str>> {
+            (name == "CLIPBOARD").then(|| Cow::from("first\n  second"))
+        };
+        let snippet = Snippet::parse("[${CLIPBOARD:empty}]$0").unwrap();
+        let render = |policy| {
+            let mut ctx = SnippetRenderCtx::builder()
+                .resolve_var(resolver)
+                .line_ending("\n")
+                .multiline_values(policy)
+                .build();
+            snippet.render_at("\n", &mut ctx, 0).0
+        };
+        assert_eq!(render(MultilineValuePolicy::Collapse), "[first second]");
+        assert_eq!(render(MultilineValuePolicy::Escape), "[first\\n  second]");
+        // a rejected value renders the default text instead
+        assert_eq!(render(MultilineValuePolicy::Reject), "[empty]");
+    }
+
+    #[test]
+    fn render_at_line_column_coordinates() {
+        use crate::{Position, Range, Rope};
+
+        let doc = Rope::from("fn main() {\n    body\n}\n");
+        let snippet = Snippet::parse("dbg!($1)$0").unwrap();
+        let (transaction, _, rendered) = snippet.render_coords(
+            &doc,
+            Position { row: 1, col: 4 },
+            &mut SnippetRenderCtx::test_ctx(),
+        );
+        let mut doc = doc;
+        assert!(transaction.apply(&mut doc));
+        assert_eq!(doc, "fn main() {\n    dbg!()body\n}\n");
+        assert_eq!(&rendered.tabstops[0].ranges[..], &[Range::point(21)]);
+    }
+
+    #[test]
+    fn invisible_chars_in_values_are_stripped_or_escaped() {
+        use std::borrow::Cow;
+
+        use crate::snippets::render::InvisibleCharPolicy;
+
+        let resolver = |name: &str| -> Option<Cow<'static, str>> {
+            (name == "CLIPBOARD").then(|| Cow::from("a\u{202e}b\u{200b}c"))
+        };
+        let snippet = Snippet::parse("$CLIPBOARD$0").unwrap();
+        let render = |policy| {
+            let mut ctx = SnippetRenderCtx::builder()
+                .resolve_var(resolver)
+                .line_ending("\n")
+                .invisible_chars(policy)
+                .build();
+            snippet.render_at("\n", &mut ctx, 0).0
+        };
+        assert_eq!(render(InvisibleCharPolicy::Keep), "a\u{202e}b\u{200b}c");
+        assert_eq!(render(InvisibleCharPolicy::Strip), "abc");
+        assert_eq!(render(InvisibleCharPolicy::Escape), "a\\u{202e}b\\u{200b}c");
+    }
+
+    #[test]
+    fn collapse_blank_lines_drops_empty_optional_lines() {
+        use std::borrow::Cow;
+
+        let resolver = |name: &str| -> Option<Cow<'static, str>> {
+            (name == "AUTHOR").then(|| Cow::from("Ada"))
+        };
+        let snippet = Snippet::parse("A\n${AUTHOR}\n${LICENSE}\n\nB$0").unwrap();
+        let mut ctx = SnippetRenderCtx::builder()
+            .resolve_var(resolver)
+            .line_ending("\n")
+            .collapse_blank_lines(true)
+            .build();
+        let (text, _) = snippet.render_at("\n", &mut ctx, 0);
+        // the `${LICENSE}` line rendered to nothing and is dropped, the
+        // literal blank line stays
+        assert_eq!(text, "A\nAda\n\nB");
+    }
+
+    #[test]
+    fn preview_shows_old_and_new_lines() {
+        use crate::{smallvec, Range, Rope, Selection};
+
+        let doc = Rope::from("one\ntwo\n");
+        let selection = Selection::new(smallvec![Range::new(0, 3)], 0);
+        let snippet = Snippet::parse("if $1 {\n\t$0\n}").unwrap();
+        let preview = snippet.preview_diff(
+            &doc,
+            &selection,
+            |range| (range.from(), range.to()),
+            &mut SnippetRenderCtx::test_ctx(),
+        );
+        assert_eq!(preview, "@@ line 1 @@\n- one\n+ if  {\n+     \n+ }\n");
+        // previewing leaves the document untouched
+        assert_eq!(doc, "one\ntwo\n");
+    }
+
+    #[test]
+    fn mixed_snippets_expand_in_one_transaction() {
+        use crate::snippets::render::TabstopKind;
+        use crate::{Range, Rope};
+
+        let doc = Rope::from("a b");
+        let parens = Snippet::parse("(${1:x})$0").unwrap();
+        let brackets = Snippet::parse("[$1]$0").unwrap();
+        let (transaction, rendered) = Snippet::render_mixed(
+            &doc,
+            [(Range::point(0), &parens), (Range::point(2), &brackets)],
+            &mut SnippetRenderCtx::test_ctx(),
+        );
+        let mut doc = doc;
+        assert!(transaction.apply(&mut doc));
+        assert_eq!(doc, "(x)a []b");
+        // `$1` of both snippets is visited together, the final tabstops
+        // collapse into one
+        assert_eq!(rendered.tabstops.len(), 2);
+        assert_eq!(
+            &rendered.tabstops[0].ranges[..],
+            &[Range::new(1, 2), Range::point(6)]
+        );
+        assert_eq!(
+            &rendered.tabstops[1].ranges[..],
+            &[Range::point(3), Range::point(7)]
+        );
+        assert!(matches!(rendered.tabstops[0].kind, TabstopKind::Placeholder));
+    }
+
+    #[test]
+    fn render_around_selection_wraps_each_range() {
+        use crate::{smallvec, Range, Rope, Selection};
+
+        let doc = Rope::from("foo bar");
+        let selection = Selection::new(smallvec![Range::new(0, 3), Range::new(4, 7)], 0);
+        let snippet = Snippet::parse("(${TM_SELECTED_TEXT})$0").unwrap();
+        let (transaction, _, rendered) =
+            snippet.render_around_selection(&doc, &selection, &mut SnippetRenderCtx::test_ctx());
+        let mut doc = doc;
+        assert!(transaction.apply(&mut doc));
+        assert_eq!(doc, "(foo) (bar)");
+        assert_eq!(
+            &rendered.tabstops[0].ranges[..],
+            &[Range::point(5), Range::point(11)]
+        );
+    }
+
+    #[test]
+    fn pair_positions_mark_inserted_pairs() {
+        let snippet = Snippet::parse("foo(${1:x})$0").unwrap();
+        let (text, rendered) = snippet.render_at("\n", &mut SnippetRenderCtx::test_ctx(), 0);
+        assert_eq!(text, "foo(x)");
+        // the position just inside the parentheses the snippet inserted
+        assert_eq!(rendered.pair_positions, &[4]);
+
+        let snippet = Snippet::parse("[\"$1\"]$0").unwrap();
+        let (text, rendered) = snippet.render_at("\n", &mut SnippetRenderCtx::test_ctx(), 0);
+        assert_eq!(text, "[\"\"]");
+        assert_eq!(rendered.pair_positions, &[1, 2, 3]);
+    }
+
+    #[test]
+    fn auto_indent_final_tabstop_line() {
+        use crate::Range;
+
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        ctx.auto_indent_final_tabstop = true;
+        let snippet = Snippet::parse("if $1 {\n$0\n}").unwrap();
+        let (text, rendered) = snippet.render_at("\n", &mut ctx, 0);
+        // the blank landing line gets one indent level
+        assert_eq!(text, "if  {\n    \n}");
+        assert_eq!(rendered.tabstops[1].ranges[0], Range::point(10));
+
+        // a line the author indented themselves is left alone
+        let snippet = Snippet::parse("if $1 {\n\t$0\n}").unwrap();
+        let (text, rendered) = snippet.render_at("\n", &mut ctx, 0);
+        assert_eq!(text, "if  {\n    \n}");
+        assert_eq!(rendered.tabstops[1].ranges[0], Range::point(10));
+    }
+
+    #[test]
+    fn trim_trailing_whitespace_on_blank_placeholder_lines() {
+        use crate::Range;
+
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        ctx.trim_trailing_whitespace = true;
+        let snippet = Snippet::parse("if x {\n    $1\n}$0").unwrap();
+        let (text, rendered) = snippet.render_at("\n", &mut ctx, 0);
+        // the line held nothing but the empty tabstop, its indentation is
+        // dropped instead of lingering as trailing whitespace
+        assert_eq!(text, "if x {\n\n}");
+        assert_eq!(rendered.tabstops[0].ranges[0], Range::point(7));
+
+        // a placeholder with content keeps the indentation in front of it
+        let snippet = Snippet::parse("if x {\n    ${1:x}\n}$0").unwrap();
+        let (text, rendered) = snippet.render_at("\n", &mut ctx, 0);
+        assert_eq!(text, "if x {\n    x\n}");
+        assert_eq!(rendered.tabstops[0].ranges[0], Range::new(11, 12));
+        assert_eq!(rendered.tabstops[0].byte_ranges[0], (11, 12));
+    }
+
+    #[test]
+    fn hard_wrapping_at_text_width() {
+        use crate::Range;
+
+        let mut ctx = SnippetRenderCtx::test_ctx();
+        ctx.text_width = Some(16);
+        ctx.continue_comment = Some("// ".into());
+        let snippet = Snippet::parse("one two three four five$0").unwrap();
+        let (text, rendered) = snippet.render_at("\n  ", &mut ctx, 0);
+        // "four" would end at column 20, so the line breaks before it and
+        // the continuation restates the indentation and comment leader
+        assert_eq!(text, "one two three\n  // four five");
+        assert_eq!(rendered.tabstops[0].ranges[0], Range::point(28));
+    }
+
+    #[test]
+    fn placeholder_keeps_relative_indentation() {
+        // lines of a multi-line default stay aligned with the line the
+        // placeholder starts on
+        let (text, _) = render("if {\n    ${1:foo();\nbar();}\n}");
+        assert_eq!(text, "if {\n    foo();\n    bar();\n}");
+    }
+
+    #[test]
+    fn byte_ranges_track_multibyte_text() {
+        let snippet = Snippet::parse("ü${1:éé}x").unwrap();
+        let (text, rendered) = snippet.render_at("\n", &mut SnippetRenderCtx::test_ctx(), 0);
+        assert_eq!(text, "üééx");
+        assert_eq!(&rendered.tabstops[0].byte_ranges[..], &[(2, 6)]);
+        assert_eq!(rendered.byte_ranges, &[(0, 7)]);
+    }
+
+    #[test]
+    fn snapping_to_graphemes() {
+        use crate::snippets::render::{RenderedSnippet, Tabstop, TabstopKind};
+        use crate::{smallvec, Range, Rope};
+
+        let doc = Rope::from("e\u{0301}x");
+        let mut rendered = RenderedSnippet {
+            tabstops: vec![Tabstop {
+                ranges: smallvec![Range::new(1, 2)],
+                byte_ranges: smallvec![(1, 3)],
+                parent: None,
+                kind: TabstopKind::Empty,
+            }],
+            ranges: vec![Range::new(1, 2)],
+            byte_ranges: vec![(1, 3)],
+            ..RenderedSnippet::default()
+        };
+        rendered.snap_to_graphemes(doc.slice(..));
+        // the range is widened to cover the whole grapheme cluster
+        assert_eq!(&rendered.tabstops[0].ranges[..], &[Range::new(0, 2)]);
+        assert_eq!(rendered.ranges, &[Range::new(0, 2)]);
+    }
+
+    #[test]
+    fn rope_rendering_matches_string_rendering() {
+        let snippet = Snippet::parse("fn ${1:name}() {\n    $0\n}").unwrap();
+        let (text, rendered) = snippet.render_at("\n  ", &mut SnippetRenderCtx::test_ctx(), 0);
+        let (rope, rope_rendered) =
+            snippet.render_rope_at("\n  ", &mut SnippetRenderCtx::test_ctx(), 0);
+        assert_eq!(rope, text.as_str());
+        assert_eq!(rendered, rope_rendered);
+    }
+
+    #[test]
+    fn robust_parsing() {
+        assert_text("$", "$");
+        assert_text("\\\\$", "\\$");
+        assert_text("{", "{");
+        assert_text("\\}", "}");
+        assert_text("\\abc", "\\abc");
+        assert_text("foo${f:\\}}bar", "foo}bar");
+        assert_text("\\{", "\\{");
+        assert_text("I need \\\\\\$", "I need \\$");
+        assert_text("\\", "\\");
+        assert_text("\\{{", "\\{{");
+        assert_text("{{", "{{");
+        assert_text("{{dd", "{{dd");
+        assert_text("}}", "}}");
+        assert_text("ff}}", "ff}}");
+        assert_text("farboo", "farboo");
+        assert_text("far{{}}boo", "far{{}}boo");
+        assert_text("far{{123}}boo", "far{{123}}boo");
+        assert_text("far\\{{123}}boo", "far\\{{123}}boo");
+        assert_text("far{{id:bern}}boo", "far{{id:bern}}boo");
+        assert_text("far{{id:bern {{basel}}}}boo", "far{{id:bern {{basel}}}}boo");
+        assert_text("${}$\\a\\$\\}\\\\", "${}$\\a$}\\");
+        assert_text("far`123`boo", "far`123`boo");
+        assert_text("far\\`123\\`boo", "far\\`123\\`boo");
+        assert_text("\\$far-boo", "$far-boo");
+    }
+}